@@ -0,0 +1,645 @@
+use std::cmp;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek};
+use std::path;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use proto::peer;
+
+/// The audio properties of a file, as far as they could be determined.
+#[derive(Clone, Debug, Default, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct AudioInfo {
+    /// The bitrate in kilobits per second, averaged over the whole file.
+    pub bitrate: Option<u32>,
+    /// The duration in seconds.
+    pub duration: Option<u32>,
+    /// Whether the bitrate is variable.
+    pub is_vbr: Option<bool>,
+    /// The sample rate in hertz.
+    pub sample_rate: Option<u32>,
+    /// The number of bits per sample, for lossless formats.
+    pub bit_depth: Option<u32>,
+}
+
+impl AudioInfo {
+    /// Returns the properties as file attributes, as sent in file listings
+    /// and search results.
+    pub fn to_attributes(&self) -> Vec<(u32, u32)> {
+        let mut attributes = Vec::new();
+        if let Some(bitrate) = self.bitrate {
+            attributes.push((peer::ATTRIBUTE_BITRATE, bitrate));
+        }
+        if let Some(duration) = self.duration {
+            attributes.push((peer::ATTRIBUTE_DURATION, duration));
+        }
+        if let Some(is_vbr) = self.is_vbr {
+            attributes.push((peer::ATTRIBUTE_VBR, is_vbr as u32));
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            attributes.push((peer::ATTRIBUTE_SAMPLE_RATE, sample_rate));
+        }
+        if let Some(bit_depth) = self.bit_depth {
+            attributes.push((peer::ATTRIBUTE_BIT_DEPTH, bit_depth));
+        }
+        attributes
+    }
+
+    /// Returns the properties described by the given file attributes.
+    /// Unknown attributes are ignored.
+    pub fn from_attributes(attributes: &[(u32, u32)]) -> Self {
+        let mut info = Self::default();
+        for &(attribute, value) in attributes {
+            match attribute {
+                peer::ATTRIBUTE_BITRATE => info.bitrate = Some(value),
+                peer::ATTRIBUTE_DURATION => info.duration = Some(value),
+                peer::ATTRIBUTE_VBR => info.is_vbr = Some(value != 0),
+                peer::ATTRIBUTE_SAMPLE_RATE => info.sample_rate = Some(value),
+                peer::ATTRIBUTE_BIT_DEPTH => info.bit_depth = Some(value),
+                _ => (),
+            }
+        }
+        info
+    }
+}
+
+/// Reads the audio properties of the file at the given path from its
+/// headers, if it is an MP3, FLAC or Ogg file.
+/// Returns None if the file is of another type or its headers cannot be
+/// made sense of.
+pub fn read_info(path: &path::Path) -> io::Result<Option<AudioInfo>> {
+    let extension = match path.extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => return Ok(None),
+    };
+    let reader: fn(&mut fs::File, u64) -> io::Result<Option<AudioInfo>> = match &*extension {
+        "mp3" => read_mp3_info,
+        "flac" => read_flac_info,
+        "ogg" | "oga" | "opus" => read_ogg_info,
+        _ => return Ok(None),
+    };
+
+    let mut file = fs::File::open(path)?;
+    let file_size = file.metadata()?.len();
+    reader(&mut file, file_size)
+}
+
+/// Reads up to the given number of bytes from the current position.
+fn read_up_to<R: Read>(file: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(max);
+    file.take(max as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Returns the average bitrate in kilobits per second of the given number
+/// of bytes played over the given number of samples at the given rate.
+fn average_bitrate(num_bytes: u64, num_samples: u64, sample_rate: u32) -> Option<u32> {
+    if num_samples == 0 {
+        return None;
+    }
+    Some((num_bytes * 8 * sample_rate as u64 / num_samples / 1000) as u32)
+}
+
+/// Skips the ID3v2 tag at the start of the file, if any. Returns the offset
+/// at which the audio data starts.
+fn skip_id3v2<R: Read + Seek>(file: &mut R) -> io::Result<u64> {
+    let header = read_up_to(file, 10)?;
+    let offset = if header.len() == 10 && &header[..3] == b"ID3" {
+        // The size is a "syncsafe" integer: 7 bits per byte.
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |size, &byte| (size << 7) | (byte & 0x7f) as u64);
+        let has_footer = header[5] & 0x10 != 0;
+        10 + size + if has_footer { 10 } else { 0 }
+    } else {
+        0
+    };
+    file.seek(io::SeekFrom::Start(offset))?;
+    Ok(offset)
+}
+
+/*=====*
+ * MP3 *
+ *=====*/
+
+/// How far into the audio data to look for the first frame.
+const MP3_SEARCH_LEN: usize = 64 * 1024;
+
+const MP3_V1_BITRATES: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MP3_V2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const MP3_V1_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// The parts of an MPEG audio layer III frame header we care about.
+struct Mp3FrameHeader {
+    is_mpeg1: bool,
+    bitrate: u32,
+    sample_rate: u32,
+    is_mono: bool,
+}
+
+impl Mp3FrameHeader {
+    /// Parses the given four bytes, if they are a layer III frame header.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes[0] != 0xff || bytes[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+        let version = (bytes[1] >> 3) & 0x3;
+        let layer = (bytes[1] >> 1) & 0x3;
+        let bitrate_index = (bytes[2] >> 4) as usize;
+        let sample_rate_index = ((bytes[2] >> 2) & 0x3) as usize;
+        // Version 1 is reserved, layer 1 means layer III.
+        if version == 1
+            || layer != 1
+            || bitrate_index == 0
+            || bitrate_index == 15
+            || sample_rate_index == 3
+        {
+            return None;
+        }
+
+        let is_mpeg1 = version == 3;
+        let bitrate = if is_mpeg1 {
+            MP3_V1_BITRATES[bitrate_index]
+        } else {
+            MP3_V2_BITRATES[bitrate_index]
+        };
+        let sample_rate = match version {
+            3 => MP3_V1_SAMPLE_RATES[sample_rate_index],
+            2 => MP3_V1_SAMPLE_RATES[sample_rate_index] / 2,
+            _ => MP3_V1_SAMPLE_RATES[sample_rate_index] / 4,
+        };
+        Some(Mp3FrameHeader {
+            is_mpeg1: is_mpeg1,
+            bitrate: bitrate,
+            sample_rate: sample_rate,
+            is_mono: bytes[3] >> 6 == 3,
+        })
+    }
+
+    fn samples_per_frame(&self) -> u64 {
+        if self.is_mpeg1 {
+            1152
+        } else {
+            576
+        }
+    }
+
+    /// Returns the offset from the start of the frame of the Xing or Info
+    /// header, if any, which comes right after the side information.
+    fn xing_offset(&self) -> usize {
+        4 + match (self.is_mpeg1, self.is_mono) {
+            (true, false) => 32,
+            (true, true) => 17,
+            (false, false) => 17,
+            (false, true) => 9,
+        }
+    }
+}
+
+fn read_mp3_info<R: Read + Seek>(file: &mut R, file_size: u64) -> io::Result<Option<AudioInfo>> {
+    let audio_start = skip_id3v2(file)?;
+    let bytes = read_up_to(file, MP3_SEARCH_LEN)?;
+
+    let (frame_start, header) = match (0..bytes.len().saturating_sub(3))
+        .filter_map(|i| Mp3FrameHeader::parse(&bytes[i..i + 4]).map(|header| (i, header)))
+        .next()
+    {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let frame = &bytes[frame_start..];
+    let audio_size = file_size.saturating_sub(audio_start + frame_start as u64);
+
+    // VBR files announce their number of frames in a Xing or VBRI header in
+    // the first frame. Info headers are Xing headers written by encoders
+    // for CBR files.
+    let mut num_frames = None;
+    let mut num_bytes = None;
+    let mut is_vbr = false;
+    let xing_offset = header.xing_offset();
+    if frame.len() >= xing_offset + 16 {
+        let xing = &frame[xing_offset..];
+        if &xing[..4] == b"Xing" || &xing[..4] == b"Info" {
+            is_vbr = &xing[..4] == b"Xing";
+            let flags = BigEndian::read_u32(&xing[4..8]);
+            let mut field = 8;
+            if flags & 0x1 != 0 {
+                num_frames = Some(BigEndian::read_u32(&xing[field..field + 4]) as u64);
+                field += 4;
+            }
+            if flags & 0x2 != 0 {
+                num_bytes = Some(BigEndian::read_u32(&xing[field..field + 4]) as u64);
+            }
+        }
+    }
+    if frame.len() >= 36 + 18 && &frame[36..40] == b"VBRI" {
+        is_vbr = true;
+        num_bytes = Some(BigEndian::read_u32(&frame[46..50]) as u64);
+        num_frames = Some(BigEndian::read_u32(&frame[50..54]) as u64);
+    }
+
+    let audio_size = num_bytes.unwrap_or(audio_size);
+    let (duration, bitrate) = match num_frames {
+        Some(num_frames) => {
+            let num_samples = num_frames * header.samples_per_frame();
+            let bitrate = average_bitrate(audio_size, num_samples, header.sample_rate);
+            (
+                Some((num_samples / header.sample_rate as u64) as u32),
+                bitrate.or(Some(header.bitrate)),
+            )
+        }
+        None => (
+            Some((audio_size * 8 / (header.bitrate as u64 * 1000)) as u32),
+            Some(header.bitrate),
+        ),
+    };
+
+    Ok(Some(AudioInfo {
+        bitrate: bitrate,
+        duration: duration,
+        is_vbr: Some(is_vbr),
+        sample_rate: Some(header.sample_rate),
+        bit_depth: None,
+    }))
+}
+
+/*======*
+ * FLAC *
+ *======*/
+
+fn read_flac_info<R: Read + Seek>(file: &mut R, file_size: u64) -> io::Result<Option<AudioInfo>> {
+    skip_id3v2(file)?;
+    // The stream marker is followed by the STREAMINFO metadata block, which
+    // must come first.
+    let bytes = read_up_to(file, 4 + 4 + 34)?;
+    if bytes.len() < 4 + 4 + 34 || &bytes[..4] != b"fLaC" || bytes[4] & 0x7f != 0 {
+        return Ok(None);
+    }
+    let stream_info = &bytes[8..];
+
+    // Sample rate: 20 bits, channels: 3 bits, bits per sample: 5 bits,
+    // total samples: 36 bits.
+    let fields = BigEndian::read_u64(&stream_info[10..18]);
+    let sample_rate = (fields >> 44) as u32;
+    let bit_depth = ((fields >> 36) & 0x1f) as u32 + 1;
+    let num_samples = fields & 0xf_ffff_ffff;
+    if sample_rate == 0 {
+        return Ok(None);
+    }
+
+    let (duration, bitrate) = if num_samples > 0 {
+        (
+            Some((num_samples / sample_rate as u64) as u32),
+            average_bitrate(file_size, num_samples, sample_rate),
+        )
+    } else {
+        (None, None)
+    };
+
+    Ok(Some(AudioInfo {
+        bitrate: bitrate,
+        duration: duration,
+        is_vbr: None,
+        sample_rate: Some(sample_rate),
+        bit_depth: Some(bit_depth),
+    }))
+}
+
+/*=====*
+ * OGG *
+ *=====*/
+
+/// The length of an Ogg page header, without its segment table.
+const OGG_PAGE_HEADER_LEN: usize = 27;
+
+/// How far from the end of the file to look for the last page.
+const OGG_LAST_PAGE_SEARCH_LEN: u64 = 64 * 1024;
+
+/// Opus always reports positions at this sample rate.
+const OPUS_GRANULE_RATE: u32 = 48000;
+
+/// Returns the granule position of the last page of the file, which is the
+/// number of samples in the whole stream.
+fn read_ogg_last_granule<R: Read + Seek>(file: &mut R, file_size: u64) -> io::Result<Option<u64>> {
+    let search_start = file_size.saturating_sub(OGG_LAST_PAGE_SEARCH_LEN);
+    file.seek(io::SeekFrom::Start(search_start))?;
+    let bytes = read_up_to(file, OGG_LAST_PAGE_SEARCH_LEN as usize)?;
+    let last_page = (0..bytes.len().saturating_sub(OGG_PAGE_HEADER_LEN))
+        .rev()
+        .find(|&i| &bytes[i..i + 4] == b"OggS");
+    Ok(last_page.map(|i| LittleEndian::read_u64(&bytes[i + 6..i + 14])))
+}
+
+fn read_ogg_info<R: Read + Seek>(file: &mut R, file_size: u64) -> io::Result<Option<AudioInfo>> {
+    let bytes = read_up_to(file, 4096)?;
+    if bytes.len() < OGG_PAGE_HEADER_LEN || &bytes[..4] != b"OggS" {
+        return Ok(None);
+    }
+    let num_segments = bytes[26] as usize;
+    let packet_start = OGG_PAGE_HEADER_LEN + num_segments;
+    let packet = &bytes[cmp::min(packet_start, bytes.len())..];
+
+    // The first packet identifies the codec.
+    let (sample_rate, granule_rate, pre_skip, nominal_bitrate) =
+        if packet.len() >= 28 && &packet[..7] == b"\x01vorbis" {
+            let sample_rate = LittleEndian::read_u32(&packet[12..16]);
+            let nominal_bitrate = LittleEndian::read_i32(&packet[20..24]);
+            (sample_rate, sample_rate, 0, nominal_bitrate)
+        } else if packet.len() >= 16 && &packet[..8] == b"OpusHead" {
+            let pre_skip = LittleEndian::read_u16(&packet[10..12]) as u64;
+            let sample_rate = LittleEndian::read_u32(&packet[12..16]);
+            (sample_rate, OPUS_GRANULE_RATE, pre_skip, 0)
+        } else {
+            return Ok(None);
+        };
+    if granule_rate == 0 {
+        return Ok(None);
+    }
+
+    let num_samples = match read_ogg_last_granule(file, file_size)? {
+        Some(granule) => granule.saturating_sub(pre_skip),
+        None => 0,
+    };
+    let duration = if num_samples > 0 {
+        Some((num_samples / granule_rate as u64) as u32)
+    } else {
+        None
+    };
+    let bitrate = if nominal_bitrate > 0 {
+        Some(nominal_bitrate as u32 / 1000)
+    } else {
+        average_bitrate(file_size, num_samples, granule_rate)
+    };
+
+    Ok(Some(AudioInfo {
+        bitrate: bitrate,
+        duration: duration,
+        is_vbr: None,
+        sample_rate: if sample_rate > 0 {
+            Some(sample_rate)
+        } else {
+            None
+        },
+        bit_depth: None,
+    }))
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+    use super::{read_flac_info, read_mp3_info, read_ogg_info, AudioInfo, Mp3FrameHeader};
+
+    /// MPEG-1 layer III, 128 kbps, 44100 Hz, stereo.
+    const MPEG1_HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x00];
+    /// MPEG-2 layer III, 80 kbps, 22050 Hz, mono.
+    const MPEG2_HEADER: [u8; 4] = [0xff, 0xf3, 0x90, 0xc0];
+
+    fn mp3_info(bytes: &[u8]) -> Option<AudioInfo> {
+        read_mp3_info(&mut io::Cursor::new(bytes), bytes.len() as u64).unwrap()
+    }
+
+    fn flac_info(bytes: &[u8], file_size: u64) -> Option<AudioInfo> {
+        read_flac_info(&mut io::Cursor::new(bytes), file_size).unwrap()
+    }
+
+    fn ogg_info(bytes: &[u8]) -> Option<AudioInfo> {
+        read_ogg_info(&mut io::Cursor::new(bytes), bytes.len() as u64).unwrap()
+    }
+
+    /// Returns an MPEG-1 frame of the given length with the given bytes
+    /// written at the given offset.
+    fn mpeg1_frame(len: usize, offset: usize, contents: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; len];
+        frame[..4].copy_from_slice(&MPEG1_HEADER);
+        frame[offset..offset + contents.len()].copy_from_slice(contents);
+        frame
+    }
+
+    /// Returns an Ogg page header with the given granule position, followed
+    /// by the given packet.
+    fn ogg_page(granule: u64, packet: &[u8]) -> Vec<u8> {
+        let mut page = vec![0; 27];
+        page[..4].copy_from_slice(b"OggS");
+        LittleEndian::write_u64(&mut page[6..14], granule);
+        page[26] = 1;
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    #[test]
+    fn mp3_frame_header_mpeg1() {
+        let header = Mp3FrameHeader::parse(&MPEG1_HEADER).unwrap();
+        assert!(header.is_mpeg1);
+        assert_eq!(header.bitrate, 128);
+        assert_eq!(header.sample_rate, 44100);
+        assert!(!header.is_mono);
+        assert_eq!(header.samples_per_frame(), 1152);
+        assert_eq!(header.xing_offset(), 36);
+    }
+
+    #[test]
+    fn mp3_frame_header_mpeg2() {
+        let header = Mp3FrameHeader::parse(&MPEG2_HEADER).unwrap();
+        assert!(!header.is_mpeg1);
+        assert_eq!(header.bitrate, 80);
+        assert_eq!(header.sample_rate, 22050);
+        assert!(header.is_mono);
+        assert_eq!(header.samples_per_frame(), 576);
+        assert_eq!(header.xing_offset(), 13);
+    }
+
+    #[test]
+    fn mp3_frame_header_invalid() {
+        // No frame sync.
+        assert!(Mp3FrameHeader::parse(&[0xfe, 0xfb, 0x90, 0x00]).is_none());
+        // Reserved version.
+        assert!(Mp3FrameHeader::parse(&[0xff, 0xeb, 0x90, 0x00]).is_none());
+        // Layer II.
+        assert!(Mp3FrameHeader::parse(&[0xff, 0xfd, 0x90, 0x00]).is_none());
+        // Free and invalid bitrates.
+        assert!(Mp3FrameHeader::parse(&[0xff, 0xfb, 0x00, 0x00]).is_none());
+        assert!(Mp3FrameHeader::parse(&[0xff, 0xfb, 0xf0, 0x00]).is_none());
+        // Reserved sample rate.
+        assert!(Mp3FrameHeader::parse(&[0xff, 0xfb, 0x9c, 0x00]).is_none());
+    }
+
+    #[test]
+    fn mp3_cbr() {
+        // 16000 bytes at 128 kbps last one second.
+        let mut bytes = vec![0; 16000];
+        bytes[..4].copy_from_slice(&MPEG1_HEADER);
+        assert_eq!(
+            mp3_info(&bytes),
+            Some(AudioInfo {
+                bitrate: Some(128),
+                duration: Some(1),
+                is_vbr: Some(false),
+                sample_rate: Some(44100),
+                bit_depth: None,
+            })
+        );
+    }
+
+    #[test]
+    fn mp3_xing_after_id3v2() {
+        let mut xing = [0; 16];
+        xing[..4].copy_from_slice(b"Xing");
+        BigEndian::write_u32(&mut xing[4..8], 0x3);
+        BigEndian::write_u32(&mut xing[8..12], 1000);
+        BigEndian::write_u32(&mut xing[12..16], 1_000_000);
+
+        // An ID3v2 tag with a 10 byte body, then some junk before the frame.
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x00\x0a".to_vec();
+        bytes.extend_from_slice(&[0xff; 10]);
+        bytes.extend_from_slice(&[0x00; 5]);
+        bytes.extend(mpeg1_frame(417, 36, &xing));
+
+        // 1000 frames of 1152 samples at 44100 Hz, in 1000000 bytes.
+        assert_eq!(
+            mp3_info(&bytes),
+            Some(AudioInfo {
+                bitrate: Some(306),
+                duration: Some(26),
+                is_vbr: Some(true),
+                sample_rate: Some(44100),
+                bit_depth: None,
+            })
+        );
+    }
+
+    #[test]
+    fn mp3_info_header_is_cbr() {
+        let mut info = [0; 16];
+        info[..4].copy_from_slice(b"Info");
+        BigEndian::write_u32(&mut info[4..8], 0x1);
+        BigEndian::write_u32(&mut info[8..12], 1000);
+
+        let bytes = mpeg1_frame(417, 36, &info);
+        let info = mp3_info(&bytes).unwrap();
+        assert_eq!(info.is_vbr, Some(false));
+        assert_eq!(info.duration, Some(26));
+    }
+
+    #[test]
+    fn mp3_vbri() {
+        let mut vbri = [0; 18];
+        vbri[..4].copy_from_slice(b"VBRI");
+        BigEndian::write_u32(&mut vbri[10..14], 1_000_000);
+        BigEndian::write_u32(&mut vbri[14..18], 1000);
+
+        let bytes = mpeg1_frame(417, 36, &vbri);
+        let info = mp3_info(&bytes).unwrap();
+        assert_eq!(info.is_vbr, Some(true));
+        assert_eq!(info.bitrate, Some(306));
+        assert_eq!(info.duration, Some(26));
+    }
+
+    #[test]
+    fn mp3_truncated() {
+        assert_eq!(mp3_info(&[]), None);
+        assert_eq!(mp3_info(&MPEG1_HEADER[..3]), None);
+        assert_eq!(mp3_info(b"ID3\x04\x00\x00\x00\x00\x7f\x7f"), None);
+        // A Xing header cut short is ignored.
+        let bytes = mpeg1_frame(44, 36, b"Xing\x00\x00\x00\x03");
+        assert_eq!(mp3_info(&bytes).unwrap().is_vbr, Some(false));
+        // So is the header of a frame at the very end.
+        assert!(mp3_info(&MPEG1_HEADER).is_some());
+    }
+
+    #[test]
+    fn flac_stream_info() {
+        let mut bytes = b"fLaC\x80\x00\x00\x22".to_vec();
+        let mut stream_info = [0; 34];
+        // 44100 Hz, 2 channels, 16 bits per sample, 441000 samples.
+        let fields = (44100 << 44) | (1 << 41) | (15 << 36) | 441000;
+        BigEndian::write_u64(&mut stream_info[10..18], fields);
+        bytes.extend_from_slice(&stream_info);
+
+        assert_eq!(
+            flac_info(&bytes, 1_764_000),
+            Some(AudioInfo {
+                bitrate: Some(1411),
+                duration: Some(10),
+                is_vbr: None,
+                sample_rate: Some(44100),
+                bit_depth: Some(16),
+            })
+        );
+
+        // The number of samples may be unknown.
+        BigEndian::write_u64(&mut bytes[18..26], (44100 << 44) | (15 << 36));
+        let info = flac_info(&bytes, 1_764_000).unwrap();
+        assert_eq!(info.duration, None);
+        assert_eq!(info.bitrate, None);
+    }
+
+    #[test]
+    fn flac_invalid() {
+        assert_eq!(flac_info(&[], 0), None);
+        assert_eq!(flac_info(b"fLaC\x80\x00\x00\x22\x00", 9), None);
+        // STREAMINFO must come first.
+        let mut bytes = b"fLaC\x81\x00\x00\x22".to_vec();
+        bytes.extend_from_slice(&[0xff; 34]);
+        assert_eq!(flac_info(&bytes, 42), None);
+    }
+
+    #[test]
+    fn ogg_vorbis() {
+        let mut packet = b"\x01vorbis".to_vec();
+        packet.extend_from_slice(&[0; 23]);
+        packet[11] = 2;
+        LittleEndian::write_u32(&mut packet[12..16], 44100);
+        LittleEndian::write_i32(&mut packet[20..24], 160000);
+
+        let mut bytes = ogg_page(0, &packet);
+        bytes.extend(ogg_page(441000, &[0; 10]));
+        assert_eq!(
+            ogg_info(&bytes),
+            Some(AudioInfo {
+                bitrate: Some(160),
+                duration: Some(10),
+                is_vbr: None,
+                sample_rate: Some(44100),
+                bit_depth: None,
+            })
+        );
+    }
+
+    #[test]
+    fn ogg_opus() {
+        let mut packet = b"OpusHead\x01\x02".to_vec();
+        packet.extend_from_slice(&[0; 9]);
+        LittleEndian::write_u16(&mut packet[10..12], 312);
+        LittleEndian::write_u32(&mut packet[12..16], 44100);
+
+        let mut bytes = ogg_page(0, &packet);
+        bytes.extend(ogg_page(5 * 48000 + 312, &[0; 10]));
+        let info = ogg_info(&bytes).unwrap();
+        assert_eq!(info.duration, Some(5));
+        assert_eq!(info.sample_rate, Some(44100));
+    }
+
+    #[test]
+    fn ogg_truncated() {
+        assert_eq!(ogg_info(&[]), None);
+        assert_eq!(ogg_info(b"OggS\x00\x02"), None);
+        // The segment table runs past the end of the data.
+        let mut bytes = ogg_page(0, b"\x01vorbis");
+        bytes[26] = 255;
+        assert_eq!(ogg_info(&bytes), None);
+        // The identification packet is cut short.
+        assert_eq!(ogg_info(&ogg_page(0, b"\x01vorbis\x00\x00")), None);
+        assert_eq!(ogg_info(&ogg_page(0, b"OpusHead\x01")), None);
+    }
+}
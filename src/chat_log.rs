@@ -0,0 +1,290 @@
+use std::collections;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path;
+
+use rustc_serialize::json;
+
+use room;
+
+/*=======*
+ * ERROR *
+ *=======*/
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    JSONEncoderError(json::EncoderError),
+    JSONDecoderError(json::DecoderError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IOError(ref err) => write!(f, "IOError: {}", err),
+            Error::JSONEncoderError(ref err) => write!(f, "JSONEncoderError: {}", err),
+            Error::JSONDecoderError(ref err) => write!(f, "JSONDecoderError: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::IOError(_) => "IOError",
+            Error::JSONEncoderError(_) => "JSONEncoderError",
+            Error::JSONDecoderError(_) => "JSONDecoderError",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::IOError(ref err) => Some(err),
+            Error::JSONEncoderError(ref err) => Some(err),
+            Error::JSONDecoderError(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl From<json::EncoderError> for Error {
+    fn from(err: json::EncoderError) -> Self {
+        Error::JSONEncoderError(err)
+    }
+}
+
+impl From<json::DecoderError> for Error {
+    fn from(err: json::DecoderError) -> Self {
+        Error::JSONDecoderError(err)
+    }
+}
+
+/*===========*
+ * FILE NAME *
+ *===========*/
+
+const LOG_EXTENSION: &'static str = "log";
+
+/// Returns the name of the log file of the given room, or of the given user
+/// for private conversations.
+/// Names may contain any character, so every byte that might not be allowed
+/// in a file name is percent-encoded.
+fn file_name(room_name: &str) -> String {
+    let mut name = String::new();
+    for &byte in room_name.as_bytes() {
+        if byte.is_ascii_alphanumeric() || b" -_".contains(&byte) {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    name.push('.');
+    name.push_str(LOG_EXTENSION);
+    name
+}
+
+/// Returns the name of the room logged in the file with the given name, or
+/// None if it is not a log file.
+fn room_name(file_name: &str) -> Option<String> {
+    let suffix = format!(".{}", LOG_EXTENSION);
+    if !file_name.ends_with(&suffix) {
+        return None;
+    }
+    let encoded = file_name[..file_name.len() - suffix.len()].as_bytes();
+
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' {
+            let hex = match encoded.get(i + 1..i + 3) {
+                Some(hex) => hex,
+                None => return None,
+            };
+            let hex = match ::std::str::from_utf8(hex) {
+                Ok(hex) => hex,
+                Err(_) => return None,
+            };
+            match u8::from_str_radix(hex, 16) {
+                Ok(byte) => bytes.push(byte),
+                Err(_) => return None,
+            }
+            i += 3;
+        } else {
+            bytes.push(encoded[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/*==========*
+ * CHAT LOG *
+ *==========*/
+
+/// The chat log keeps every message said in the chat rooms we joined on
+/// disk, so that they outlive the client. Private conversations are kept in
+/// a chat log of their own, keyed by the name of the other user instead.
+/// Each room is logged to its own file, which is only ever appended to, one
+/// JSON-encoded message per line.
+#[derive(Debug)]
+pub struct ChatLog {
+    /// The directory containing the log files.
+    dir_path: path::PathBuf,
+}
+
+impl ChatLog {
+    /// Creates a chat log writing to files in the given directory, which is
+    /// created when the first message is logged.
+    pub fn new(dir_path: &str) -> Self {
+        ChatLog {
+            dir_path: path::PathBuf::from(dir_path),
+        }
+    }
+
+    /// Appends the given message to the log of the given room.
+    pub fn append(&self, room_name: &str, message: &room::Message) -> Result<(), Error> {
+        let mut line = json::encode(message)?;
+        line.push('\n');
+
+        fs::create_dir_all(&self.dir_path)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir_path.join(file_name(room_name)))?;
+        // Write the whole line at once, so that a crash cannot interleave
+        // half a message with the next one.
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Calls the given function on every message logged for the given room,
+    /// in chronological order. Lines that cannot be decoded, such as a line
+    /// cut short by a crash, are skipped.
+    fn for_each<F>(&self, room_name: &str, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(room::Message),
+    {
+        let file = match fs::File::open(self.dir_path.join(file_name(room_name))) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(Error::from(err)),
+        };
+
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            match json::decode(&line) {
+                Ok(message) => f(message),
+                Err(err) => warn!("Skipping chat log line in room {:?}: {}", room_name, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the last `max_messages` messages logged for the given room,
+    /// in chronological order.
+    pub fn recent(
+        &self,
+        room_name: &str,
+        max_messages: usize,
+    ) -> Result<Vec<room::Message>, Error> {
+        let mut messages = collections::VecDeque::new();
+        self.for_each(room_name, |message| {
+            messages.push_back(message);
+            if messages.len() > max_messages {
+                messages.pop_front();
+            }
+        })?;
+        Ok(messages.into_iter().collect())
+    }
+
+    /// Returns the names of all the rooms with a log.
+    fn room_names(&self) -> Result<Vec<String>, Error> {
+        let entries = match fs::read_dir(&self.dir_path) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Error::from(err)),
+        };
+
+        let mut room_names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if let Some(room_name) = entry.file_name().to_str().and_then(room_name) {
+                room_names.push(room_name);
+            }
+        }
+        room_names.sort();
+        Ok(room_names)
+    }
+
+    /// Returns the most recent `max_messages` messages accepted by the given
+    /// function, as (room name, message) pairs, in chronological order.
+    /// Only the given room is searched, if any, otherwise all rooms are.
+    pub fn search<F>(
+        &self,
+        room_name: Option<&str>,
+        max_messages: usize,
+        accepts: F,
+    ) -> Result<Vec<(String, room::Message)>, Error>
+    where
+        F: Fn(&room::Message) -> bool,
+    {
+        let room_names = match room_name {
+            Some(room_name) => vec![room_name.to_string()],
+            None => self.room_names()?,
+        };
+
+        let mut results = Vec::new();
+        for room_name in room_names {
+            let mut room_results = collections::VecDeque::new();
+            self.for_each(&room_name, |message| {
+                if accepts(&message) {
+                    room_results.push_back(message);
+                    if room_results.len() > max_messages {
+                        room_results.pop_front();
+                    }
+                }
+            })?;
+            results.extend(
+                room_results
+                    .into_iter()
+                    .map(|message| (room_name.clone(), message)),
+            );
+        }
+
+        results.sort_by_key(|&(_, ref message)| message.timestamp);
+        let num_skipped = results.len().saturating_sub(max_messages);
+        Ok(results.split_off(num_skipped))
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use super::{file_name, room_name};
+
+    #[test]
+    fn file_name_roundtrip() {
+        for name in ["plain room", "a/b\\c", "..", "café %20"].iter() {
+            assert_eq!(room_name(&file_name(name)), Some(name.to_string()));
+        }
+        assert_eq!(file_name("a/b"), "a%2Fb.log");
+    }
+
+    #[test]
+    fn room_name_invalid() {
+        assert_eq!(room_name("room.txt"), None);
+        assert_eq!(room_name("room%2.log"), None);
+        assert_eq!(room_name("room%zz.log"), None);
+    }
+}
@@ -1,15 +1,25 @@
+use std::collections;
+use std::fs;
 use std::net;
 use std::sync::mpsc;
+use std::thread;
+use std::time;
 
 use mio;
 use slab;
 
+use audio;
+use chat_log;
 use config;
 use control;
+use download;
+use highlight;
 use proto;
 use proto::peer;
 use proto::server;
 use room;
+use share;
+use upload;
 use user;
 
 #[derive(Debug)]
@@ -47,19 +57,146 @@ struct Peer {
     state: PeerState,
 }
 
+/// This structure identifies a control request, so that replies to it can be
+/// sent to the controller that made it.
+#[derive(Clone, Copy, Debug)]
+struct Requester {
+    controller_id: usize,
+    /// The id the controller gave the request, if any.
+    request_id: Option<u64>,
+}
+
+/// This structure contains what we know about one of our searches.
+#[derive(Debug)]
+struct Search {
+    query: String,
+    filters: control::SearchFilters,
+    /// The request that started the search, which its results are for.
+    requester: Option<Requester>,
+    /// The number of results forwarded to the controller so far.
+    num_results: usize,
+    /// When the search was started.
+    start_time: time::Instant,
+}
+
+/// Returns the score of the given search result, found by a user with the
+/// given upload queue and speed. Higher is better.
+fn search_result_score(
+    file: &control::SearchResultFile,
+    has_free_slot: bool,
+    average_speed: u32,
+    queue_length: u32,
+) -> i64 {
+    let mut score = 0;
+    if has_free_slot {
+        score += config::SEARCH_SCORE_FREE_SLOT;
+    }
+    score += (average_speed / 1024) as i64 * config::SEARCH_SCORE_PER_KIB_PER_SEC;
+    score += queue_length as i64 * config::SEARCH_SCORE_PER_QUEUED_UPLOAD;
+    if let Some(bitrate) = file.audio.bitrate {
+        score += bitrate as i64 * config::SEARCH_SCORE_PER_KBPS;
+    }
+    score
+}
+
+/// Returns the priority of uploads to the given user.
+fn upload_priority(users: &user::UserMap, user_name: &str) -> upload::Priority {
+    if users.is_privileged(user_name) {
+        upload::Priority::Privileged
+    } else if users.is_buddy(user_name) {
+        upload::Priority::Buddy
+    } else {
+        upload::Priority::Regular
+    }
+}
+
+/// Forwards everything received on the given channel to the given one,
+/// wrapped in an incoming message, until either end is dropped.
+fn forward<T>(
+    rx: mpsc::Receiver<T>,
+    tx: mpsc::Sender<IncomingMessage>,
+    wrap: fn(T) -> IncomingMessage,
+) where
+    T: Send + 'static,
+{
+    thread::spawn(move || {
+        for message in rx.iter() {
+            if tx.send(wrap(message)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
 pub struct Client {
     proto_tx: mio::deprecated::Sender<proto::Request>,
-    proto_rx: mpsc::Receiver<proto::Response>,
+    /// The channel on which messages from both the protocol agent and the
+    /// controllers are received.
+    incoming_rx: mpsc::Receiver<IncomingMessage>,
 
-    control_tx: Option<control::Sender>,
-    control_rx: mpsc::Receiver<control::Notification>,
+    /// The connected controllers, by connection id.
+    controllers: collections::HashMap<usize, control::Sender>,
+    /// The control request being handled, if any, to which replies are sent.
+    requester: Option<Requester>,
 
     login_status: LoginStatus,
+    /// Whether the controller wants the messages said in all public rooms.
+    is_public_chat_enabled: bool,
+    /// Whether the controller told other users it is away.
+    is_away: bool,
 
     rooms: room::RoomMap,
+    chat_log: chat_log::ChatLog,
+    private_chat_log: chat_log::ChatLog,
+    /// The id of the next message of each private conversation, read from
+    /// the private chat log when first needed.
+    private_message_ids: collections::HashMap<String, u64>,
+    highlighter: highlight::Highlighter,
+    auto_join_rooms: room::AutoJoinList,
+    /// Whether the auto-join rooms should be joined once the room list is
+    /// received.
+    is_auto_join_pending: bool,
     users: user::UserMap,
+    downloads: download::DownloadMap,
+    shares: share::ShareMap,
+    /// The scan of the shared directories in progress, if any.
+    share_scan: Option<share::Scan>,
+    /// Watches the shared directories for changes, if possible.
+    share_watcher: Option<share::Watcher>,
+    uploads: upload::UploadQueue,
 
     peers: slab::Slab<Peer, usize>,
+    /// The ids of the "P" peer connections to each user.
+    user_peers: collections::HashMap<String, usize>,
+    /// The messages waiting for a peer connection to each user to open.
+    pending_peer_messages: collections::HashMap<String, Vec<peer::Message>>,
+
+    /// The remote paths of the files users have announced they will upload
+    /// to us, and when they announced them, keyed by user name and transfer
+    /// token.
+    download_tokens: collections::HashMap<(String, u32), (String, time::Instant)>,
+    /// The user name and remote path of the file being received on each
+    /// transfer connection.
+    download_transfers: collections::HashMap<usize, (String, String)>,
+    /// The transfer connections on which data was received since the
+    /// controllers were last told about the progress of their downloads.
+    download_progress: collections::HashSet<usize>,
+    /// The folders we asked users to list for us, and the requests that
+    /// asked for them, keyed by user name and token.
+    folder_requests: collections::HashMap<(String, u32), (String, Option<Requester>)>,
+    /// Our searches, keyed by ticket.
+    searches: collections::HashMap<u32, Search>,
+    /// The user name, remote path and transfer token of the file being sent
+    /// on each transfer connection.
+    upload_transfers: collections::HashMap<usize, (String, String, u32)>,
+    /// When we last asked users where our downloads stand in their queues.
+    last_place_in_queue_poll: time::Instant,
+
+    bandwidth_limits: proto::BandwidthLimits,
+    /// The rates at which files were transferred during the last tick.
+    throughput: proto::Throughput,
+
+    next_token: u32,
 }
 
 impl Client {
@@ -71,24 +208,135 @@ impl Client {
         proto_rx: mpsc::Receiver<proto::Response>,
         control_rx: mpsc::Receiver<control::Notification>,
     ) -> Self {
+        let (incoming_tx, incoming_rx) = mpsc::channel();
+        forward(proto_rx, incoming_tx.clone(), IncomingMessage::Proto);
+        forward(
+            control_rx,
+            incoming_tx,
+            IncomingMessage::ControlNotification,
+        );
+
+        let downloads = match download::DownloadMap::load(config::DOWNLOAD_QUEUE_PATH) {
+            Ok(downloads) => downloads,
+            Err(err) => {
+                error!(
+                    "Cannot load download queue from {:?}: {}",
+                    config::DOWNLOAD_QUEUE_PATH,
+                    err
+                );
+                download::DownloadMap::new(config::DOWNLOAD_QUEUE_PATH)
+            }
+        };
+
+        let auto_join_rooms =
+            match room::AutoJoinList::load(config::AUTO_JOIN_ROOMS_PATH, config::AUTO_JOIN_ROOMS) {
+                Ok(auto_join_rooms) => auto_join_rooms,
+                Err(err) => {
+                    error!(
+                        "Cannot load auto-join rooms from {:?}: {}",
+                        config::AUTO_JOIN_ROOMS_PATH,
+                        err
+                    );
+                    room::AutoJoinList::new(config::AUTO_JOIN_ROOMS_PATH, config::AUTO_JOIN_ROOMS)
+                }
+            };
+
+        let shares = match share::ShareMap::load(config::SHARE_CACHE_PATH, config::SHARED_DIRS) {
+            Ok(shares) => shares,
+            Err(err) => {
+                error!(
+                    "Cannot load share cache from {:?}: {}",
+                    config::SHARE_CACHE_PATH,
+                    err
+                );
+                share::ShareMap::new(config::SHARED_DIRS)
+            }
+        };
+        // Whatever changed while we were not running is picked up by this
+        // first scan, while the cached index is served in the meantime.
+        let share_scan = share::Scan::start(&shares);
+        let delay = time::Duration::from_secs(config::SHARE_WATCH_DELAY_SECS);
+        let share_watcher = match share::Watcher::start(&shares, delay) {
+            Ok(share_watcher) => Some(share_watcher),
+            Err(err) => {
+                error!("Cannot watch shared directories: {}", err);
+                None
+            }
+        };
+
+        let mut users = user::UserMap::new();
+        for user_name in config::BUDDIES {
+            users.insert_buddy(user_name.to_string());
+        }
+
         Client {
             proto_tx: proto_tx,
-            proto_rx: proto_rx,
+            incoming_rx: incoming_rx,
 
-            control_tx: None,
-            control_rx: control_rx,
+            controllers: collections::HashMap::new(),
+            requester: None,
 
             login_status: LoginStatus::Pending,
-
-            rooms: room::RoomMap::new(),
-            users: user::UserMap::new(),
+            is_public_chat_enabled: false,
+            is_away: false,
+
+            rooms: room::RoomMap::new(config::MAX_ROOM_MESSAGES),
+            chat_log: chat_log::ChatLog::new(config::CHAT_LOG_DIR),
+            private_chat_log: chat_log::ChatLog::new(config::PRIVATE_CHAT_LOG_DIR),
+            private_message_ids: collections::HashMap::new(),
+            highlighter: highlight::Highlighter::new(
+                config::USERNAME,
+                config::HIGHLIGHT_WORDS,
+                config::HIGHLIGHT_PATTERNS,
+            ),
+            auto_join_rooms: auto_join_rooms,
+            is_auto_join_pending: false,
+            users: users,
+            downloads: downloads,
+            shares: shares,
+            share_scan: Some(share_scan),
+            share_watcher: share_watcher,
+            uploads: upload::UploadQueue::new(
+                config::MAX_QUEUED_FILES_PER_USER,
+                config::MAX_QUEUED_BYTES_PER_USER,
+            ),
 
             peers: slab::Slab::new(config::MAX_PEERS),
+            user_peers: collections::HashMap::new(),
+            pending_peer_messages: collections::HashMap::new(),
+
+            download_tokens: collections::HashMap::new(),
+            download_transfers: collections::HashMap::new(),
+            download_progress: collections::HashSet::new(),
+            folder_requests: collections::HashMap::new(),
+            searches: collections::HashMap::new(),
+            upload_transfers: collections::HashMap::new(),
+            last_place_in_queue_poll: time::Instant::now(),
+
+            bandwidth_limits: proto::BandwidthLimits::from_config(),
+            throughput: proto::Throughput::default(),
+
+            next_token: 0,
         }
     }
 
     /// Runs the client, potentially forever.
     pub fn run(&mut self) {
+        self.log_in();
+
+        loop {
+            match self.recv() {
+                IncomingMessage::Proto(response) => self.handle_proto_response(response),
+
+                IncomingMessage::ControlNotification(notif) => {
+                    self.handle_control_notification(notif)
+                }
+            }
+        }
+    }
+
+    /// Logs in to the server.
+    fn log_in(&mut self) {
         info!("Logging in...");
         self.send_to_server(server::ServerRequest::LoginRequest(
             server::LoginRequest::new(
@@ -104,22 +352,10 @@ impl Client {
                 port: config::LISTEN_PORT,
             },
         ));
-
-        loop {
-            match self.recv() {
-                IncomingMessage::Proto(response) => self.handle_proto_response(response),
-
-                IncomingMessage::ControlNotification(notif) => {
-                    self.handle_control_notification(notif)
-                }
-            }
-        }
     }
 
-    // Necessary to break out in different function because self cannot be
-    // borrowed in the select arms due to *macro things*.
     fn recv(&mut self) -> IncomingMessage {
-        IncomingMessage::Proto(self.proto_rx.recv().unwrap())
+        self.incoming_rx.recv().unwrap()
     }
 
     /// Send a request to the server.
@@ -136,15 +372,70 @@ impl Client {
             .unwrap();
     }
 
-    /// Send a response to the controller client.
-    fn send_to_controller(&mut self, response: control::Response) {
-        let result = match self.control_tx {
+    /// Send a message to the given user, opening a peer connection to them
+    /// first if need be.
+    fn send_to_user(&mut self, user_name: &str, message: peer::Message) {
+        let peer_opt = match self.user_peers.get(user_name) {
+            Some(&peer_id) => match self.peers.get(peer_id) {
+                Some(&Peer {
+                    state: PeerState::Open,
+                    ..
+                }) => Some((peer_id, true)),
+                Some(_) => Some((peer_id, false)),
+                None => None,
+            },
+            None => None,
+        };
+
+        match peer_opt {
+            Some((peer_id, true)) => self.send_to_peer(peer_id, message),
+
+            Some((_, false)) => {
+                // The message will be sent once the connection is open.
+                self.pending_peer_messages
+                    .entry(user_name.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(message);
+            }
+
+            None => {
+                // Look up the user's address first, the connection will be
+                // opened once we know it.
+                self.pending_peer_messages
+                    .entry(user_name.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(message);
+                self.send_to_server(server::ServerRequest::PeerAddressRequest(
+                    server::PeerAddressRequest {
+                        username: user_name.to_string(),
+                    },
+                ));
+            }
+        }
+    }
+
+    /// Returns a new token, for use in peer connections and transfers.
+    fn new_token(&mut self) -> u32 {
+        let token = self.next_token;
+        self.next_token = self.next_token.wrapping_add(1);
+        token
+    }
+
+    /// Send a response to the given controller client, as a reply to the
+    /// request with the given id if any.
+    fn send_to_controller_id(
+        &mut self,
+        controller_id: usize,
+        request_id: Option<u64>,
+        response: &control::Response,
+    ) {
+        let result = match self.controllers.get_mut(&controller_id) {
             None => {
-                // Silently drop control requests when controller is
+                // Silently drop control responses when the controller is
                 // disconnected.
                 return;
             }
-            Some(ref mut control_tx) => control_tx.send(response),
+            Some(control_tx) => control_tx.send_reply(request_id, response),
         };
         // If we failed to send, we assume it means that the other end of the
         // channel has been dropped, i.e. the controller has disconnected.
@@ -152,31 +443,81 @@ impl Client {
         // a controller again. If that happens, there would have probably been
         // a panic anyway, so we might never hit this corner case.
         if let Err(_) = result {
-            info!("Controller has disconnected.");
-            self.control_tx = None;
+            info!("Controller {} has disconnected.", controller_id);
+            self.controllers.remove(&controller_id);
+        }
+    }
+
+    /// Send a response to all controller clients.
+    fn send_to_controller(&mut self, response: control::Response) {
+        let controller_ids: Vec<usize> = self.controllers.keys().cloned().collect();
+        for controller_id in controller_ids {
+            self.send_to_controller_id(controller_id, None, &response);
+        }
+    }
+
+    /// Send a response to the given requester, or to all controller clients
+    /// if there is none.
+    fn send_to_requester(&mut self, requester: Option<Requester>, response: control::Response) {
+        match requester {
+            Some(requester) => {
+                self.send_to_controller_id(requester.controller_id, requester.request_id, &response)
+            }
+            None => self.send_to_controller(response),
         }
     }
 
+    /// Send a response to the controller client whose request is being
+    /// handled, or to all of them if the response is not a reply.
+    fn reply_to_controller(&mut self, response: control::Response) {
+        let requester = self.requester;
+        self.send_to_requester(requester, response);
+    }
+
+    /// Tell the controller client whose request is being handled that it
+    /// failed, or all of them if the failure is not due to a request.
+    fn reply_error<E>(&mut self, context: &str, err: E)
+    where
+        E: Into<control::ErrorResponse>,
+    {
+        let response = err.into();
+        error!("{}: {}", context, response.message);
+        self.reply_to_controller(control::Response::ErrorResponse(response));
+    }
+
     /*===============================*
      * CONTROL NOTIFICATION HANDLING *
      *===============================*/
 
     fn handle_control_notification(&mut self, notif: control::Notification) {
         match notif {
-            control::Notification::Connected(tx) => {
-                self.control_tx = Some(tx);
+            control::Notification::Connected(controller_id, tx) => {
+                info!("Controller {} has connected.", controller_id);
+                self.controllers.insert(controller_id, tx);
+                // Everything that happens from now on is sent to the new
+                // controller too, so this is all it needs to catch up.
+                let response = control::Response::StateSnapshotResponse(self.state_snapshot());
+                self.send_to_controller_id(controller_id, None, &response);
             }
 
-            control::Notification::Disconnected => {
-                self.control_tx = None;
+            control::Notification::Disconnected(controller_id) => {
+                info!("Controller {} has disconnected.", controller_id);
+                self.controllers.remove(&controller_id);
             }
 
             control::Notification::Error(e) => {
                 debug!("Control loop error: {}", e);
-                self.control_tx = None;
+                self.controllers.clear();
             }
 
-            control::Notification::Request(req) => self.handle_control_request(req),
+            control::Notification::Request(controller_id, request_id, req) => {
+                self.requester = Some(Requester {
+                    controller_id: controller_id,
+                    request_id: request_id,
+                });
+                self.handle_control_request(req);
+                self.requester = None;
+            }
         }
     }
 
@@ -186,6 +527,10 @@ impl Client {
 
     fn handle_control_request(&mut self, request: control::Request) {
         match request {
+            // The handshake is done by the control loop, before any request
+            // reaches us.
+            control::Request::HelloRequest(_) => (),
+
             control::Request::LoginStatusRequest => self.handle_login_status_request(),
 
             control::Request::RoomJoinRequest(room_name) => {
@@ -202,19 +547,100 @@ impl Client {
                 self.handle_room_message_request(request)
             }
 
+            control::Request::RoomHistoryRequest(request) => {
+                self.handle_room_history_request(request)
+            }
+
+            control::Request::RoomMarkReadRequest(room_name) => {
+                self.handle_room_mark_read_request(room_name)
+            }
+
+            control::Request::ChatLogSearchRequest(request) => {
+                self.handle_chat_log_search_request(request)
+            }
+
             control::Request::UserListRequest => self.handle_user_list_request(),
-            /*
-            _ =>{
-                error!("Unhandled control request: {:?}", request);
-            },
-            */
+
+            control::Request::DownloadRequest(request) => self.handle_download_request(request),
+
+            control::Request::DownloadFolderRequest(request) => {
+                self.handle_download_folder_request(request)
+            }
+
+            control::Request::DownloadListRequest => self.handle_download_list_request(),
+
+            control::Request::BandwidthRequest => self.handle_bandwidth_request(),
+
+            control::Request::SetBandwidthLimitsRequest(limits) => {
+                self.handle_set_bandwidth_limits_request(limits)
+            }
+
+            control::Request::SearchRequest(request) => self.handle_search_request(request),
+
+            control::Request::EnablePublicChatRequest => self.handle_enable_public_chat_request(),
+
+            control::Request::DisablePublicChatRequest => self.handle_disable_public_chat_request(),
+
+            control::Request::ShareRescanRequest => self.handle_share_rescan_request(),
         }
     }
 
-    fn handle_login_status_request(&mut self) {
+    /// Returns everything a newly connected controller needs to know.
+    fn state_snapshot(&self) -> control::StateSnapshotResponse {
+        let mut rooms = Vec::new();
+        for (room_name, room) in self.rooms.get_joined_rooms() {
+            let messages = self
+                .rooms
+                .get_messages(&room_name, None, None, config::SNAPSHOT_ROOM_MESSAGES)
+                .unwrap_or_else(|err| {
+                    error!("Cannot get messages of room {:?}: {}", room_name, err);
+                    Vec::new()
+                });
+            rooms.push(control::RoomSnapshot {
+                room_name: room_name,
+                room: room,
+                messages: messages,
+            });
+        }
+
+        let uploads = self
+            .uploads
+            .get_list()
+            .into_iter()
+            .map(|upload| control::UploadInfo {
+                is_active: upload.state != upload::State::Queued,
+                user_name: upload.user_name,
+                remote_path: upload.remote_path,
+                size: upload.size,
+                bytes_sent: upload.bytes_sent,
+            })
+            .collect();
+
+        let searches = self
+            .searches
+            .iter()
+            .map(|(&ticket, search)| control::SearchSnapshot {
+                ticket: ticket,
+                query: search.query.clone(),
+                filters: search.filters.clone(),
+                num_results: search.num_results,
+            })
+            .collect();
+
+        control::StateSnapshotResponse {
+            login_status: self.login_status_response(),
+            rooms: rooms,
+            users: self.users.get_list(),
+            downloads: self.downloads.get_list(),
+            uploads: uploads,
+            searches: searches,
+        }
+    }
+
+    fn login_status_response(&self) -> control::LoginStatusResponse {
         let username = config::USERNAME.to_string();
 
-        let response = match self.login_status {
+        match self.login_status {
             LoginStatus::Pending => control::LoginStatusResponse::Pending { username: username },
 
             LoginStatus::Success(ref motd) => control::LoginStatusResponse::Success {
@@ -226,26 +652,24 @@ impl Client {
                 username: username,
                 reason: reason.clone(),
             },
-        };
-        self.send_to_controller(control::Response::LoginStatusResponse(response));
+        }
     }
 
-    fn handle_room_join_request(&mut self, room_name: String) {
-        match self.rooms.start_joining(&room_name) {
-            Ok(()) => {
-                info!("Requesting to join room {:?}", room_name);
-                self.send_to_server(server::ServerRequest::RoomJoinRequest(
-                    server::RoomJoinRequest {
-                        room_name: room_name,
-                    },
-                ));
-            }
+    fn handle_login_status_request(&mut self) {
+        let response = self.login_status_response();
+        self.reply_to_controller(control::Response::LoginStatusResponse(response));
+    }
 
-            Err(err) => error!("RoomLeaveRequest: {}", err),
+    fn handle_room_join_request(&mut self, room_name: String) {
+        // The room is only joined automatically from now on once we know
+        // joining it works.
+        if let Err(err) = self.join_room(room_name) {
+            self.reply_error("RoomJoinRequest", err);
         }
     }
 
     fn handle_room_leave_request(&mut self, room_name: String) {
+        self.auto_join_rooms.remove(&room_name);
         match self.rooms.start_leaving(&room_name) {
             Ok(()) => {
                 info!("Requesting to leave room {:?}", room_name);
@@ -256,14 +680,14 @@ impl Client {
                 ));
             }
 
-            Err(err) => error!("RoomLeaveRequest: {}", err),
+            Err(err) => self.reply_error("RoomLeaveRequest", err),
         }
     }
 
     fn handle_room_list_request(&mut self) {
         // First send the controller client what we have in memory.
         let rooms = self.rooms.get_room_list();
-        self.send_to_controller(control::Response::RoomListResponse(
+        self.reply_to_controller(control::Response::RoomListResponse(
             control::RoomListResponse { rooms: rooms },
         ));
         // Then ask the server for an updated version, which will be forwarded
@@ -272,170 +696,1715 @@ impl Client {
     }
 
     fn handle_room_message_request(&mut self, request: control::RoomMessageRequest) {
-        self.send_to_server(server::ServerRequest::RoomMessageRequest(
-            server::RoomMessageRequest {
-                room_name: request.room_name,
-                message: request.message,
-            },
-        ));
+        let command = match control::Command::parse(&request.message) {
+            Ok(command) => command,
+            Err(error) => {
+                self.reply_error(
+                    "RoomMessageRequest",
+                    control::ErrorResponse::new(control::ErrorCode::InvalidCommand, error),
+                );
+                return;
+            }
+        };
+
+        match command {
+            control::Command::Say(message) => self.send_to_server(
+                server::ServerRequest::RoomMessageRequest(server::RoomMessageRequest {
+                    room_name: request.room_name,
+                    message: message,
+                }),
+            ),
+
+            control::Command::Join(room_name) => self.handle_room_join_request(room_name),
+
+            control::Command::Leave(room_name_opt) => {
+                self.handle_room_leave_request(room_name_opt.unwrap_or(request.room_name))
+            }
+
+            control::Command::Msg(user_name, message) => {
+                self.send_to_server(server::ServerRequest::MessageUserRequest(
+                    server::MessageUserRequest {
+                        user_name: user_name.clone(),
+                        message: message.clone(),
+                    },
+                ));
+                let timestamp = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+                    Ok(duration) => duration.as_secs(),
+                    Err(_) => 0,
+                };
+                self.add_private_message(
+                    user_name,
+                    config::USERNAME.to_string(),
+                    message,
+                    timestamp,
+                );
+            }
+
+            control::Command::Away => {
+                self.is_away = !self.is_away;
+                // Otherwise the status is sent upon logging in.
+                if let LoginStatus::Success(_) = self.login_status {
+                    self.send_status();
+                }
+            }
+
+            control::Command::Ignore(user_name) => {
+                info!("Ignoring user {:?}", user_name);
+                self.users.insert_ignored(user_name);
+            }
+
+            control::Command::Unignore(user_name) => {
+                info!("No longer ignoring user {:?}", user_name);
+                self.users.remove_ignored(&user_name);
+            }
+
+            control::Command::Search(query) => self.handle_search_request(control::SearchRequest {
+                query: query,
+                filters: control::SearchFilters::default(),
+            }),
+
+            control::Command::Browse(user_name) => {
+                info!("Browsing the shares of user {:?}", user_name);
+                self.send_to_user(&user_name, peer::Message::SharedFileListRequest);
+            }
+
+            control::Command::Info(user_name) => {
+                // The stats are only forwarded for users we know of. Watching
+                // the user makes the server tell us about them first.
+                self.send_to_server(server::ServerRequest::WatchUserRequest(
+                    server::WatchUserRequest {
+                        user_name: user_name.clone(),
+                    },
+                ));
+                self.send_to_server(server::ServerRequest::UserInfoRequest(
+                    server::UserInfoRequest {
+                        user_name: user_name,
+                    },
+                ))
+            }
+        }
+    }
+
+    fn handle_room_history_request(&mut self, request: control::RoomHistoryRequest) {
+        let result = self.rooms.get_messages(
+            &request.room_name,
+            request.before,
+            request.after,
+            request.limit,
+        );
+        match result {
+            Ok(messages) => self.reply_to_controller(control::Response::RoomHistoryResponse(
+                control::RoomHistoryResponse {
+                    room_name: request.room_name,
+                    messages: messages,
+                },
+            )),
+
+            Err(err) => self.reply_error("RoomHistoryRequest", err),
+        }
+    }
+
+    fn handle_room_mark_read_request(&mut self, room_name: String) {
+        if let Err(err) = self.rooms.mark_read(&room_name) {
+            self.reply_error("RoomMarkReadRequest", err);
+        }
+    }
+
+    fn handle_chat_log_search_request(&mut self, request: control::ChatLogSearchRequest) {
+        let search_all = request.room_name.is_none() && request.peer_name.is_none();
+        let search = |chat_log: &chat_log::ChatLog, name: &Option<String>| {
+            if name.is_none() && !search_all {
+                return Ok(Vec::new());
+            }
+            chat_log.search(
+                name.as_ref().map(|name| &**name),
+                request.limit,
+                |message| request.accepts(message),
+            )
+        };
+
+        let result = search(&self.chat_log, &request.room_name).and_then(|messages| {
+            let private_messages = search(&self.private_chat_log, &request.peer_name)?;
+            Ok(control::ChatLogSearchResponse {
+                messages: messages,
+                private_messages: private_messages,
+            })
+        });
+        match result {
+            Ok(response) => {
+                self.reply_to_controller(control::Response::ChatLogSearchResponse(response))
+            }
+
+            Err(err) => self.reply_error("ChatLogSearchRequest", err),
+        }
     }
 
     fn handle_user_list_request(&mut self) {
         // Send the controller client what we have in memory.
         let user_list = self.users.get_list();
-        self.send_to_controller(control::Response::UserListResponse(
+        self.reply_to_controller(control::Response::UserListResponse(
             control::UserListResponse {
                 user_list: user_list,
             },
         ));
     }
 
-    /*=========================*
-     * PROTO RESPONSE HANDLING *
-     *=========================*/
+    fn handle_download_request(&mut self, request: control::DownloadRequest) {
+        let download = download::Download::new(
+            request.user_name,
+            request.file_name,
+            request.size,
+            config::DOWNLOAD_DIR,
+        );
+        let user_name = download.user_name.clone();
 
-    fn handle_proto_response(&mut self, response: proto::Response) {
-        match response {
-            proto::Response::ServerResponse(server_response) => {
-                self.handle_server_response(server_response)
+        if let Err(err) = self.downloads.enqueue(download.clone()) {
+            self.reply_error("DownloadRequest", err);
+            return;
+        }
+
+        info!(
+            "Queued download of {:?} from user {:?}",
+            download.remote_path, user_name
+        );
+        self.send_to_controller(control::Response::DownloadResponse(
+            control::DownloadResponse { download: download },
+        ));
+
+        // Find out if the user is online. If so, the download is requested
+        // as soon as we hear back from the server.
+        self.send_to_server(server::ServerRequest::WatchUserRequest(
+            server::WatchUserRequest {
+                user_name: user_name,
+            },
+        ));
+    }
+
+    fn handle_download_folder_request(&mut self, request: control::DownloadFolderRequest) {
+        info!(
+            "Asking user {:?} for the contents of folder {:?}",
+            request.user_name, request.folder_name
+        );
+        // The files are queued once we know what they are.
+        let token = self.new_token();
+        self.folder_requests.insert(
+            (request.user_name.clone(), token),
+            (request.folder_name.clone(), self.requester),
+        );
+        self.send_to_user(
+            &request.user_name,
+            peer::Message::FolderContentsRequest(peer::FolderContentsRequest {
+                token: token,
+                folder_name: request.folder_name,
+            }),
+        );
+    }
+
+    fn handle_download_list_request(&mut self) {
+        let downloads = self.downloads.get_list();
+        self.reply_to_controller(control::Response::DownloadListResponse(
+            control::DownloadListResponse {
+                downloads: downloads,
+            },
+        ));
+    }
+
+    fn handle_bandwidth_request(&mut self) {
+        let limits = self.bandwidth_limits.clone();
+        let throughput = self.throughput.clone();
+        self.reply_to_controller(control::Response::BandwidthResponse(
+            control::BandwidthResponse {
+                limits: limits,
+                throughput: throughput,
+            },
+        ));
+    }
+
+    fn handle_set_bandwidth_limits_request(&mut self, limits: proto::BandwidthLimits) {
+        if limits.has_zero_rate() {
+            self.reply_error(
+                "SetBandwidthLimitsRequest",
+                control::ErrorResponse::new(
+                    control::ErrorCode::InvalidRequest,
+                    "rate limits must be positive, or null for no limit".to_string(),
+                ),
+            );
+            return;
+        }
+        self.bandwidth_limits = limits.clone();
+        self.proto_tx
+            .send(proto::Request::SetBandwidthLimits(limits))
+            .unwrap();
+        self.handle_bandwidth_request();
+    }
+
+    fn handle_search_request(&mut self, request: control::SearchRequest) {
+        info!("Searching for {:?}", request.query);
+        // Results are filtered and forwarded to the controller as they come
+        // in.
+        let ticket = self.new_token();
+        self.searches.insert(
+            ticket,
+            Search {
+                query: request.query.clone(),
+                filters: request.filters,
+                requester: self.requester,
+                num_results: 0,
+                start_time: time::Instant::now(),
+            },
+        );
+        self.reply_to_controller(control::Response::SearchResponse(control::SearchResponse {
+            ticket: ticket,
+            query: request.query.clone(),
+        }));
+        self.send_to_server(server::ServerRequest::FileSearchRequest(
+            server::FileSearchRequest {
+                ticket: ticket,
+                query: request.query,
+            },
+        ));
+    }
+
+    fn handle_enable_public_chat_request(&mut self) {
+        self.is_public_chat_enabled = true;
+        // Otherwise the request is sent upon logging in.
+        if let LoginStatus::Success(_) = self.login_status {
+            self.send_to_server(server::ServerRequest::EnablePublicChatRequest);
+        }
+    }
+
+    fn handle_disable_public_chat_request(&mut self) {
+        self.is_public_chat_enabled = false;
+        if let LoginStatus::Success(_) = self.login_status {
+            self.send_to_server(server::ServerRequest::DisablePublicChatRequest);
+        }
+    }
+
+    fn handle_share_rescan_request(&mut self) {
+        if self.share_scan.is_some() {
+            self.reply_error(
+                "ShareRescanRequest",
+                control::ErrorResponse::new(
+                    control::ErrorCode::ShareScanInProgress,
+                    "shared directories are already being scanned".to_string(),
+                ),
+            );
+            return;
+        }
+        info!("Scanning shared directories");
+        self.share_scan = Some(share::Scan::start(&self.shares));
+    }
+
+    /// Tells the server whether we are away or online.
+    fn send_status(&mut self) {
+        let status = if self.is_away {
+            proto::UserStatus::Away
+        } else {
+            proto::UserStatus::Online
+        };
+        info!("Setting status to {:?}", status);
+        self.send_to_server(server::ServerRequest::SetStatusRequest(
+            server::SetStatusRequest { status: status },
+        ));
+    }
+
+    /*===============*
+     * ROOM HANDLING *
+     *===============*/
+
+    /// Asks the server to let us join the given room.
+    fn join_room(&mut self, room_name: String) -> Result<(), room::Error> {
+        self.rooms.start_joining(&room_name)?;
+
+        info!("Requesting to join room {:?}", room_name);
+        self.send_to_server(server::ServerRequest::RoomJoinRequest(
+            server::RoomJoinRequest {
+                room_name: room_name,
+            },
+        ));
+        Ok(())
+    }
+
+    /*================*
+     * SHARE HANDLING *
+     *================*/
+
+    /// Checks on the scan of the shared directories in progress, if any.
+    /// Once it is over, its results replace the index and are persisted.
+    /// The controller is kept informed either way.
+    fn poll_share_scan(&mut self) {
+        let shares_opt = match self.share_scan {
+            Some(ref scan) => scan.try_finish(),
+            None => return,
+        };
+
+        let response = match shares_opt {
+            None => {
+                let progress = self.share_scan.as_ref().unwrap().progress();
+                control::ShareScanResponse {
+                    is_done: false,
+                    num_folders: progress.num_folders,
+                    num_files: progress.num_files,
+                }
             }
 
-            proto::Response::PeerConnectionOpen(peer_id) => {
-                self.handle_peer_connection_open(peer_id)
+            Some(mut shares) => {
+                self.share_scan = None;
+                shares.set_excluded_phrases(self.shares.excluded_phrases());
+                self.shares = shares;
+                self.shares_changed();
+                control::ShareScanResponse {
+                    is_done: true,
+                    num_folders: self.shares.num_folders(),
+                    num_files: self.shares.num_files(),
+                }
+            }
+        };
+        self.send_to_controller(control::Response::ShareScanResponse(response));
+    }
+
+    /// Applies the changes made to the shared directories since the last
+    /// call, as notified by the filesystem.
+    fn apply_share_changes(&mut self) {
+        let changes = match self.share_watcher {
+            Some(ref share_watcher) => share_watcher.poll(),
+            None => return,
+        };
+
+        if changes.needs_rescan {
+            info!("Shared directories changed too much, scanning them again");
+            self.share_scan = Some(share::Scan::start(&self.shares));
+            return;
+        }
+        if changes.dirs.is_empty() {
+            return;
+        }
+
+        for dir_path in changes.dirs.iter() {
+            info!("Shared directory {:?} changed", dir_path);
+            self.shares.refresh_dir(dir_path);
+        }
+        self.shares_changed();
+    }
+
+    /// Persists the index of shared files, which changed, and lets the
+    /// server know how much we share.
+    fn shares_changed(&mut self) {
+        info!(
+            "Sharing {} files in {} folders publicly",
+            self.shares.num_files(),
+            self.shares.num_folders()
+        );
+        if let Err(err) = self.shares.save(config::SHARE_CACHE_PATH) {
+            error!(
+                "Cannot save share cache to {:?}: {}",
+                config::SHARE_CACHE_PATH,
+                err
+            );
+        }
+        if let LoginStatus::Success(_) = self.login_status {
+            self.send_shared_folders_files();
+        }
+    }
+
+    /// Tells the server how many folders and files we share.
+    fn send_shared_folders_files(&mut self) {
+        let num_folders = self.shares.num_folders() as u32;
+        let num_files = self.shares.num_files() as u32;
+        self.send_to_server(server::ServerRequest::SharedFoldersFilesRequest(
+            server::SharedFoldersFilesRequest {
+                num_folders: num_folders,
+                num_files: num_files,
+            },
+        ));
+    }
+
+    /*===================*
+     * DOWNLOAD HANDLING *
+     *===================*/
+
+    /// Asks the given user to queue uploads of all the files we are waiting
+    /// to download from them.
+    fn request_downloads(&mut self, user_name: &str) {
+        let remote_paths = self.downloads.start_requesting(user_name);
+        if remote_paths.is_empty() {
+            return;
+        }
+
+        info!(
+            "Requesting {} queued downloads from user {:?}",
+            remote_paths.len(),
+            user_name
+        );
+        for remote_path in remote_paths {
+            self.send_to_user(
+                user_name,
+                peer::Message::QueueUpload(peer::QueueUpload {
+                    file_name: remote_path,
+                }),
+            );
+        }
+        self.send_downloads_to_controller(user_name);
+    }
+
+    /// Sends the last known information about the downloads from the given
+    /// user to the controller.
+    fn send_downloads_to_controller(&mut self, user_name: &str) {
+        for download in self.downloads.get_user_list(user_name) {
+            self.send_to_controller(control::Response::DownloadResponse(
+                control::DownloadResponse { download: download },
+            ));
+        }
+    }
+
+    /// Asks remote users where the downloads they have queued for us stand in
+    /// their upload queues.
+    fn poll_places_in_queue(&mut self) {
+        for (user_name, remote_path) in self.downloads.requested() {
+            self.send_to_user(
+                &user_name,
+                peer::Message::PlaceInQueueRequest(peer::PlaceInQueueRequest {
+                    file_name: remote_path,
+                }),
+            );
+        }
+    }
+
+    /// Starts receiving the given download on the given transfer connection,
+    /// resuming from the end of the partial file if there is one.
+    /// Returns the file to write to, the offset from which to receive the
+    /// file and the expected size of the file.
+    fn start_download_transfer(
+        &mut self,
+        peer_id: usize,
+        user_name: &str,
+        remote_path: &str,
+    ) -> Result<(fs::File, u64, u64), String> {
+        let download = match self.downloads.get(user_name, remote_path) {
+            Some(download) => download.clone(),
+            None => return Err("download not found".to_string()),
+        };
+
+        let file = match download.open_incomplete_file(config::INCOMPLETE_DIR) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("cannot open partial file: {}", err)),
+        };
+        let offset = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(err) => return Err(format!("cannot read partial file size: {}", err)),
+        };
+        if offset > download.size {
+            return Err(format!(
+                "partial file is larger than advertised size {}",
+                download.size
+            ));
+        }
+
+        if let Err(err) = self
+            .downloads
+            .start_transferring(user_name, remote_path, offset)
+        {
+            return Err(format!("{}", err));
+        }
+        self.download_transfers
+            .insert(peer_id, (user_name.to_string(), remote_path.to_string()));
+
+        info!(
+            "Receiving {:?} from user {:?} from offset {} of {}",
+            remote_path, user_name, offset, download.size
+        );
+        Ok((file, offset, download.size))
+    }
+
+    /// Checks the partial file of the given download, whose transfer
+    /// connection has closed.
+    /// If it has the advertised size, it is moved to the download directory.
+    /// If it is shorter, the download is requested again, to be resumed
+    /// where it stopped.
+    fn finish_download_transfer(&mut self, user_name: &str, remote_path: &str) {
+        let download = match self.downloads.get(user_name, remote_path) {
+            Some(download) => download.clone(),
+            None => return,
+        };
+
+        let incomplete_path = download.incomplete_path(config::INCOMPLETE_DIR);
+        let bytes_received = match fs::metadata(&incomplete_path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) => {
+                error!(
+                    "Cannot read size of partial file {:?}: {}",
+                    incomplete_path, err
+                );
+                let result = self.downloads.set_state(
+                    user_name,
+                    remote_path,
+                    download::State::Failed(format!("cannot read partial file: {}", err)),
+                );
+                if let Err(err) = result {
+                    error!("Cannot update download: {}", err);
+                }
+                self.send_downloads_to_controller(user_name);
+                return;
+            }
+        };
+
+        let state = if bytes_received < download.size {
+            info!(
+                "Download of {:?} from user {:?} interrupted after {} of {} bytes",
+                remote_path, user_name, bytes_received, download.size
+            );
+            let num_stalls =
+                self.downloads
+                    .record_interruption(user_name, remote_path, bytes_received);
+            if num_stalls > config::MAX_DOWNLOAD_RETRIES {
+                download::State::Failed(format!(
+                    "interrupted {} times in a row without progress",
+                    num_stalls
+                ))
+            } else {
+                download::State::Queued
+            }
+        } else if bytes_received > download.size {
+            download::State::Failed(format!(
+                "received {} bytes, expected {}",
+                bytes_received, download.size
+            ))
+        } else {
+            match download.move_incomplete_file(config::INCOMPLETE_DIR) {
+                Ok(local_path) => {
+                    info!(
+                        "Downloaded {:?} from user {:?} to {:?}",
+                        remote_path, user_name, local_path
+                    );
+                    if let Err(err) =
+                        self.downloads
+                            .set_local_path(user_name, remote_path, local_path)
+                    {
+                        error!("Cannot update download: {}", err);
+                    }
+                    download::State::Complete
+                }
+                Err(err) => download::State::Failed(format!("cannot move file: {}", err)),
+            }
+        };
+        let is_queued = state == download::State::Queued;
+
+        let result = self
+            .downloads
+            .set_bytes_received(user_name, remote_path, bytes_received)
+            .and_then(|()| self.downloads.set_state(user_name, remote_path, state));
+        if let Err(err) = result {
+            error!("Cannot update download: {}", err);
+        }
+
+        if let Some(download) = self.downloads.get(user_name, remote_path).cloned() {
+            self.send_to_controller(control::Response::DownloadResponse(
+                control::DownloadResponse { download: download },
+            ));
+        }
+        if is_queued {
+            self.request_downloads(user_name);
+        }
+    }
+
+    /// Tells the controllers how far the downloads that made progress since
+    /// the last tick have come.
+    fn send_download_progress(&mut self) {
+        let peer_ids: Vec<usize> = self.download_progress.drain().collect();
+        for peer_id in peer_ids {
+            let download = match self.download_transfers.get(&peer_id) {
+                Some(&(ref user_name, ref remote_path)) => {
+                    self.downloads.get(user_name, remote_path).cloned()
+                }
+                None => None,
+            };
+            if let Some(download) = download {
+                self.send_to_controller(control::Response::DownloadResponse(
+                    control::DownloadResponse { download: download },
+                ));
+            }
+        }
+    }
+
+    /*=================*
+     * UPLOAD HANDLING *
+     *=================*/
+
+    /// Queues the upload of the given shared file to the given user.
+    /// Returns the reason to give the user if it cannot be queued.
+    fn queue_upload(&mut self, user_name: &str, remote_path: &str) -> Result<(), String> {
+        let is_buddy = self.users.is_buddy(user_name);
+        let shared_file = match self.shares.get(remote_path, is_buddy) {
+            Some(shared_file) => shared_file.clone(),
+            None => return Err("File not shared.".to_string()),
+        };
+
+        let upload = upload::Upload::new(
+            user_name.to_string(),
+            remote_path.to_string(),
+            shared_file.local_path,
+            shared_file.size,
+        );
+        match self.uploads.enqueue(upload) {
+            Ok(()) => info!("Queued upload of {:?} to user {:?}", remote_path, user_name),
+            // Users ask again when they think we might have forgotten.
+            Err(upload::Error::UploadAlreadyQueued(_, _)) => (),
+            Err(upload::Error::TooManyFiles(_)) => return Err("Too many files".to_string()),
+            Err(upload::Error::TooManyBytes(_)) => return Err("Too many megabytes".to_string()),
+            Err(err) => return Err(format!("{}", err)),
+        }
+
+        self.start_uploads();
+        Ok(())
+    }
+
+    /// Gives the free upload slots to the next uploads in the queue, asking
+    /// their downloaders whether they are ready to receive them.
+    fn start_uploads(&mut self) {
+        while self.uploads.num_active() < config::MAX_UPLOAD_SLOTS {
+            let token = self.new_token();
+            let upload = {
+                let users = &self.users;
+                match self
+                    .uploads
+                    .start_next(|user_name| upload_priority(users, user_name), token)
+                {
+                    Some(upload) => upload,
+                    None => return,
+                }
+            };
+
+            info!(
+                "Offering {:?} to user {:?}",
+                upload.remote_path, upload.user_name
+            );
+            self.send_to_user(
+                &upload.user_name,
+                peer::Message::TransferRequest(peer::TransferRequest {
+                    direction: peer::TransferDirection::Upload,
+                    token: token,
+                    file_name: upload.remote_path,
+                    file_size: Some(upload.size),
+                }),
+            );
+        }
+    }
+
+    /// Gives up on the given uploads, which never started, and gives their
+    /// slots to the next uploads in the queue.
+    fn abandon_upload_requests(&mut self, uploads: Vec<upload::Upload>) {
+        if uploads.is_empty() {
+            return;
+        }
+        for upload in uploads {
+            info!(
+                "User {:?} did not accept upload of {:?}, giving up",
+                upload.user_name, upload.remote_path
+            );
+        }
+        self.start_uploads();
+    }
+
+    /// Opens a transfer connection to send the file the given user has
+    /// agreed to receive as the transfer identified by the given token.
+    fn start_upload_transfer(&mut self, user_name: &str, token: u32) {
+        let remote_path = match self.uploads.get_requested(user_name, token) {
+            Some(upload) => upload.remote_path.clone(),
+            None => {
+                warn!("User {:?} accepted unknown transfer {}", user_name, token);
+                return;
+            }
+        };
+
+        // Transfer connections go to the same address as message
+        // connections.
+        let address_opt = self
+            .user_peers
+            .get(user_name)
+            .and_then(|&peer_id| self.peers.get(peer_id))
+            .map(|peer| (peer.ip, peer.port));
+        let (ip, port) = match address_opt {
+            Some(address) => address,
+            None => {
+                self.fail_upload(user_name, &remote_path, "no address to connect to");
+                return;
+            }
+        };
+
+        let peer = Peer {
+            user_name: user_name.to_string(),
+            ip: ip,
+            port: port,
+            connection_type: "F".to_string(),
+            token: self.new_token(),
+            state: PeerState::Opening,
+        };
+
+        match self.peers.insert(peer) {
+            Ok(peer_id) => {
+                info!(
+                    "Opening transfer connection {} to {}:{} to upload {:?}",
+                    peer_id, ip, port, remote_path
+                );
+                if let Err(err) = self.uploads.start_transferring(user_name, &remote_path) {
+                    error!("Cannot update upload: {}", err);
+                }
+                self.upload_transfers
+                    .insert(peer_id, (user_name.to_string(), remote_path, token));
+                self.proto_tx
+                    .send(proto::Request::TransferConnect(
+                        peer_id,
+                        user_name.to_string(),
+                        proto::TransferMode::Send,
+                        ip,
+                        port,
+                    ))
+                    .unwrap();
+            }
+
+            Err(peer) => {
+                warn!(
+                    "Cannot open peer connection {:?}: too many already open",
+                    peer
+                );
+                self.fail_upload(user_name, &remote_path, "too many connections");
+            }
+        }
+    }
+
+    /// Starts sending the file on the given transfer connection, now that it
+    /// is open.
+    fn send_upload(&mut self, peer_id: usize) {
+        let (user_name, remote_path, token) = match self.upload_transfers.get(&peer_id) {
+            Some(transfer) => transfer.clone(),
+            None => return,
+        };
+        let upload = match self.uploads.get(&user_name, &remote_path) {
+            Some(upload) => upload.clone(),
+            None => return,
+        };
+
+        match fs::File::open(&upload.local_path) {
+            Ok(file) => {
+                info!("Sending {:?} to user {:?}", remote_path, user_name);
+                self.proto_tx
+                    .send(proto::Request::TransferSend(
+                        peer_id,
+                        file,
+                        token,
+                        upload.size,
+                    ))
+                    .unwrap();
+            }
+            Err(err) => {
+                error!("Cannot open {:?}: {}", upload.local_path, err);
+                // The upload fails once the connection is closed.
+                self.proto_tx
+                    .send(proto::Request::TransferClose(peer_id))
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Frees the slot of the given upload, whose transfer connection has
+    /// closed, and lets the user know if the file was not sent entirely.
+    fn finish_upload_transfer(&mut self, user_name: &str, remote_path: &str) {
+        let upload = match self.uploads.finish(user_name, remote_path) {
+            Ok(upload) => upload,
+            Err(err) => {
+                error!("Cannot finish upload: {}", err);
+                return;
+            }
+        };
+
+        if upload.bytes_sent == upload.size {
+            info!("Uploaded {:?} to user {:?}", remote_path, user_name);
+        } else {
+            info!(
+                "Upload of {:?} to user {:?} interrupted after {} of {} bytes",
+                remote_path, user_name, upload.bytes_sent, upload.size
+            );
+            self.send_to_user(
+                user_name,
+                peer::Message::UploadFailed(peer::UploadFailed {
+                    file_name: remote_path.to_string(),
+                }),
+            );
+        }
+        self.start_uploads();
+    }
+
+    /// Gives up on the given upload before its transfer connection opened.
+    fn fail_upload(&mut self, user_name: &str, remote_path: &str, reason: &str) {
+        error!(
+            "Cannot upload {:?} to user {:?}: {}",
+            remote_path, user_name, reason
+        );
+        if let Err(err) = self.uploads.finish(user_name, remote_path) {
+            error!("Cannot finish upload: {}", err);
+        }
+        self.send_to_user(
+            user_name,
+            peer::Message::UploadFailed(peer::UploadFailed {
+                file_name: remote_path.to_string(),
+            }),
+        );
+        self.start_uploads();
+    }
+
+    /*=========================*
+     * PROTO RESPONSE HANDLING *
+     *=========================*/
+
+    fn handle_proto_response(&mut self, response: proto::Response) {
+        match response {
+            proto::Response::ServerResponse(server_response) => {
+                self.handle_server_response(server_response)
+            }
+
+            proto::Response::PeerConnectionOpen(peer_id) => {
+                self.handle_peer_connection_open(peer_id)
+            }
+
+            proto::Response::PeerConnectionClosed(peer_id) => {
+                self.handle_peer_connection_closed(peer_id)
+            }
+
+            proto::Response::ServerConnectionClosed => self.handle_server_connection_closed(),
+
+            proto::Response::ServerConnectionOpen => self.log_in(),
+
+            proto::Response::PeerMessage(peer_id, message) => {
+                self.handle_peer_message(peer_id, message)
+            }
+
+            proto::Response::TransferToken(peer_id, token) => {
+                self.handle_transfer_token(peer_id, token)
+            }
+
+            proto::Response::TransferProgress(peer_id, position) => {
+                self.handle_transfer_progress(peer_id, position)
+            }
+
+            proto::Response::Tick => self.handle_tick(),
+
+            proto::Response::Throughput(throughput) => self.throughput = throughput,
+        }
+    }
+
+    fn handle_tick(&mut self) {
+        if let LoginStatus::Success(_) = self.login_status {
+            let interval = time::Duration::from_secs(config::PLACE_IN_QUEUE_POLL_INTERVAL_SECS);
+            if self.last_place_in_queue_poll.elapsed() >= interval {
+                self.last_place_in_queue_poll = time::Instant::now();
+                self.poll_places_in_queue();
+            }
+        }
+
+        let timeout = time::Duration::from_secs(config::UPLOAD_REQUEST_TIMEOUT_SECS);
+        let expired = self.uploads.expire_requests(timeout);
+        self.abandon_upload_requests(expired);
+
+        self.expire_searches();
+        self.expire_download_tokens();
+        self.send_download_progress();
+
+        self.poll_share_scan();
+        // Changes made during a scan are only applied once it is over, lest
+        // its results overwrite them.
+        if self.share_scan.is_none() {
+            self.apply_share_changes();
+        }
+    }
+
+    /// Forgets everything that only held while we were logged in. The rooms
+    /// we were in are joined again once we log in again.
+    fn handle_server_connection_closed(&mut self) {
+        self.login_status = LoginStatus::Pending;
+        self.is_auto_join_pending = false;
+        let response = self.login_status_response();
+        self.send_to_controller(control::Response::LoginStatusResponse(response));
+
+        for room_name in self.rooms.leave_all() {
+            self.send_to_controller(control::Response::RoomLeaveResponse(
+                control::RoomLeaveResponse {
+                    room_name: room_name,
+                },
+            ));
+        }
+    }
+
+    fn handle_peer_connection_closed(&mut self, peer_id: usize) {
+        // If the peer is removed, this holds its user name and whether the
+        // connection was ever open.
+        let removed_opt = {
+            let mut occupied_entry = match self.peers.entry(peer_id) {
+                None | Some(slab::Entry::Vacant(_)) => {
+                    error!("Unknown peer connection {} has closed", peer_id);
+                    return;
+                }
+
+                Some(slab::Entry::Occupied(occupied_entry)) => occupied_entry,
+            };
+
+            let is_transfer_connection = occupied_entry.get().connection_type == "F";
+
+            match occupied_entry.get_mut().state {
+                PeerState::Open => {
+                    info!("Peer connection {} has closed", peer_id);
+                    let (peer, _) = occupied_entry.remove();
+                    Some((peer.user_name, true))
+                }
+
+                PeerState::WaitingFirewalled => {
+                    error!(
+                        "Peer connection {} has closed, was waiting: inconsistent",
+                        peer_id
+                    );
+                    let (peer, _) = occupied_entry.remove();
+                    Some((peer.user_name, false))
+                }
+
+                PeerState::Opening if is_transfer_connection => {
+                    // We do not accept incoming connections yet, so there is
+                    // no point in asking for a reverse transfer connection.
+                    info!("Transfer connection {} has been refused", peer_id);
+                    let (peer, _) = occupied_entry.remove();
+                    Some((peer.user_name, false))
+                }
+
+                PeerState::Opening => {
+                    info!(
+                        "Peer connection {} has been refused, trying reverse",
+                        peer_id
+                    );
+
+                    let peer = occupied_entry.get_mut();
+                    peer.state = PeerState::WaitingFirewalled;
+
+                    self.proto_tx
+                        .send(proto::Request::ServerRequest(
+                            server::ServerRequest::ConnectToPeerRequest(
+                                server::ConnectToPeerRequest {
+                                    token: peer.token,
+                                    user_name: peer.user_name.clone(),
+                                    connection_type: peer.connection_type.clone(),
+                                },
+                            ),
+                        ))
+                        .unwrap();
+
+                    None
+                }
+
+                PeerState::OpeningFirewalled => {
+                    info!(
+                        "Peer connection {} has been refused, cannot connect",
+                        peer_id
+                    );
+
+                    let (peer, _) = occupied_entry.remove();
+                    self.proto_tx
+                        .send(proto::Request::ServerRequest(
+                            server::ServerRequest::CannotConnectRequest(
+                                server::CannotConnectRequest {
+                                    token: peer.token,
+                                    user_name: peer.user_name.clone(),
+                                },
+                            ),
+                        ))
+                        .unwrap();
+
+                    Some((peer.user_name, false))
+                }
+            }
+        };
+
+        if let Some((user_name, was_open)) = removed_opt {
+            self.forget_peer(peer_id, &user_name, was_open);
+        }
+    }
+
+    /// Forgets about the given closed peer connection to the given user.
+    /// If the connection never opened, messages waiting for it are dropped.
+    /// If a file was being received on it, checks whether it is complete.
+    /// If a file was being sent on it, frees the upload slot.
+    fn forget_peer(&mut self, peer_id: usize, user_name: &str, was_open: bool) {
+        if let Some((user_name, remote_path)) = self.download_transfers.remove(&peer_id) {
+            self.download_progress.remove(&peer_id);
+            self.finish_download_transfer(&user_name, &remote_path);
+            return;
+        }
+        if let Some((user_name, remote_path, _)) = self.upload_transfers.remove(&peer_id) {
+            self.finish_upload_transfer(&user_name, &remote_path);
+            return;
+        }
+        if self.user_peers.get(user_name) != Some(&peer_id) {
+            return;
+        }
+        self.user_peers.remove(user_name);
+        if !was_open {
+            self.drop_pending_peer_messages(user_name);
+        }
+    }
+
+    /// Drops the messages waiting for a peer connection to the given user to
+    /// open, for example because the connection cannot be established.
+    fn drop_pending_peer_messages(&mut self, user_name: &str) {
+        if let Some(messages) = self.pending_peer_messages.remove(user_name) {
+            warn!(
+                "Dropping {} messages to user {:?}",
+                messages.len(),
+                user_name
+            );
+            for message in messages {
+                if let peer::Message::FolderContentsRequest(request) = message {
+                    self.fail_folder_request(user_name, request.token);
+                }
+            }
+        }
+        // Upload requests may have been among them, try again later.
+        self.downloads.requeue(user_name);
+        self.send_downloads_to_controller(user_name);
+        // So may have offers to upload files to the user.
+        let cancelled = self.uploads.cancel_requests(user_name);
+        self.abandon_upload_requests(cancelled);
+    }
+
+    /// Tells the controller that asked for the contents of the folder
+    /// requested with the given token that the given user cannot be reached.
+    fn fail_folder_request(&mut self, user_name: &str, token: u32) {
+        let key = (user_name.to_string(), token);
+        let (folder_name, requester) = match self.folder_requests.remove(&key) {
+            Some(folder_request) => folder_request,
+            None => return,
+        };
+        let message = format!(
+            "cannot reach user {:?} to list folder {:?}",
+            user_name, folder_name
+        );
+        error!("DownloadFolderRequest: {}", message);
+        self.send_to_requester(
+            requester,
+            control::Response::ErrorResponse(control::ErrorResponse::new(
+                control::ErrorCode::UserUnreachable,
+                message,
+            )),
+        );
+    }
+
+    /// Sends the messages waiting for the given peer connection to open.
+    fn send_pending_peer_messages(&mut self, peer_id: usize) {
+        let user_name = match self.peers.get(peer_id) {
+            Some(peer) => peer.user_name.clone(),
+            None => return,
+        };
+        if self.user_peers.get(&user_name) != Some(&peer_id) {
+            return;
+        }
+        if let Some(messages) = self.pending_peer_messages.remove(&user_name) {
+            for message in messages {
+                self.send_to_peer(peer_id, message);
+            }
+        }
+    }
+
+    fn handle_peer_connection_open(&mut self, peer_id: usize) {
+        let message = match self.peers.get_mut(peer_id) {
+            None => {
+                error!("Unknown peer connection {} is open", peer_id);
+                return;
+            }
+
+            Some(
+                peer @ &mut Peer {
+                    state: PeerState::Open,
+                    ..
+                },
+            ) => {
+                error!("Peer connection {} was already open: {:?}", peer_id, peer);
+                return;
+            }
+
+            Some(
+                peer @ &mut Peer {
+                    state: PeerState::WaitingFirewalled,
+                    ..
+                },
+            ) => {
+                error!("Peer connection {} was waiting: {:?}", peer_id, peer);
+                return;
+            }
+
+            Some(
+                peer @ &mut Peer {
+                    state: PeerState::Opening,
+                    ..
+                },
+            ) => {
+                info!("Peer connection {} is now open: {:?}", peer_id, peer);
+                // Mark it as open.
+                peer.state = PeerState::Open;
+                // Send a PeerInit.
+                peer::Message::PeerInit(peer::PeerInit {
+                    user_name: config::USERNAME.to_string(),
+                    connection_type: peer.connection_type.clone(),
+                    token: peer.token,
+                })
+            }
+
+            Some(
+                peer @ &mut Peer {
+                    state: PeerState::OpeningFirewalled,
+                    ..
+                },
+            ) => {
+                info!("Peer connection {} is now open: {:?}", peer_id, peer);
+                // Mark it as open.
+                peer.state = PeerState::Open;
+                // Send a PierceFirewall.
+                peer::Message::PierceFirewall(peer.token)
+            }
+        };
+
+        self.send_to_peer(peer_id, message);
+        self.send_pending_peer_messages(peer_id);
+        self.send_upload(peer_id);
+    }
+
+    fn handle_transfer_token(&mut self, peer_id: usize, token: u32) {
+        let user_name = match self.peers.get(peer_id) {
+            Some(peer) => peer.user_name.clone(),
+            None => {
+                error!("Unknown transfer connection {} sent token", peer_id);
+                return;
+            }
+        };
+
+        let remote_path = match self.download_tokens.remove(&(user_name.clone(), token)) {
+            Some((remote_path, _)) => remote_path,
+            None => {
+                warn!("User {:?} sent unknown transfer token {}", user_name, token);
+                self.proto_tx
+                    .send(proto::Request::TransferClose(peer_id))
+                    .unwrap();
+                return;
+            }
+        };
+
+        match self.start_download_transfer(peer_id, &user_name, &remote_path) {
+            Ok((file, offset, size)) => {
+                self.proto_tx
+                    .send(proto::Request::TransferReceive(peer_id, file, offset, size))
+                    .unwrap();
+            }
+            Err(err) => {
+                error!(
+                    "Cannot receive {:?} from user {:?}: {}",
+                    remote_path, user_name, err
+                );
+                let state = download::State::Failed(err);
+                if let Err(err) = self.downloads.set_state(&user_name, &remote_path, state) {
+                    error!("Cannot update download: {}", err);
+                }
+                self.proto_tx
+                    .send(proto::Request::TransferClose(peer_id))
+                    .unwrap();
+            }
+        }
+        self.send_downloads_to_controller(&user_name);
+    }
+
+    fn handle_transfer_progress(&mut self, peer_id: usize, position: u64) {
+        if let Some((user_name, remote_path, _)) = self.upload_transfers.get(&peer_id).cloned() {
+            if let Err(err) = self
+                .uploads
+                .set_bytes_sent(&user_name, &remote_path, position)
+            {
+                error!("Cannot update upload: {}", err);
+            }
+            return;
+        }
+
+        let (user_name, remote_path) = match self.download_transfers.get(&peer_id) {
+            Some(transfer) => transfer.clone(),
+            None => {
+                error!("Unknown transfer connection {} made progress", peer_id);
+                return;
+            }
+        };
+
+        if let Err(err) = self
+            .downloads
+            .set_bytes_received(&user_name, &remote_path, position)
+        {
+            error!("Cannot update download: {}", err);
+            return;
+        }
+        // Data arrives in small chunks, so the controllers are only told
+        // about it once per tick.
+        self.download_progress.insert(peer_id);
+    }
+
+    /*=======================*
+     * PEER MESSAGE HANDLING *
+     *=======================*/
+
+    fn handle_peer_message(&mut self, peer_id: usize, message: peer::Message) {
+        let user_name = match self.peers.get(peer_id) {
+            Some(peer) => peer.user_name.clone(),
+            None => {
+                error!(
+                    "Unknown peer connection {} sent message: {:?}",
+                    peer_id, message
+                );
+                return;
+            }
+        };
+
+        match message {
+            peer::Message::FileSearchResult(result) => {
+                self.handle_file_search_result(&user_name, result)
+            }
+
+            peer::Message::FolderContentsRequest(request) => {
+                self.handle_folder_contents_request(peer_id, &user_name, request)
+            }
+
+            peer::Message::FolderContentsResponse(response) => {
+                self.handle_folder_contents_response(&user_name, response)
+            }
+
+            peer::Message::PlaceInQueueRequest(request) => {
+                self.handle_place_in_queue_request(peer_id, &user_name, request)
+            }
+
+            peer::Message::PlaceInQueueResponse(response) => {
+                self.handle_place_in_queue_response(&user_name, response)
+            }
+
+            peer::Message::QueueUpload(message) => {
+                self.handle_queue_upload(peer_id, &user_name, message)
+            }
+
+            peer::Message::SharedFileListRequest => {
+                self.handle_shared_file_list_request(peer_id, &user_name)
+            }
+
+            peer::Message::SharedFileListResponse(response) => {
+                self.handle_shared_file_list_response(&user_name, response)
+            }
+
+            peer::Message::TransferRequest(request) => {
+                self.handle_transfer_request(peer_id, &user_name, request)
+            }
+
+            peer::Message::TransferResponse(response) => {
+                self.handle_transfer_response(&user_name, response)
+            }
+
+            peer::Message::UploadDenied(message) => self.handle_upload_denied(&user_name, message),
+
+            peer::Message::UploadFailed(message) => self.handle_upload_failed(&user_name, message),
+
+            message => warn!("Unhandled message from user {:?}: {:?}", user_name, message),
+        }
+    }
+
+    /// Forgets the searches started long enough ago that their results are
+    /// no longer worth forwarding.
+    fn expire_searches(&mut self) {
+        let duration = time::Duration::from_secs(config::SEARCH_DURATION_SECS);
+        let expired: Vec<u32> = self
+            .searches
+            .iter()
+            .filter(|&(_, search)| search.start_time.elapsed() >= duration)
+            .map(|(&ticket, _)| ticket)
+            .collect();
+        for ticket in expired {
+            if let Some(search) = self.searches.remove(&ticket) {
+                info!(
+                    "Search for {:?} expired after {} results",
+                    search.query, search.num_results
+                );
+            }
+        }
+    }
+
+    fn handle_file_search_result(&mut self, user_name: &str, result: peer::FileSearchResult) {
+        let (requester, query, files) = {
+            let search = match self.searches.get_mut(&result.ticket) {
+                Some(search) => search,
+                None => {
+                    // Late results for expired searches are to be expected.
+                    debug!(
+                        "User {:?} sent results for unknown search {}",
+                        user_name, result.ticket
+                    );
+                    return;
+                }
+            };
+
+            if !search
+                .filters
+                .accepts_user(user_name, result.has_free_slot, result.queue_length)
+            {
+                return;
+            }
+
+            let mut files: Vec<control::SearchResultFile> = Vec::new();
+            for file in result.files {
+                let mut file = control::SearchResultFile {
+                    audio: audio::AudioInfo::from_attributes(&file.attributes),
+                    name: file.name,
+                    size: file.size,
+                    score: 0,
+                };
+                if !search.filters.accepts_file(&file) {
+                    continue;
+                }
+                file.score = search_result_score(
+                    &file,
+                    result.has_free_slot,
+                    result.average_speed,
+                    result.queue_length,
+                );
+                files.push(file);
+            }
+
+            // Once the limit is reached, later results are dropped whatever
+            // their score: ranking what was forwarded is left to the
+            // controller. Only the batch that reaches the limit keeps its
+            // best files.
+            files.sort_by(|a, b| b.score.cmp(&a.score));
+            let num_left = config::MAX_FORWARDED_SEARCH_RESULTS.saturating_sub(search.num_results);
+            files.truncate(num_left);
+            if files.is_empty() {
+                return;
+            }
+            search.num_results += files.len();
+            (search.requester, search.query.clone(), files)
+        };
+
+        let response = control::Response::SearchResultResponse(control::SearchResultResponse {
+            ticket: result.ticket,
+            query: query,
+            user_name: user_name.to_string(),
+            files: files,
+            has_free_slot: result.has_free_slot,
+            average_speed: result.average_speed,
+            queue_length: result.queue_length,
+        });
+        self.send_to_requester(requester, response);
+    }
+
+    fn handle_shared_file_list_request(&mut self, peer_id: usize, user_name: &str) {
+        info!("User {:?} is browsing our shares", user_name);
+        let folders = self.shares.folders(self.users.is_buddy(user_name));
+        self.send_to_peer(
+            peer_id,
+            peer::Message::SharedFileListResponse(peer::SharedFileListResponse {
+                folders: folders,
+            }),
+        );
+    }
+
+    fn handle_shared_file_list_response(
+        &mut self,
+        user_name: &str,
+        response: peer::SharedFileListResponse,
+    ) {
+        self.send_to_controller(control::Response::SharedFileListResponse(
+            control::SharedFileListResponse {
+                user_name: user_name.to_string(),
+                folders: response.folders,
+            },
+        ));
+    }
+
+    fn handle_folder_contents_request(
+        &mut self,
+        peer_id: usize,
+        user_name: &str,
+        request: peer::FolderContentsRequest,
+    ) {
+        info!(
+            "User {:?} is browsing our folder {:?}",
+            user_name, request.folder_name
+        );
+        let folders = self
+            .shares
+            .folder_contents(&request.folder_name, self.users.is_buddy(user_name));
+        self.send_to_peer(
+            peer_id,
+            peer::Message::FolderContentsResponse(peer::FolderContentsResponse {
+                token: request.token,
+                folder_name: request.folder_name,
+                folders: folders,
+            }),
+        );
+    }
+
+    fn handle_folder_contents_response(
+        &mut self,
+        user_name: &str,
+        response: peer::FolderContentsResponse,
+    ) {
+        let key = (user_name.to_string(), response.token);
+        let folder_name = match self.folder_requests.remove(&key) {
+            Some((folder_name, _)) => folder_name,
+            None => {
+                warn!(
+                    "Unsolicited contents of folder {:?} from user {:?}",
+                    response.folder_name, user_name
+                );
+                return;
             }
+        };
 
-            proto::Response::PeerConnectionClosed(peer_id) => {
-                self.handle_peer_connection_closed(peer_id)
+        let mut num_queued = 0;
+        for folder in response.folders {
+            let local_dir =
+                download::local_folder_path(config::DOWNLOAD_DIR, &folder_name, &folder.name);
+            for file in folder.files {
+                let remote_path = format!("{}\\{}", folder.name, file.name);
+                let download = download::Download::new(
+                    user_name.to_string(),
+                    remote_path,
+                    file.size,
+                    &local_dir,
+                );
+                match self.downloads.enqueue(download) {
+                    Ok(()) => num_queued += 1,
+                    Err(err) => warn!("DownloadFolderRequest: {}", err),
+                }
             }
+        }
 
-            _ => {
-                warn!("Unhandled proto response: {:?}", response);
-            }
+        info!(
+            "Queued {} downloads from folder {:?} of user {:?}",
+            num_queued, folder_name, user_name
+        );
+        // We just heard from the user, so they must be online.
+        self.request_downloads(user_name);
+    }
+
+    fn handle_place_in_queue_request(
+        &mut self,
+        peer_id: usize,
+        user_name: &str,
+        request: peer::PlaceInQueueRequest,
+    ) {
+        let place_opt = {
+            let users = &self.users;
+            self.uploads
+                .place_in_queue(user_name, &request.file_name, |user_name| {
+                    upload_priority(users, user_name)
+                })
+        };
+        match place_opt {
+            Some(place) => self.send_to_peer(
+                peer_id,
+                peer::Message::PlaceInQueueResponse(peer::PlaceInQueueResponse {
+                    file_name: request.file_name,
+                    place: place,
+                }),
+            ),
+            None => info!(
+                "User {:?} asked for place of unknown upload {:?}",
+                user_name, request.file_name
+            ),
         }
     }
 
-    fn handle_peer_connection_closed(&mut self, peer_id: usize) {
-        let mut occupied_entry = match self.peers.entry(peer_id) {
-            None | Some(slab::Entry::Vacant(_)) => {
-                error!("Unknown peer connection {} has closed", peer_id);
-                return;
-            }
+    fn handle_place_in_queue_response(
+        &mut self,
+        user_name: &str,
+        response: peer::PlaceInQueueResponse,
+    ) {
+        let result =
+            self.downloads
+                .set_place_in_queue(user_name, &response.file_name, response.place);
+        if let Err(err) = result {
+            warn!("Cannot set place in queue: {}", err);
+            return;
+        }
+        self.send_downloads_to_controller(user_name);
+    }
 
-            Some(slab::Entry::Occupied(occupied_entry)) => occupied_entry,
-        };
+    fn handle_queue_upload(&mut self, peer_id: usize, user_name: &str, message: peer::QueueUpload) {
+        if let Err(reason) = self.queue_upload(user_name, &message.file_name) {
+            info!(
+                "Denying upload of {:?} to user {:?}: {}",
+                message.file_name, user_name, reason
+            );
+            self.send_to_peer(
+                peer_id,
+                peer::Message::UploadDenied(peer::UploadDenied {
+                    file_name: message.file_name,
+                    reason: reason,
+                }),
+            );
+        }
+    }
 
-        match occupied_entry.get_mut().state {
-            PeerState::Open => {
-                info!("Peer connection {} has closed", peer_id);
-                occupied_entry.remove();
-            }
+    fn handle_upload_denied(&mut self, user_name: &str, message: peer::UploadDenied) {
+        info!(
+            "User {:?} denied upload of {:?}: {}",
+            user_name, message.file_name, message.reason
+        );
+        let state = download::State::Denied(message.reason);
+        if let Err(err) = self
+            .downloads
+            .set_state(user_name, &message.file_name, state)
+        {
+            warn!("Cannot mark download as denied: {}", err);
+            return;
+        }
+        self.send_downloads_to_controller(user_name);
+    }
 
-            PeerState::WaitingFirewalled => {
-                error!(
-                    "Peer connection {} has closed, was waiting: inconsistent",
-                    peer_id
-                );
-                occupied_entry.remove();
-            }
+    fn handle_upload_failed(&mut self, user_name: &str, message: peer::UploadFailed) {
+        info!(
+            "User {:?} failed to upload {:?}",
+            user_name, message.file_name
+        );
+        match self.downloads.get(user_name, &message.file_name) {
+            Some(download) if !download.state.is_finished() => (),
+            _ => return,
+        }
+        // Whatever was received so far is kept, and will be resumed from if
+        // the download is requested again.
+        let state = download::State::Failed("upload failed".to_string());
+        if let Err(err) = self
+            .downloads
+            .set_state(user_name, &message.file_name, state)
+        {
+            warn!("Cannot mark download as failed: {}", err);
+            return;
+        }
+        self.send_downloads_to_controller(user_name);
+    }
 
-            PeerState::Opening => {
+    fn handle_transfer_request(
+        &mut self,
+        peer_id: usize,
+        user_name: &str,
+        request: peer::TransferRequest,
+    ) {
+        let token = request.token;
+        let result = if request.direction == peer::TransferDirection::Download {
+            // Some clients ask for files this way instead of with a
+            // QueueUpload message. The upload is queued all the same.
+            self.queue_upload(user_name, &request.file_name)
+                .and_then(|()| Err("Queued".to_string()))
+        } else {
+            self.accept_transfer_request(user_name, request)
+        };
+        let response = match result {
+            Ok(()) => peer::TransferResponse::Allowed {
+                token: token,
+                file_size: None,
+            },
+            Err(reason) => {
                 info!(
-                    "Peer connection {} has been refused, trying reverse",
-                    peer_id
+                    "Denying transfer {} from user {:?}: {}",
+                    token, user_name, reason
                 );
+                peer::TransferResponse::Denied {
+                    token: token,
+                    reason: reason,
+                }
+            }
+        };
+        self.send_to_peer(peer_id, peer::Message::TransferResponse(response));
+    }
 
-                let peer = occupied_entry.get_mut();
-                peer.state = PeerState::WaitingFirewalled;
-
-                self.proto_tx
-                    .send(proto::Request::ServerRequest(
-                        server::ServerRequest::ConnectToPeerRequest(server::ConnectToPeerRequest {
-                            token: peer.token,
-                            user_name: peer.user_name.clone(),
-                            connection_type: peer.connection_type.clone(),
-                        }),
-                    ))
-                    .unwrap();
+    fn handle_transfer_response(&mut self, user_name: &str, response: peer::TransferResponse) {
+        match response {
+            peer::TransferResponse::Allowed { token, .. } => {
+                self.start_upload_transfer(user_name, token)
             }
 
-            PeerState::OpeningFirewalled => {
+            peer::TransferResponse::Denied { token, reason } => {
+                let remote_path = match self.uploads.get_requested(user_name, token) {
+                    Some(upload) => upload.remote_path.clone(),
+                    None => {
+                        warn!("User {:?} refused unknown transfer {}", user_name, token);
+                        return;
+                    }
+                };
                 info!(
-                    "Peer connection {} has been refused, cannot connect",
-                    peer_id
+                    "User {:?} refused upload of {:?}: {}",
+                    user_name, remote_path, reason
                 );
-
-                let (peer, _) = occupied_entry.remove();
-                self.proto_tx
-                    .send(proto::Request::ServerRequest(
-                        server::ServerRequest::CannotConnectRequest(server::CannotConnectRequest {
-                            token: peer.token,
-                            user_name: peer.user_name,
-                        }),
-                    ))
-                    .unwrap();
+                if let Err(err) = self.uploads.finish(user_name, &remote_path) {
+                    error!("Cannot finish upload: {}", err);
+                }
+                self.start_uploads();
             }
         }
     }
 
-    fn handle_peer_connection_open(&mut self, peer_id: usize) {
-        let message = match self.peers.get_mut(peer_id) {
-            None => {
-                error!("Unknown peer connection {} is open", peer_id);
-                return;
-            }
-
-            Some(
-                peer @ &mut Peer {
-                    state: PeerState::Open,
-                    ..
-                },
-            ) => {
-                error!("Peer connection {} was already open: {:?}", peer_id, peer);
-                return;
-            }
+    /// Checks that we are waiting for the file the given user wants to
+    /// upload, and if so remembers the transfer token so as to recognize the
+    /// file connection when it opens.
+    /// Returns the reason to give the user otherwise.
+    fn accept_transfer_request(
+        &mut self,
+        user_name: &str,
+        request: peer::TransferRequest,
+    ) -> Result<(), String> {
+        if request.direction != peer::TransferDirection::Upload {
+            return Err("File not shared.".to_string());
+        }
 
-            Some(
-                peer @ &mut Peer {
-                    state: PeerState::WaitingFirewalled,
-                    ..
-                },
-            ) => {
-                error!("Peer connection {} was waiting: {:?}", peer_id, peer);
-                return;
-            }
+        match self.downloads.get(user_name, &request.file_name) {
+            Some(&download::Download {
+                state: download::State::Requested,
+                ..
+            }) => (),
+            _ => return Err("Cancelled".to_string()),
+        }
 
-            Some(
-                peer @ &mut Peer {
-                    state: PeerState::Opening,
-                    ..
-                },
-            ) => {
-                info!("Peer connection {} is now open: {:?}", peer_id, peer);
-                // Mark it as open.
-                peer.state = PeerState::Open;
-                // Send a PeerInit.
-                peer::Message::PeerInit(peer::PeerInit {
-                    user_name: config::USERNAME.to_string(),
-                    connection_type: peer.connection_type.clone(),
-                    token: peer.token,
-                })
+        if let Some(file_size) = request.file_size {
+            if let Err(err) = self
+                .downloads
+                .set_size(user_name, &request.file_name, file_size)
+            {
+                error!("Cannot update download: {}", err);
             }
+        }
 
-            Some(
-                peer @ &mut Peer {
-                    state: PeerState::OpeningFirewalled,
-                    ..
-                },
-            ) => {
-                info!("Peer connection {} is now open: {:?}", peer_id, peer);
-                // Mark it as open.
-                peer.state = PeerState::Open;
-                // Send a PierceFirewall.
-                peer::Message::PierceFirewall(peer.token)
-            }
-        };
+        // The user may be trying again with a new token, in which case the
+        // old one will never be used.
+        self.download_tokens
+            .retain(|&(ref token_user_name, _), &mut (ref remote_path, _)| {
+                token_user_name != user_name || *remote_path != request.file_name
+            });
+        self.download_tokens.insert(
+            (user_name.to_string(), request.token),
+            (request.file_name, time::Instant::now()),
+        );
+        Ok(())
+    }
 
-        self.send_to_peer(peer_id, message);
+    /// Forgets the transfer tokens of files whose transfer connection never
+    /// opened.
+    fn expire_download_tokens(&mut self) {
+        let timeout = time::Duration::from_secs(config::DOWNLOAD_TOKEN_TIMEOUT_SECS);
+        self.download_tokens.retain(
+            |&(ref user_name, token), &mut (ref remote_path, announced)| {
+                if announced.elapsed() < timeout {
+                    return true;
+                }
+                info!(
+                    "User {:?} never sent {:?} with transfer token {}",
+                    user_name, remote_path, token
+                );
+                false
+            },
+        );
     }
 
     /*==========================*
@@ -448,12 +2417,32 @@ impl Client {
                 self.handle_connect_to_peer_response(response)
             }
 
+            server::ServerResponse::ExcludedSearchPhrasesResponse(response) => {
+                self.handle_excluded_search_phrases_response(response)
+            }
+
+            server::ServerResponse::FileSearchResponse(response) => {
+                self.handle_file_search_response(response)
+            }
+
             server::ServerResponse::LoginResponse(response) => self.handle_login_response(response),
 
+            server::ServerResponse::MessageUserResponse(response) => {
+                self.handle_message_user_response(response)
+            }
+
+            server::ServerResponse::PeerAddressResponse(response) => {
+                self.handle_peer_address_response(response)
+            }
+
             server::ServerResponse::PrivilegedUsersResponse(response) => {
                 self.handle_privileged_users_response(response)
             }
 
+            server::ServerResponse::PublicChatMessageResponse(response) => {
+                self.handle_public_chat_message_response(response)
+            }
+
             server::ServerResponse::RoomJoinResponse(response) => {
                 self.handle_room_join_response(response)
             }
@@ -490,6 +2479,10 @@ impl Client {
                 self.handle_user_status_response(response)
             }
 
+            server::ServerResponse::WatchUserResponse(response) => {
+                self.handle_watch_user_response(response)
+            }
+
             server::ServerResponse::UnknownResponse(code) => {
                 warn!("Unknown response: code {}", code)
             }
@@ -499,8 +2492,12 @@ impl Client {
     }
 
     fn handle_connect_to_peer_response(&mut self, response: server::ConnectToPeerResponse) {
+        let is_message_connection = response.connection_type == "P";
+        let is_transfer_connection = response.connection_type == "F";
+        let user_name = response.user_name;
+
         let peer = Peer {
-            user_name: response.user_name,
+            user_name: user_name.clone(),
             ip: response.ip,
             port: response.port,
             connection_type: response.connection_type,
@@ -514,13 +2511,21 @@ impl Client {
                     "Opening peer connection {} to {}:{} to pierce firewall",
                     peer_id, response.ip, response.port
                 );
-                self.proto_tx
-                    .send(proto::Request::PeerConnect(
+                if is_message_connection {
+                    self.user_peers.entry(user_name.clone()).or_insert(peer_id);
+                }
+                let request = if is_transfer_connection {
+                    proto::Request::TransferConnect(
                         peer_id,
+                        user_name,
+                        proto::TransferMode::Receive,
                         response.ip,
                         response.port,
-                    ))
-                    .unwrap();
+                    )
+                } else {
+                    proto::Request::PeerConnect(peer_id, response.ip, response.port)
+                };
+                self.proto_tx.send(request).unwrap();
             }
 
             Err(peer) => {
@@ -532,6 +2537,46 @@ impl Client {
         }
     }
 
+    fn handle_excluded_search_phrases_response(
+        &mut self,
+        response: server::ExcludedSearchPhrasesResponse,
+    ) {
+        info!(
+            "Server forbids {} phrases in search results",
+            response.phrases.len()
+        );
+        self.shares.set_excluded_phrases(response.phrases);
+    }
+
+    fn handle_file_search_response(&mut self, response: server::FileSearchResponse) {
+        if response.user_name == config::USERNAME {
+            return;
+        }
+        let is_buddy = self.users.is_buddy(&response.user_name);
+        let files = self
+            .shares
+            .search(&response.query, config::MAX_SEARCH_RESULTS, is_buddy);
+        if files.is_empty() {
+            return;
+        }
+
+        debug!(
+            "Sending {} results for {:?} to user {:?}",
+            files.len(),
+            response.query,
+            response.user_name
+        );
+        let result = peer::FileSearchResult {
+            user_name: config::USERNAME.to_string(),
+            ticket: response.ticket,
+            files: files,
+            has_free_slot: self.uploads.num_active() < config::MAX_UPLOAD_SLOTS,
+            average_speed: 0,
+            queue_length: self.uploads.num_queued() as u32,
+        };
+        self.send_to_user(&response.user_name, peer::Message::FileSearchResult(result));
+    }
+
     fn handle_login_response(&mut self, login: server::LoginResponse) {
         if let LoginStatus::Pending = self.login_status {
             match login {
@@ -557,6 +2602,28 @@ impl Client {
                         )),
                     }
                     self.login_status = LoginStatus::Success(motd);
+                    self.send_shared_folders_files();
+
+                    // Join the auto-join rooms once we know which rooms
+                    // exist.
+                    self.is_auto_join_pending = true;
+                    self.send_to_server(server::ServerRequest::RoomListRequest);
+                    if self.is_public_chat_enabled {
+                        self.send_to_server(server::ServerRequest::EnablePublicChatRequest);
+                    }
+                    if self.is_away {
+                        self.send_status();
+                    }
+
+                    // Find out which of the users we are waiting to download
+                    // from are online.
+                    for user_name in self.downloads.queued_user_names() {
+                        self.send_to_server(server::ServerRequest::WatchUserRequest(
+                            server::WatchUserRequest {
+                                user_name: user_name,
+                            },
+                        ));
+                    }
                 }
 
                 server::LoginResponse::LoginFail { reason } => {
@@ -572,6 +2639,61 @@ impl Client {
         }
     }
 
+    fn handle_peer_address_response(&mut self, response: server::PeerAddressResponse) {
+        if !self.pending_peer_messages.contains_key(&response.username) {
+            // We have nothing to tell this user.
+            return;
+        }
+        if self.user_peers.contains_key(&response.username) {
+            // A connection is already being opened.
+            return;
+        }
+
+        if response.port == 0 {
+            info!(
+                "User {:?} is offline, cannot open peer connection",
+                response.username
+            );
+            self.drop_pending_peer_messages(&response.username);
+            return;
+        }
+
+        let user_name = response.username;
+        let peer = Peer {
+            user_name: user_name.clone(),
+            ip: response.ip,
+            port: response.port,
+            connection_type: "P".to_string(),
+            token: self.new_token(),
+            state: PeerState::Opening,
+        };
+
+        match self.peers.insert(peer) {
+            Ok(peer_id) => {
+                info!(
+                    "Opening peer connection {} to {}:{}",
+                    peer_id, response.ip, response.port
+                );
+                self.user_peers.insert(user_name, peer_id);
+                self.proto_tx
+                    .send(proto::Request::PeerConnect(
+                        peer_id,
+                        response.ip,
+                        response.port,
+                    ))
+                    .unwrap();
+            }
+
+            Err(peer) => {
+                warn!(
+                    "Cannot open peer connection {:?}: too many already open",
+                    peer
+                );
+                self.drop_pending_peer_messages(&user_name);
+            }
+        }
+    }
+
     fn handle_privileged_users_response(&mut self, response: server::PrivilegedUsersResponse) {
         self.users.set_all_privileged(response.users);
     }
@@ -588,6 +2710,23 @@ impl Client {
             error!("RoomJoinResponse: {}", err);
             return;
         }
+        self.auto_join_rooms.insert(&response.room_name);
+
+        // Pick up the conversation where we left it last time.
+        match self
+            .chat_log
+            .recent(&response.room_name, config::MAX_ROOM_MESSAGES)
+        {
+            Ok(messages) => {
+                if let Err(err) = self.rooms.restore_messages(&response.room_name, messages) {
+                    error!("RoomJoinResponse: {}", err);
+                }
+            }
+            Err(err) => error!(
+                "Error reading chat log of room {:?}: {}",
+                response.room_name, err
+            ),
+        }
 
         // Then update the user structs based on the info we just got.
         for user in response.users.drain(..) {
@@ -620,26 +2759,152 @@ impl Client {
         self.send_to_controller(control::Response::RoomListResponse(
             control::RoomListResponse { rooms: rooms },
         ));
+
+        if self.is_auto_join_pending {
+            self.is_auto_join_pending = false;
+            for room_name in self.auto_join_rooms.room_names() {
+                if let Err(err) = self.join_room(room_name.clone()) {
+                    error!("Cannot auto-join room {:?}: {}", room_name, err);
+                }
+            }
+        }
+    }
+
+    fn handle_message_user_response(&mut self, response: server::MessageUserResponse) {
+        // Otherwise the server sends the message again every time we log in,
+        // even if we ignore its sender.
+        self.send_to_server(server::ServerRequest::MessageAckedRequest(
+            server::MessageAckedRequest {
+                message_id: response.id,
+            },
+        ));
+
+        if self.users.is_ignored(&response.user_name) {
+            debug!(
+                "Ignoring private message from user {:?}",
+                response.user_name
+            );
+            return;
+        }
+
+        self.add_private_message(
+            response.user_name.clone(),
+            response.user_name,
+            response.message,
+            response.timestamp as u64,
+        );
+    }
+
+    /// Logs the given message of our private conversation with the given
+    /// peer, said by either of us, and forwards it to the controllers.
+    fn add_private_message(
+        &mut self,
+        peer_name: String,
+        user_name: String,
+        message: String,
+        timestamp: u64,
+    ) {
+        if !self.private_message_ids.contains_key(&peer_name) {
+            let next_id = match self.private_chat_log.recent(&peer_name, 1) {
+                Ok(messages) => messages.last().map_or(0, |message| message.id + 1),
+                Err(err) => {
+                    error!("Error reading chat log of user {:?}: {}", peer_name, err);
+                    0
+                }
+            };
+            self.private_message_ids.insert(peer_name.clone(), next_id);
+        }
+        let id = self.private_message_ids.get_mut(&peer_name).unwrap();
+
+        let message = room::Message {
+            id: *id,
+            timestamp: timestamp,
+            user_name: user_name,
+            message: message,
+            is_highlighted: false,
+        };
+        *id += 1;
+
+        if let Err(err) = self.private_chat_log.append(&peer_name, &message) {
+            error!(
+                "Error logging private message with user {:?}: {}",
+                peer_name, err
+            );
+        }
+
+        self.send_to_controller(control::Response::PrivateMessageResponse(
+            control::PrivateMessageResponse {
+                user_name: peer_name,
+                message: message,
+            },
+        ));
+    }
+
+    fn handle_public_chat_message_response(&mut self, response: server::PublicChatMessageResponse) {
+        // Public chat is a feed of its own, whatever rooms we are a member
+        // of, so it does not go into the room map.
+        self.send_to_controller(control::Response::PublicChatMessageResponse(
+            control::PublicChatMessageResponse {
+                room_name: response.room_name,
+                user_name: response.user_name,
+                message: response.message,
+            },
+        ));
     }
 
     fn handle_room_message_response(&mut self, response: server::RoomMessageResponse) {
+        if self.users.is_ignored(&response.user_name) {
+            debug!(
+                "Ignoring message from user {:?} in room {:?}",
+                response.user_name, response.room_name
+            );
+            return;
+        }
+
+        // The server sends us back our own messages, which we need not be
+        // told about.
+        let is_own = response.user_name == config::USERNAME;
+        let is_highlighted = !is_own && self.highlighter.is_match(&response.message);
         let result = self.rooms.add_message(
             &response.room_name,
-            room::Message {
-                user_name: response.user_name.clone(),
-                message: response.message.clone(),
-            },
+            response.user_name,
+            response.message,
+            is_own,
+            is_highlighted,
         );
-        if let Err(err) = result {
-            error!("RoomMessageResponse: {}", err);
-            return;
+        let message = match result {
+            Ok(message) => message,
+            Err(err) => {
+                error!("RoomMessageResponse: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = self.chat_log.append(&response.room_name, &message) {
+            error!(
+                "Error logging message in room {:?}: {}",
+                response.room_name, err
+            );
+        }
+
+        if is_highlighted {
+            let num_mentions = match self.rooms.get(&response.room_name) {
+                Some(room) => room.num_mentions,
+                None => 0,
+            };
+            self.send_to_controller(control::Response::HighlightResponse(
+                control::HighlightResponse {
+                    room_name: response.room_name.clone(),
+                    message: message.clone(),
+                    num_mentions: num_mentions,
+                },
+            ));
         }
 
         self.send_to_controller(control::Response::RoomMessageResponse(
             control::RoomMessageResponse {
                 room_name: response.room_name,
-                user_name: response.user_name,
-                message: response.message,
+                message: message,
             },
         ));
     }
@@ -712,10 +2977,34 @@ impl Client {
             return;
         }
 
+        if response.status != proto::UserStatus::Offline {
+            self.request_downloads(&response.user_name);
+        }
+
         if response.is_privileged {
             self.users.insert_privileged(response.user_name);
         } else {
             self.users.remove_privileged(&response.user_name);
         }
     }
+
+    fn handle_watch_user_response(&mut self, response: server::WatchUserResponse) {
+        let user = match response.user_info {
+            Some(user) => user,
+            None => {
+                warn!("Watched user {:?} does not exist", response.user_name);
+                self.downloads
+                    .fail_all(&response.user_name, "user does not exist");
+                self.send_downloads_to_controller(&response.user_name);
+                return;
+            }
+        };
+
+        let is_online = user.status != proto::UserStatus::Offline;
+        self.users.insert(user);
+
+        if is_online {
+            self.request_downloads(&response.user_name);
+        }
+    }
 }
@@ -1,3 +1,5 @@
+use share::Visibility;
+
 pub const VER_MAJOR: u32 = 181;
 pub const VER_MINOR: u32 = 100;
 
@@ -8,11 +10,100 @@ pub const PASSWORD: &'static str = "ijklmnop";
 
 pub const SERVER_HOST: &'static str = "server.slsknet.org";
 pub const SERVER_PORT: u16 = 2242;
+// How long to wait before connecting to the server again once the
+// connection is lost.
+pub const SERVER_RECONNECT_DELAY_SECS: u64 = 30;
 
 pub const LISTEN_HOST: &'static str = "0.0.0.0";
 pub const LISTEN_PORT: u16 = 2243;
 
 pub const CONTROL_HOST: &'static str = "localhost";
 pub const CONTROL_PORT: u16 = 2244;
+// The secret controllers must present to connect, either in an
+// `Authorization: Bearer <token>` header or in a `token` query parameter.
+// Controllers cannot connect until it is set.
+pub const CONTROL_TOKEN: &'static str = "";
+// The origins controllers may connect from. Any origin is allowed if empty.
+pub const CONTROL_ALLOWED_ORIGINS: &'static [&'static str] = &[];
+// The paths of the PEM-encoded certificate chain and private key with which
+// to serve the control websocket over TLS. If None, it is served in the
+// clear, which is only safe on localhost.
+pub const CONTROL_TLS_CERT_PATH: Option<&'static str> = None;
+pub const CONTROL_TLS_KEY_PATH: Option<&'static str> = None;
 
 pub const MAX_PEERS: usize = 1000;
+
+pub const TICK_INTERVAL_SECS: u64 = 1;
+
+// The number of messages remembered for each chat room.
+pub const MAX_ROOM_MESSAGES: usize = 1000;
+// The number of recent messages of each joined chat room sent to newly
+// connected controllers.
+pub const SNAPSHOT_ROOM_MESSAGES: usize = 100;
+// Where the messages said in chat rooms are logged, one file per room.
+pub const CHAT_LOG_DIR: &'static str = "chat_logs";
+// Where private messages are logged, one file per user.
+pub const PRIVATE_CHAT_LOG_DIR: &'static str = "chat_logs/users";
+// Chat messages mentioning our user name or one of these words, ignoring
+// case, or matching one of these regular expressions are highlighted.
+pub const HIGHLIGHT_WORDS: &'static [&'static str] = &[];
+pub const HIGHLIGHT_PATTERNS: &'static [&'static str] = &[];
+// The rooms joined upon logging in, until the controller joins or leaves
+// rooms, after which the rooms it was last in are joined instead.
+pub const AUTO_JOIN_ROOMS: &'static [&'static str] = &[];
+pub const AUTO_JOIN_ROOMS_PATH: &'static str = "auto_join_rooms.json";
+
+pub const DOWNLOAD_DIR: &'static str = "downloads";
+pub const INCOMPLETE_DIR: &'static str = "incomplete";
+pub const DOWNLOAD_QUEUE_PATH: &'static str = "download_queue.json";
+// The number of times in a row a download is requested again after its
+// transfer was interrupted without receiving anything new, before giving up.
+pub const MAX_DOWNLOAD_RETRIES: u32 = 5;
+pub const PLACE_IN_QUEUE_POLL_INTERVAL_SECS: u64 = 60;
+// How long a user has to open the transfer connection of a file they
+// announced, before we forget its transfer token.
+pub const DOWNLOAD_TOKEN_TIMEOUT_SECS: u64 = 60;
+
+// Transfer rate limits, in bytes per second. None means unlimited. Zero is
+// not a valid limit.
+pub const DOWNLOAD_RATE_LIMIT: Option<u64> = None;
+pub const UPLOAD_RATE_LIMIT: Option<u64> = None;
+pub const USER_DOWNLOAD_RATE_LIMIT: Option<u64> = None;
+pub const USER_UPLOAD_RATE_LIMIT: Option<u64> = None;
+// How long throttled transfers wait before trying again.
+pub const THROTTLE_DELAY_MS: u64 = 100;
+
+// The directories whose contents are shared with other users, and who can
+// see them. A directory inside a shared directory can be given a different
+// visibility, or be excluded from sharing altogether.
+pub const SHARED_DIRS: &'static [(&'static str, Visibility)] = &[];
+// Where the listings of the shared directories are cached between runs.
+pub const SHARE_CACHE_PATH: &'static str = "share_cache.json";
+// How long changes to the shared directories have to settle before they are
+// picked up.
+pub const SHARE_WATCH_DELAY_SECS: u64 = 2;
+// The users whose uploads are prioritized over those of other users, after
+// privileged users.
+pub const BUDDIES: &'static [&'static str] = &[];
+// The number of files uploaded at the same time.
+pub const MAX_UPLOAD_SLOTS: usize = 2;
+// How much a single user may have queued for upload at any time.
+pub const MAX_QUEUED_FILES_PER_USER: usize = 500;
+pub const MAX_QUEUED_BYTES_PER_USER: u64 = 10 * 1024 * 1024 * 1024;
+// How long a user has to accept an upload before its slot goes to someone
+// else.
+pub const UPLOAD_REQUEST_TIMEOUT_SECS: u64 = 60;
+// The maximum number of files sent in response to a search.
+pub const MAX_SEARCH_RESULTS: usize = 100;
+// The maximum number of files forwarded to the controller for each search.
+pub const MAX_FORWARDED_SEARCH_RESULTS: usize = 1000;
+// How long results are forwarded to the controller after starting a search,
+// after which the search is forgotten.
+pub const SEARCH_DURATION_SECS: u64 = 10 * 60;
+// How search results are ranked. Each file scores points for its user having
+// an upload slot free, per KiB/s of its user's average upload speed, per
+// upload queued by its user, and per kbps of its bitrate.
+pub const SEARCH_SCORE_FREE_SLOT: i64 = 1000;
+pub const SEARCH_SCORE_PER_KIB_PER_SEC: i64 = 1;
+pub const SEARCH_SCORE_PER_QUEUED_UPLOAD: i64 = -10;
+pub const SEARCH_SCORE_PER_KBPS: i64 = 1;
@@ -0,0 +1,159 @@
+/// This enumeration is the list of things the controller can ask for by
+/// typing a message in a chat room.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Say the given text in the room. Actions typed with `/me` are said as
+    /// is, as other clients display them as such.
+    Say(String),
+    /// Join the given room.
+    Join(String),
+    /// Leave the given room, or the room the command was typed in.
+    Leave(Option<String>),
+    /// Send the given message to the given user.
+    Msg(String, String),
+    /// Switch between being away and being online.
+    Away,
+    /// Stop or start ignoring the messages of the given user.
+    Ignore(String),
+    Unignore(String),
+    /// Search the network for the given query.
+    Search(String),
+    /// Ask the given user for the list of files they share.
+    Browse(String),
+    /// Ask the server for information about the given user.
+    Info(String),
+}
+
+/// Splits the given text into its first word and the rest of it, both
+/// trimmed.
+fn split_first_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// Returns the given argument, or an error explaining how to use the given
+/// command if it is empty.
+fn required(argument: &str, usage: &str) -> Result<String, String> {
+    if argument.is_empty() {
+        Err(format!("usage: {}", usage))
+    } else {
+        Ok(argument.to_string())
+    }
+}
+
+impl Command {
+    /// Interprets the given text typed in a chat room.
+    /// Text starting with a single `/` is a command, text starting with `//`
+    /// is said with one `/` less, and any other text is said as is.
+    /// Returns an error message to show the controller if the text is an
+    /// unknown or malformed command.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if !text.starts_with('/') || text.starts_with("/me ") {
+            return Ok(Command::Say(text.to_string()));
+        }
+        if text.starts_with("//") {
+            return Ok(Command::Say(text[1..].to_string()));
+        }
+
+        let (name, argument) = split_first_word(&text[1..]);
+        match name {
+            "me" => required(argument, "/me <action>").map(|_| Command::Say(text.to_string())),
+
+            "join" => required(argument, "/join <room>").map(Command::Join),
+
+            "leave" | "part" => Ok(Command::Leave(if argument.is_empty() {
+                None
+            } else {
+                Some(argument.to_string())
+            })),
+
+            "msg" => {
+                let (user_name, message) = split_first_word(argument);
+                if user_name.is_empty() || message.is_empty() {
+                    Err("usage: /msg <user> <message>".to_string())
+                } else {
+                    Ok(Command::Msg(user_name.to_string(), message.to_string()))
+                }
+            }
+
+            "away" => Ok(Command::Away),
+
+            "ignore" => required(argument, "/ignore <user>").map(Command::Ignore),
+
+            "unignore" => required(argument, "/unignore <user>").map(Command::Unignore),
+
+            "search" => required(argument, "/search <query>").map(Command::Search),
+
+            "browse" => required(argument, "/browse <user>").map(Command::Browse),
+
+            "info" | "whois" => required(argument, "/info <user>").map(Command::Info),
+
+            _ => Err(format!(
+                "unknown command /{}, type // to say a message starting with /",
+                name
+            )),
+        }
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    #[test]
+    fn parse_say() {
+        assert_eq!(
+            Command::parse("hello world"),
+            Ok(Command::Say("hello world".to_string()))
+        );
+        assert_eq!(
+            Command::parse("/me waves"),
+            Ok(Command::Say("/me waves".to_string()))
+        );
+        assert_eq!(
+            Command::parse("//etc/passwd"),
+            Ok(Command::Say("/etc/passwd".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            Command::parse("/join  some room "),
+            Ok(Command::Join("some room".to_string()))
+        );
+        assert_eq!(Command::parse("/leave"), Ok(Command::Leave(None)));
+        assert_eq!(
+            Command::parse("/msg alice how are you?"),
+            Ok(Command::Msg(
+                "alice".to_string(),
+                "how are you?".to_string()
+            ))
+        );
+        assert_eq!(Command::parse("/away"), Ok(Command::Away));
+        assert_eq!(
+            Command::parse("/search foo -bar"),
+            Ok(Command::Search("foo -bar".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            Command::parse("/msg alice"),
+            Err("usage: /msg <user> <message>".to_string())
+        );
+        assert_eq!(
+            Command::parse("/info"),
+            Err("usage: /info <user>".to_string())
+        );
+        assert!(Command::parse("/dance").is_err());
+    }
+}
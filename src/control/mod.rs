@@ -1,7 +1,9 @@
+mod command;
 mod request;
 mod response;
 mod ws;
 
+pub use self::command::Command;
 pub use self::request::*;
 pub use self::response::*;
 pub use self::ws::{listen, Notification, SendError, Sender};
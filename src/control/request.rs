@@ -1,7 +1,15 @@
+use proto;
+use room;
+
+use super::response::SearchResultFile;
+
 /// This enumeration is the list of possible control requests made by the
 /// controller client to the client.
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub enum Request {
+    /// The controller introduces itself. It must do so before making any
+    /// other request.
+    HelloRequest(HelloRequest),
     /// The controller wants to join a room. Contains the room name.
     RoomJoinRequest(String),
     /// The controller wants to leave a rom. Contains the room name.
@@ -12,8 +20,75 @@ pub enum Request {
     RoomListRequest,
     /// The controller wants to send a message to a chat room.
     RoomMessageRequest(RoomMessageRequest),
+    /// The controller wants to know what was said in a chat room.
+    RoomHistoryRequest(RoomHistoryRequest),
+    /// The controller read all the messages in a chat room. Contains the
+    /// room name.
+    RoomMarkReadRequest(String),
+    /// The controller wants to search the chat logs.
+    ChatLogSearchRequest(ChatLogSearchRequest),
     /// The controller wants to know the list of known users.
     UserListRequest,
+    /// The controller wants to download a file.
+    DownloadRequest(DownloadRequest),
+    /// The controller wants to download a folder and all its contents.
+    DownloadFolderRequest(DownloadFolderRequest),
+    /// The controller wants to know the list of queued downloads.
+    DownloadListRequest,
+    /// The controller wants to know the bandwidth limits and the actual
+    /// transfer rates.
+    BandwidthRequest,
+    /// The controller wants to change the bandwidth limits.
+    SetBandwidthLimitsRequest(proto::BandwidthLimits),
+    /// The controller wants to search for files.
+    SearchRequest(SearchRequest),
+    /// The controller wants the shared directories scanned again.
+    ShareRescanRequest,
+    /// The controller wants to receive the messages said in all public
+    /// rooms, whether or not the user is a member of them.
+    EnablePublicChatRequest,
+    /// The controller no longer wants to receive the messages said in all
+    /// public rooms.
+    DisablePublicChatRequest,
+}
+
+/// The names of all the control requests, as they are encoded.
+pub const REQUEST_TYPES: &'static [&'static str] = &[
+    "HelloRequest",
+    "RoomJoinRequest",
+    "RoomLeaveRequest",
+    "LoginStatusRequest",
+    "RoomListRequest",
+    "RoomMessageRequest",
+    "RoomHistoryRequest",
+    "RoomMarkReadRequest",
+    "ChatLogSearchRequest",
+    "UserListRequest",
+    "DownloadRequest",
+    "DownloadFolderRequest",
+    "DownloadListRequest",
+    "BandwidthRequest",
+    "SetBandwidthLimitsRequest",
+    "SearchRequest",
+    "ShareRescanRequest",
+    "EnablePublicChatRequest",
+    "DisablePublicChatRequest",
+];
+
+/// This structure wraps a control request with an id chosen by the
+/// controller, which is echoed in the replies to the request.
+/// Requests may also be sent as is, in which case replies are too.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct RequestEnvelope {
+    pub id: u64,
+    pub request: Request,
+}
+
+/// This structure contains the handshake request from the controller.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct HelloRequest {
+    /// The latest version of the control protocol the controller speaks.
+    pub protocol_version: u32,
 }
 
 /// This structure contains the chat room message request from the controller.
@@ -24,3 +99,248 @@ pub struct RoomMessageRequest {
     /// The message to be said.
     pub message: String,
 }
+
+/// This structure contains the chat room history request from the
+/// controller. Messages are requested page by page, using the ids of the
+/// messages at the edges of the pages already received.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct RoomHistoryRequest {
+    /// The name of the chat room whose history is requested.
+    pub room_name: String,
+    /// If given, only messages with lower ids are returned.
+    pub before: Option<u64>,
+    /// If given, only messages with higher ids are returned.
+    pub after: Option<u64>,
+    /// The maximum number of messages to return. The most recent messages
+    /// are returned, unless only `after` is given.
+    pub limit: usize,
+}
+
+/// This structure contains the chat log search request from the controller.
+/// Messages must satisfy every criterion given.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct ChatLogSearchRequest {
+    /// The room to search.
+    pub room_name: Option<String>,
+    /// The user whose private conversation with us to search.
+    /// If neither this nor `room_name` is given, all rooms and all private
+    /// conversations are searched.
+    pub peer_name: Option<String>,
+    /// Text that the message must contain, ignoring case.
+    pub text: Option<String>,
+    /// The user who must have said the message.
+    pub user_name: Option<String>,
+    /// The earliest and latest time at which the message may have been said,
+    /// in seconds since the epoch.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// The maximum number of messages to return. The most recent messages
+    /// are returned.
+    pub limit: usize,
+}
+
+impl ChatLogSearchRequest {
+    /// Returns true if the given message satisfies the search criteria.
+    pub fn accepts(&self, message: &room::Message) -> bool {
+        if let Some(ref text) = self.text {
+            if !message
+                .message
+                .to_lowercase()
+                .contains(&text.to_lowercase())
+            {
+                return false;
+            }
+        }
+        if let Some(ref user_name) = self.user_name {
+            if message.user_name != *user_name {
+                return false;
+            }
+        }
+        self.since.map_or(true, |since| message.timestamp >= since)
+            && self.until.map_or(true, |until| message.timestamp <= until)
+    }
+}
+
+/// This structure contains the download request from the controller.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct DownloadRequest {
+    /// The name of the user from whom to download the file.
+    pub user_name: String,
+    /// The path of the file on the user's machine.
+    pub file_name: String,
+    /// The size of the file in bytes, as advertised by the user.
+    pub size: u64,
+}
+
+/// This structure contains the folder download request from the controller.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct DownloadFolderRequest {
+    /// The name of the user from whom to download the folder.
+    pub user_name: String,
+    /// The path of the folder on the user's machine.
+    pub folder_name: String,
+}
+
+/// This structure contains the search request from the controller.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SearchRequest {
+    /// The query to send to other users.
+    pub query: String,
+    /// The results that are not worth forwarding to the controller.
+    pub filters: SearchFilters,
+}
+
+/// This structure describes which search results the controller is
+/// interested in. Results that do not pass every filter are dropped.
+#[derive(Clone, Debug, Default, RustcDecodable, RustcEncodable)]
+pub struct SearchFilters {
+    /// The minimum bitrate of files, in kbps. Files whose bitrate is unknown
+    /// do not pass.
+    pub min_bitrate: Option<u32>,
+    /// The minimum size of files in bytes.
+    pub min_size: Option<u64>,
+    /// The maximum size of files in bytes.
+    pub max_size: Option<u64>,
+    /// The allowed file extensions, ignoring case. Any extension is allowed
+    /// if empty.
+    pub extensions: Vec<String>,
+    /// Whether users must have an upload slot free right now.
+    pub require_free_slot: bool,
+    /// The maximum number of uploads queued by users.
+    pub max_queue_length: Option<u32>,
+    /// The users whose results are dropped.
+    pub excluded_users: Vec<String>,
+}
+
+impl SearchFilters {
+    /// Returns true if results from the given user pass the filters, given
+    /// the state of their upload queue.
+    pub fn accepts_user(&self, user_name: &str, has_free_slot: bool, queue_length: u32) -> bool {
+        if self.require_free_slot && !has_free_slot {
+            return false;
+        }
+        if let Some(max_queue_length) = self.max_queue_length {
+            if queue_length > max_queue_length {
+                return false;
+            }
+        }
+        !self
+            .excluded_users
+            .iter()
+            .any(|excluded| excluded == user_name)
+    }
+
+    /// Returns true if the given file passes the filters.
+    pub fn accepts_file(&self, file: &SearchResultFile) -> bool {
+        if let Some(min_bitrate) = self.min_bitrate {
+            match file.audio.bitrate {
+                Some(bitrate) if bitrate >= min_bitrate => (),
+                _ => return false,
+            }
+        }
+        if let Some(min_size) = self.min_size {
+            if file.size < min_size {
+                return false;
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            if file.size > max_size {
+                return false;
+            }
+        }
+        if self.extensions.is_empty() {
+            return true;
+        }
+        // Remote paths use backslashes as separators.
+        let file_name = file.name.rsplit('\\').next().unwrap_or("");
+        match file_name.rfind('.') {
+            Some(index) => {
+                let extension = file_name[index + 1..].to_lowercase();
+                self.extensions
+                    .iter()
+                    .any(|allowed| allowed.to_lowercase() == extension)
+            }
+            None => false,
+        }
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+
+    use super::{Request, REQUEST_TYPES};
+
+    /// Returns the name of the given request, as encoded.
+    /// There is deliberately no catch-all arm, so that adding a request
+    /// breaks the build until it is handled here, and in REQUEST_TYPES.
+    fn request_type(request: &Request) -> &'static str {
+        match *request {
+            Request::HelloRequest(_) => "HelloRequest",
+            Request::RoomJoinRequest(_) => "RoomJoinRequest",
+            Request::RoomLeaveRequest(_) => "RoomLeaveRequest",
+            Request::LoginStatusRequest => "LoginStatusRequest",
+            Request::RoomListRequest => "RoomListRequest",
+            Request::RoomMessageRequest(_) => "RoomMessageRequest",
+            Request::RoomHistoryRequest(_) => "RoomHistoryRequest",
+            Request::RoomMarkReadRequest(_) => "RoomMarkReadRequest",
+            Request::ChatLogSearchRequest(_) => "ChatLogSearchRequest",
+            Request::UserListRequest => "UserListRequest",
+            Request::DownloadRequest(_) => "DownloadRequest",
+            Request::DownloadFolderRequest(_) => "DownloadFolderRequest",
+            Request::DownloadListRequest => "DownloadListRequest",
+            Request::BandwidthRequest => "BandwidthRequest",
+            Request::SetBandwidthLimitsRequest(_) => "SetBandwidthLimitsRequest",
+            Request::SearchRequest(_) => "SearchRequest",
+            Request::ShareRescanRequest => "ShareRescanRequest",
+            Request::EnablePublicChatRequest => "EnablePublicChatRequest",
+            Request::DisablePublicChatRequest => "DisablePublicChatRequest",
+        }
+    }
+
+    /// Returns the smallest encoding of the request with the given name.
+    fn minimal_request(request_type: &str) -> String {
+        let fields = match request_type {
+            "HelloRequest" => r#"{"protocol_version": 1}"#,
+            "RoomJoinRequest" | "RoomLeaveRequest" | "RoomMarkReadRequest" => r#""room""#,
+            "RoomMessageRequest" => r#"{"room_name": "room", "message": "hello"}"#,
+            "RoomHistoryRequest" => r#"{"room_name": "room", "limit": 10}"#,
+            "ChatLogSearchRequest" => r#"{"limit": 10}"#,
+            "DownloadRequest" => r#"{"user_name": "alice", "file_name": "a.mp3", "size": 1}"#,
+            "DownloadFolderRequest" => r#"{"user_name": "alice", "folder_name": "music"}"#,
+            "SetBandwidthLimitsRequest" => "{}",
+            "SearchRequest" => {
+                r#"{"query": "hello", "filters": {"extensions": [],
+                    "require_free_slot": false, "excluded_users": []}}"#
+            }
+            _ => return format!("{:?}", request_type),
+        };
+        format!(
+            r#"{{"variant": {:?}, "fields": [{}]}}"#,
+            request_type, fields
+        )
+    }
+
+    #[test]
+    fn request_types_match_requests() {
+        for &expected_type in REQUEST_TYPES {
+            let payload = minimal_request(expected_type);
+            let request: Request = match json::decode(&payload) {
+                Ok(request) => request,
+                Err(err) => panic!("cannot decode {}: {}", payload, err),
+            };
+            assert_eq!(request_type(&request), expected_type);
+        }
+    }
+
+    #[test]
+    fn request_types_unique() {
+        for (i, request_type) in REQUEST_TYPES.iter().enumerate() {
+            assert!(!REQUEST_TYPES[i + 1..].contains(request_type));
+        }
+    }
+}
@@ -1,21 +1,112 @@
+use audio;
+use chat_log;
+use download;
+use proto;
 use proto::User;
 use room;
 
+use super::request::SearchFilters;
+
 /// This enumeration is the list of possible control responses from the client
 /// to the controller.
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub enum Response {
+    BandwidthResponse(BandwidthResponse),
+    ChatLogSearchResponse(ChatLogSearchResponse),
+    DownloadListResponse(DownloadListResponse),
+    DownloadResponse(DownloadResponse),
+    ErrorResponse(ErrorResponse),
+    HelloResponse(HelloResponse),
+    HighlightResponse(HighlightResponse),
     LoginStatusResponse(LoginStatusResponse),
+    PrivateMessageResponse(PrivateMessageResponse),
+    PublicChatMessageResponse(PublicChatMessageResponse),
+    RoomHistoryResponse(RoomHistoryResponse),
     RoomJoinResponse(RoomJoinResponse),
     RoomLeaveResponse(RoomLeaveResponse),
     RoomListResponse(RoomListResponse),
     RoomMessageResponse(RoomMessageResponse),
     RoomUserJoinedResponse(RoomUserJoinedResponse),
     RoomUserLeftResponse(RoomUserLeftResponse),
+    SearchResponse(SearchResponse),
+    SearchResultResponse(SearchResultResponse),
+    ShareScanResponse(ShareScanResponse),
+    SharedFileListResponse(SharedFileListResponse),
+    StateSnapshotResponse(StateSnapshotResponse),
     UserInfoResponse(UserInfoResponse),
     UserListResponse(UserListResponse),
 }
 
+/// This struct contains the bandwidth limits in effect, and the rates at
+/// which files were actually transferred during the last tick.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct BandwidthResponse {
+    pub limits: proto::BandwidthLimits,
+    pub throughput: proto::Throughput,
+}
+
+/// This struct contains the list of all queued downloads, in queue order.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct DownloadListResponse {
+    pub downloads: Vec<download::Download>,
+}
+
+/// This struct contains the last known information about a download. It is
+/// sent whenever that information changes.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct DownloadResponse {
+    pub download: download::Download,
+}
+
+/// This struct identifies a search that was just started.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SearchResponse {
+    /// The ticket identifying the search in its results.
+    pub ticket: u32,
+    pub query: String,
+}
+
+/// This struct contains the files a user found in response to one of our
+/// searches, that passed the search's filters, best first.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SearchResultResponse {
+    /// The ticket of the search to which these are results.
+    pub ticket: u32,
+    /// The query to which these are results.
+    pub query: String,
+    pub user_name: String,
+    pub files: Vec<SearchResultFile>,
+    /// Whether the user has an upload slot free right now.
+    pub has_free_slot: bool,
+    /// The average upload speed of the user, in bytes per second.
+    pub average_speed: u32,
+    /// The number of uploads queued by the user.
+    pub queue_length: u32,
+}
+
+/// This struct describes a file found by a search.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SearchResultFile {
+    /// The path of the file on the user's machine.
+    pub name: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The audio properties of the file, as far as the user knows.
+    pub audio: audio::AudioInfo,
+    /// How good a result this is, compared to others. Higher is better.
+    pub score: i64,
+}
+
+/// This struct describes how far a scan of the shared directories has got.
+/// It is sent periodically while scanning, and once more when done.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct ShareScanResponse {
+    /// Whether the scan is over, in which case the counts are final.
+    pub is_done: bool,
+    pub num_folders: usize,
+    pub num_files: usize,
+}
+
 #[derive(Debug, RustcEncodable, RustcDecodable)]
 pub struct RoomJoinResponse {
     pub room_name: String,
@@ -62,10 +153,19 @@ pub struct RoomListResponse {
     pub rooms: Vec<(String, room::Room)>,
 }
 
-/// This structure contains a message said in a chat room the user is a member
-/// of.
+/// This structure contains a private message, either sent to us by the
+/// given user or sent by us to the given user.
 #[derive(Debug, RustcDecodable, RustcEncodable)]
-pub struct RoomMessageResponse {
+pub struct PrivateMessageResponse {
+    pub user_name: String,
+    pub message: room::Message,
+}
+
+/// This structure contains a message said in a public chat room, which the
+/// user is not necessarily a member of. These are only sent once public chat
+/// is enabled.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct PublicChatMessageResponse {
     /// The name of the room in which the message was said.
     pub room_name: String,
     /// The name of the user who said the message.
@@ -74,6 +174,202 @@ pub struct RoomMessageResponse {
     pub message: String,
 }
 
+/// This structure contains a message said in a chat room the user is a member
+/// of.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct RoomMessageResponse {
+    /// The name of the room in which the message was said.
+    pub room_name: String,
+    /// The message, as saved in the room's history.
+    pub message: room::Message,
+}
+
+/// This structure contains a message said in a chat room that matches the
+/// user's highlight rules.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct HighlightResponse {
+    pub room_name: String,
+    pub message: room::Message,
+    /// The number of highlighted messages in the room since the controller
+    /// last read it.
+    pub num_mentions: usize,
+}
+
+/// This enumeration is the list of reasons why a control request can fail.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum ErrorCode {
+    /// The request could not be decoded.
+    InvalidRequest,
+    /// The controller made a request before completing the handshake.
+    HandshakeRequired,
+    /// The controller speaks a version of the control protocol that is no
+    /// longer supported.
+    IncompatibleProtocol,
+    /// The command typed in a chat room is unknown or malformed.
+    InvalidCommand,
+    /// The client does not know how to carry out the request yet.
+    NotSupported,
+    RoomNotFound,
+    MembershipChangeInvalid,
+    DownloadNotFound,
+    DownloadAlreadyQueued,
+    /// The user could not be reached, for example because they are offline.
+    UserUnreachable,
+    /// The shared directories are already being scanned.
+    ShareScanInProgress,
+    /// Reading or writing a file failed.
+    IOError,
+    /// Something went wrong that is not the controller's fault.
+    InternalError,
+}
+
+/// This structure contains the reason why a control request failed.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct ErrorResponse {
+    /// What went wrong, for the controller to act upon.
+    pub code: ErrorCode,
+    /// What went wrong, for humans to read.
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        ErrorResponse {
+            code: code,
+            message: message,
+        }
+    }
+}
+
+impl From<room::Error> for ErrorResponse {
+    fn from(err: room::Error) -> Self {
+        let code = match err {
+            room::Error::RoomNotFound(_) => ErrorCode::RoomNotFound,
+            room::Error::MembershipChangeInvalid(_, _) => ErrorCode::MembershipChangeInvalid,
+        };
+        ErrorResponse::new(code, err.to_string())
+    }
+}
+
+impl From<download::Error> for ErrorResponse {
+    fn from(err: download::Error) -> Self {
+        let code = match err {
+            download::Error::DownloadNotFound(_, _) => ErrorCode::DownloadNotFound,
+            download::Error::DownloadAlreadyQueued(_, _) => ErrorCode::DownloadAlreadyQueued,
+            download::Error::IOError(_) => ErrorCode::IOError,
+            download::Error::JSONDecoderError(_) | download::Error::JSONEncoderError(_) => {
+                ErrorCode::InternalError
+            }
+        };
+        ErrorResponse::new(code, err.to_string())
+    }
+}
+
+impl From<chat_log::Error> for ErrorResponse {
+    fn from(err: chat_log::Error) -> Self {
+        let code = match err {
+            chat_log::Error::IOError(_) => ErrorCode::IOError,
+            chat_log::Error::JSONDecoderError(_) | chat_log::Error::JSONEncoderError(_) => {
+                ErrorCode::InternalError
+            }
+        };
+        ErrorResponse::new(code, err.to_string())
+    }
+}
+
+/// This structure contains the reply to the controller's handshake.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct HelloResponse {
+    /// The version of the control protocol to speak from now on: the latest
+    /// one both ends support.
+    pub protocol_version: u32,
+    /// The names of the control requests that are supported.
+    pub request_types: Vec<String>,
+}
+
+/// This structure wraps a reply to a control request that came with an id,
+/// so that the controller can tell which request it answers.
+#[derive(Debug, RustcEncodable)]
+pub struct ResponseEnvelope<'a> {
+    /// The id of the request.
+    pub id: u64,
+    pub response: &'a Response,
+}
+
+/// This structure contains the list of files shared by a user.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SharedFileListResponse {
+    pub user_name: String,
+    pub folders: Vec<proto::peer::Folder>,
+}
+
+/// This structure contains everything a newly connected controller needs to
+/// know. Changes are sent as they happen afterwards.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct StateSnapshotResponse {
+    pub login_status: LoginStatusResponse,
+    /// The rooms the user is a member of, with their members.
+    pub rooms: Vec<RoomSnapshot>,
+    /// The users whose status we know, including those with queued
+    /// downloads, whose status the server keeps us informed of.
+    pub users: Vec<(String, User)>,
+    pub downloads: Vec<download::Download>,
+    pub uploads: Vec<UploadInfo>,
+    pub searches: Vec<SearchSnapshot>,
+}
+
+/// This structure contains what we know about a chat room the user is a
+/// member of, and the most recent messages said in it, in chronological
+/// order.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct RoomSnapshot {
+    pub room_name: String,
+    pub room: room::Room,
+    pub messages: Vec<room::Message>,
+}
+
+/// This structure contains the last known information about an upload.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct UploadInfo {
+    pub user_name: String,
+    pub remote_path: String,
+    pub size: u64,
+    pub bytes_sent: u64,
+    /// True if the upload has been given a slot, false if it is waiting for
+    /// one.
+    pub is_active: bool,
+}
+
+/// This structure describes a search in progress, which expires some time
+/// after it was started.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SearchSnapshot {
+    /// The ticket identifying the search in its results.
+    pub ticket: u32,
+    pub query: String,
+    pub filters: SearchFilters,
+    /// The number of results forwarded to the controllers so far.
+    pub num_results: usize,
+}
+
+/// This structure contains the messages found in the chat logs, as
+/// (room name, message) pairs in chronological order.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct ChatLogSearchResponse {
+    pub messages: Vec<(String, room::Message)>,
+    /// The private messages found, as (name of the other user, message)
+    /// pairs in chronological order. The limit applies to these separately.
+    pub private_messages: Vec<(String, room::Message)>,
+}
+
+/// This structure contains a page of a chat room's history, in chronological
+/// order.
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct RoomHistoryResponse {
+    pub room_name: String,
+    pub messages: Vec<room::Message>,
+}
+
 /// This struct describes the fact that the given user joined the given room.
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub struct RoomUserJoinedResponse {
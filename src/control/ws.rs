@@ -1,28 +1,43 @@
+use std::cmp;
 use std::error;
 use std::fmt;
+use std::rc::Rc;
 use std::sync::mpsc;
 
+use crypto::util::fixed_time_eq;
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream};
 use rustc_serialize::json;
 use ws;
+use ws::util::TcpStream;
 
 use config;
 
 use super::request::*;
 use super::response::*;
 
+/// The latest version of the control protocol.
+const PROTOCOL_VERSION: u32 = 1;
+/// The oldest version of the control protocol still supported.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
 /// This enum contains the possible notifications that the control loop will
 /// send to the client.
+/// Any number of controllers may be connected at the same time, each one
+/// identified by a unique connection id.
 #[derive(Debug)]
 pub enum Notification {
-    /// A new controller has connected: control messages can now be sent on the
-    /// given channel.
-    Connected(Sender),
-    /// The controller has disconnected.
-    Disconnected,
+    /// A new controller has connected with the given id and completed the
+    /// handshake: control messages can now be sent to it on the given
+    /// channel.
+    Connected(usize, Sender),
+    /// The controller with the given id has disconnected.
+    Disconnected(usize),
     /// An irretrievable error has arisen.
     Error(String),
-    /// The controller has sent a request.
-    Request(Request),
+    /// The controller with the given id has sent a request, with the given
+    /// request id if any.
+    Request(usize, Option<u64>, Request),
 }
 
 /// This error is returned when a `Sender` fails to send a control request.
@@ -81,20 +96,192 @@ pub struct Sender {
 
 impl Sender {
     /// Queues up a control response to be sent to the controller.
-    pub fn send(&mut self, response: Response) -> Result<(), SendError> {
-        let encoded = try!(json::encode(&response));
+    pub fn send(&mut self, response: &Response) -> Result<(), SendError> {
+        let encoded = try!(json::encode(response));
         try!(self.sender.send(encoded));
         Ok(())
     }
+
+    /// Queues up a reply to the request with the given id to be sent to the
+    /// controller. Replies to requests without an id are sent as is.
+    pub fn send_reply(
+        &mut self,
+        request_id: Option<u64>,
+        response: &Response,
+    ) -> Result<(), SendError> {
+        let id = match request_id {
+            Some(id) => id,
+            None => return self.send(response),
+        };
+        let encoded = try!(json::encode(&ResponseEnvelope {
+            id: id,
+            response: response,
+        }));
+        try!(self.sender.send(encoded));
+        Ok(())
+    }
+}
+
+/// Decodes the given control request, with its id if it has one.
+fn decode_request(payload: &str) -> Result<(Option<u64>, Request), json::DecoderError> {
+    if let Ok(envelope) = json::decode::<RequestEnvelope>(payload) {
+        return Ok((Some(envelope.id), envelope.request));
+    }
+    let request = try!(json::decode(payload));
+    Ok((None, request))
+}
+
+/// Explains why the given control request could not be decoded, and returns
+/// its id if it can still be found.
+/// Requests of unknown types are told apart from malformed requests, so that
+/// controllers newer than us can tell what we do not support.
+fn describe_invalid_request(
+    payload: &str,
+    err: json::DecoderError,
+) -> (Option<u64>, ErrorResponse) {
+    let json = match json::Json::from_str(payload) {
+        Ok(json) => json,
+        Err(e) => {
+            return (
+                None,
+                ErrorResponse::new(ErrorCode::InvalidRequest, format!("Invalid JSON: {}", e)),
+            )
+        }
+    };
+
+    let request_id = json.find("id").and_then(|id| id.as_u64());
+    let request = json.find("request").unwrap_or(&json);
+    // Requests without fields are encoded as their name, the others as an
+    // object with the name as its variant.
+    let request_type = request
+        .as_string()
+        .or_else(|| request.find("variant").and_then(|name| name.as_string()));
+
+    let response = match request_type {
+        Some(request_type) if !REQUEST_TYPES.contains(&request_type) => ErrorResponse::new(
+            ErrorCode::NotSupported,
+            format!("Unknown request type {:?}", request_type),
+        ),
+        _ => ErrorResponse::new(
+            ErrorCode::InvalidRequest,
+            format!("Invalid request: {}", err),
+        ),
+    };
+    (request_id, response)
+}
+
+/// Returns the version of the control protocol to speak with a controller
+/// whose latest version is the given one, or None if there is none.
+fn negotiate_version(protocol_version: u32) -> Option<u32> {
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        None
+    } else {
+        Some(cmp::min(protocol_version, PROTOCOL_VERSION))
+    }
+}
+
+/// Returns the value of the given parameter in the query string of the given
+/// resource, if any.
+fn query_param<'a>(resource: &'a str, name: &str) -> Option<&'a str> {
+    let query = match resource.find('?') {
+        Some(i) => &resource[i + 1..],
+        None => return None,
+    };
+    query
+        .split('&')
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Returns the given percent-encoded query string value, decoded, or None if
+/// it is not validly encoded.
+fn percent_decode(value: &str) -> Option<String> {
+    let encoded = value.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' {
+            let hex = match encoded.get(i + 1..i + 3) {
+                Some(hex) => hex,
+                None => return None,
+            };
+            let hex = match ::std::str::from_utf8(hex) {
+                Ok(hex) => hex,
+                Err(_) => return None,
+            };
+            match u8::from_str_radix(hex, 16) {
+                Ok(byte) => bytes.push(byte),
+                Err(_) => return None,
+            }
+            i += 3;
+        } else {
+            bytes.push(encoded[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Returns the token presented by the controller in the given handshake
+/// request, if any.
+fn request_token(request: &ws::Request) -> Option<String> {
+    if let Some(header) = request.header("authorization") {
+        if let Ok(header) = ::std::str::from_utf8(header) {
+            if header.starts_with("Bearer ") {
+                return Some(header["Bearer ".len()..].trim().to_string());
+            }
+        }
+    }
+    // Browsers cannot set headers on websocket requests, hence the query
+    // parameter.
+    query_param(request.resource(), "token").and_then(percent_decode)
+}
+
+/// Checks that the given handshake request comes from an allowed origin and
+/// carries the right token. Returns the status code and reason with which to
+/// reject the request otherwise.
+fn check_request(request: &ws::Request) -> Result<(), (u16, &'static str)> {
+    if !config::CONTROL_ALLOWED_ORIGINS.is_empty() {
+        let is_allowed = match request.origin() {
+            Ok(Some(origin)) => config::CONTROL_ALLOWED_ORIGINS.contains(&origin),
+            _ => false,
+        };
+        if !is_allowed {
+            return Err((403, "Forbidden"));
+        }
+    }
+
+    let is_authorized = match request_token(request) {
+        Some(token) => {
+            !token.is_empty() && fixed_time_eq(token.as_bytes(), config::CONTROL_TOKEN.as_bytes())
+        }
+        None => false,
+    };
+    if !is_authorized {
+        return Err((401, "Unauthorized"));
+    }
+    Ok(())
 }
 
 /// This struct handles a single websocket connection.
-#[derive(Debug)]
 struct Handler {
+    /// The id of the connection.
+    id: usize,
     /// The channel on which to send notifications to the client.
     client_tx: mpsc::Sender<Notification>,
     /// The channel on which to send messages to the controller.
     socket_tx: ws::Sender,
+    /// Encrypts the connection, if TLS is enabled.
+    tls_acceptor: Option<Rc<SslAcceptor>>,
+    /// The version of the control protocol agreed upon with the controller,
+    /// once the handshake is done.
+    protocol_version: Option<u32>,
 }
 
 impl Handler {
@@ -107,20 +294,111 @@ impl Handler {
             }
         }
     }
+
+    /// Sends the given response to the controller directly, as a reply to
+    /// the request with the given id if any.
+    fn send_to_controller(&self, request_id: Option<u64>, response: &Response) {
+        let mut sender = Sender {
+            sender: self.socket_tx.clone(),
+        };
+        if let Err(e) = sender.send_reply(request_id, response) {
+            error!("Error sending response to controller {}: {}", self.id, e);
+        }
+    }
+
+    fn send_error(&self, request_id: Option<u64>, code: ErrorCode, message: String) {
+        warn!("Controller {}: {}", self.id, message);
+        self.send_to_controller(
+            request_id,
+            &Response::ErrorResponse(ErrorResponse::new(code, message)),
+        );
+    }
+
+    fn handle_hello_request(
+        &mut self,
+        request_id: Option<u64>,
+        request: HelloRequest,
+    ) -> ws::Result<()> {
+        if self.protocol_version.is_some() {
+            self.send_error(
+                request_id,
+                ErrorCode::InvalidRequest,
+                "Handshake already done".to_string(),
+            );
+            return Ok(());
+        }
+
+        let protocol_version = match negotiate_version(request.protocol_version) {
+            Some(protocol_version) => protocol_version,
+            None => {
+                self.send_error(
+                    request_id,
+                    ErrorCode::IncompatibleProtocol,
+                    format!(
+                        "Protocol version {} is no longer supported, the oldest supported \
+                         version is {}",
+                        request.protocol_version, MIN_PROTOCOL_VERSION
+                    ),
+                );
+                return self.socket_tx.close(ws::CloseCode::Protocol);
+            }
+        };
+        if protocol_version < request.protocol_version {
+            info!(
+                "Controller {} speaks protocol version {}, downgrading to {}",
+                self.id, request.protocol_version, protocol_version
+            );
+        }
+        self.protocol_version = Some(protocol_version);
+
+        self.send_to_controller(
+            request_id,
+            &Response::HelloResponse(HelloResponse {
+                protocol_version: protocol_version,
+                request_types: REQUEST_TYPES.iter().map(|name| name.to_string()).collect(),
+            }),
+        );
+        self.send_to_client(Notification::Connected(
+            self.id,
+            Sender {
+                sender: self.socket_tx.clone(),
+            },
+        ))
+    }
 }
 
 impl ws::Handler for Handler {
+    fn on_request(&mut self, request: &ws::Request) -> ws::Result<ws::Response> {
+        if let Err((status, reason)) = check_request(request) {
+            warn!("Rejecting controller {}: {}", self.id, reason);
+            return Ok(ws::Response::new(status, reason, Vec::new()));
+        }
+        ws::Response::from_request(request)
+    }
+
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> ws::Result<SslStream<TcpStream>> {
+        match self.tls_acceptor {
+            Some(ref tls_acceptor) => tls_acceptor.accept(sock).map_err(From::from),
+            // The server only encrypts connections when TLS is enabled.
+            None => Err(ws::Error::new(
+                ws::ErrorKind::Internal,
+                "TLS is not enabled",
+            )),
+        }
+    }
+
     fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        // The client only hears of the controller once it says hello.
         info!("Websocket open");
-        self.send_to_client(Notification::Connected(Sender {
-            sender: self.socket_tx.clone(),
-        }))
+        Ok(())
     }
 
     fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
         info!("Websocket closed: code: {:?}, reason: {:?}", code, reason);
-        self.send_to_client(Notification::Disconnected)
-            .unwrap_or(())
+        if self.protocol_version.is_some() {
+            self.send_to_client(Notification::Disconnected(self.id))
+                .unwrap_or(())
+        }
     }
 
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
@@ -137,32 +415,101 @@ impl ws::Handler for Handler {
         };
 
         // Decode the json control request.
-        let control_request = match json::decode(&payload) {
-            Ok(control_request) => control_request,
+        let (request_id, control_request) = match decode_request(&payload) {
+            Ok(decoded) => decoded,
             Err(e) => {
-                error!("Received invalid JSON message from controller: {}", e);
-                return Err(ws::Error::new(ws::ErrorKind::Protocol, "Invalid JSON"));
+                error!("Received invalid control request from controller: {}", e);
+                // Tell the controller what went wrong.
+                let (request_id, response) = describe_invalid_request(&payload, e);
+                self.send_to_controller(request_id, &Response::ErrorResponse(response));
+                return Ok(());
             }
         };
 
-        debug!("Received control request: {:?}", control_request);
+        debug!(
+            "Received control request {:?}: {:?}",
+            request_id, control_request
+        );
+
+        let control_request = match control_request {
+            Request::HelloRequest(request) => {
+                return self.handle_hello_request(request_id, request)
+            }
+            control_request => control_request,
+        };
+        if self.protocol_version.is_none() {
+            self.send_error(
+                request_id,
+                ErrorCode::HandshakeRequired,
+                "A HelloRequest must be sent first".to_string(),
+            );
+            return Ok(());
+        }
 
         // Send the control request to the client.
-        self.send_to_client(Notification::Request(control_request))
+        self.send_to_client(Notification::Request(self.id, request_id, control_request))
     }
 }
 
+/// Builds the TLS acceptor for the certificate and key stored in
+/// configuration, or returns None if TLS is not configured.
+fn tls_acceptor() -> Result<Option<SslAcceptor>, ErrorStack> {
+    let (cert_path, key_path) = match (config::CONTROL_TLS_CERT_PATH, config::CONTROL_TLS_KEY_PATH)
+    {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Ok(None),
+    };
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(cert_path)?;
+    builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    Ok(Some(builder.build()))
+}
+
 /// Start listening on the socket address stored in configuration, and send
 /// control notifications to the client through the given channel.
 pub fn listen(client_tx: mpsc::Sender<Notification>) {
+    // Anyone who can reach the port could drive the client otherwise.
+    if config::CONTROL_TOKEN.is_empty() {
+        error!("Not listening for controllers, CONTROL_TOKEN is not configured");
+        client_tx
+            .send(Notification::Error(
+                "Not listening for controllers, CONTROL_TOKEN is not configured".to_string(),
+            ))
+            .unwrap();
+        return;
+    }
+
+    let tls_acceptor = match tls_acceptor() {
+        Ok(tls_acceptor) => tls_acceptor.map(Rc::new),
+        Err(e) => {
+            error!("Unable to set up TLS: {}", e);
+            client_tx
+                .send(Notification::Error(format!("Unable to set up TLS: {}", e)))
+                .unwrap();
+            return;
+        }
+    };
+    if tls_acceptor.is_none() {
+        warn!("Control websocket is not encrypted, TLS is not configured");
+    }
+
+    let mut next_id = 0;
     let websocket_result = ws::Builder::new()
         .with_settings(ws::Settings {
-            max_connections: 1,
+            encrypt_server: tls_acceptor.is_some(),
             ..ws::Settings::default()
         })
-        .build(|socket_tx| Handler {
-            client_tx: client_tx.clone(),
-            socket_tx: socket_tx,
+        .build(|socket_tx| {
+            let id = next_id;
+            next_id += 1;
+            Handler {
+                id: id,
+                client_tx: client_tx.clone(),
+                socket_tx: socket_tx,
+                tls_acceptor: tls_acceptor.clone(),
+                protocol_version: None,
+            }
         });
 
     let websocket = match websocket_result {
@@ -194,3 +541,59 @@ pub fn listen(client_tx: mpsc::Sender<Notification>) {
         }
     }
 }
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        negotiate_version, percent_decode, query_param, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    };
+
+    #[test]
+    fn query_param_found() {
+        assert_eq!(query_param("/?token=abc", "token"), Some("abc"));
+        assert_eq!(query_param("/ws?a=1&token=abc&b=2", "token"), Some("abc"));
+        assert_eq!(query_param("/?token=", "token"), Some(""));
+    }
+
+    #[test]
+    fn query_param_not_found() {
+        assert_eq!(query_param("/", "token"), None);
+        assert_eq!(query_param("/?tokens=abc&token", "token"), None);
+    }
+
+    #[test]
+    fn percent_decode_valid() {
+        assert_eq!(percent_decode("abc"), Some("abc".to_string()));
+        assert_eq!(percent_decode("a%2Bb%26c%3D"), Some("a+b&c=".to_string()));
+        assert_eq!(percent_decode("caf%C3%A9"), Some("café".to_string()));
+    }
+
+    #[test]
+    fn percent_decode_invalid() {
+        assert_eq!(percent_decode("abc%2"), None);
+        assert_eq!(percent_decode("abc%zz"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn negotiate_version_supported() {
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION),
+            Some(MIN_PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 1),
+            Some(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn negotiate_version_unsupported() {
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+    }
+}
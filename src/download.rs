@@ -0,0 +1,725 @@
+use std::collections;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path;
+
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use rustc_serialize::json;
+
+/// This enumeration is the list of possible states for a download.
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum State {
+    /// The download is waiting for the remote user to come online.
+    Queued,
+    /// The remote user has been asked to queue the upload, but hasn't started
+    /// transferring the file yet.
+    Requested,
+    /// The file is being transferred.
+    Transferring,
+    /// The file has been downloaded entirely.
+    Complete,
+    /// The download failed, for the given reason.
+    Failed(String),
+    /// The remote user refused to upload the file, for the given reason.
+    Denied(String),
+}
+
+impl State {
+    /// Returns true if the download is over, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        match *self {
+            State::Complete | State::Failed(_) | State::Denied(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// This structure contains the last known information about a download.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct Download {
+    /// The name of the user from whom the file is downloaded.
+    pub user_name: String,
+    /// The path of the file on the remote user's machine.
+    pub remote_path: String,
+    /// The size of the file in bytes, as advertised by the remote user.
+    pub size: u64,
+    /// The path at which the file is saved locally.
+    pub local_path: String,
+    /// The number of bytes received so far.
+    pub bytes_received: u64,
+    /// The state of the download.
+    pub state: State,
+    /// The position of the file in the remote user's upload queue, if known.
+    pub place_in_queue: Option<u32>,
+}
+
+/// Returns the name of the file at the given remote path.
+fn remote_file_name(remote_path: &str) -> &str {
+    // Remote paths use backslashes as separators, whatever the local
+    // platform.
+    match remote_path.rsplit('\\').next() {
+        Some(file_name) => file_name,
+        None => remote_path,
+    }
+}
+
+/// Returns the given remote path component, made safe for use as a local
+/// file name, or None if it should be skipped altogether.
+/// This prevents remote users from making us write outside the download
+/// directory.
+fn sanitize_component(component: &str) -> Option<String> {
+    match component {
+        "" | "." | ".." => None,
+        _ => Some(component.replace('/', "_")),
+    }
+}
+
+/// Returns the local directory, under the given directory, in which to save
+/// the files of the given remote subfolder of the given remote folder.
+/// The remote folder keeps its name, and the structure of its subfolders is
+/// preserved.
+pub fn local_folder_path(local_dir: &str, remote_folder: &str, remote_subfolder: &str) -> String {
+    let mut local_path = path::PathBuf::from(local_dir);
+    if let Some(component) = sanitize_component(remote_file_name(remote_folder)) {
+        local_path.push(component);
+    }
+
+    let is_subfolder = remote_subfolder.starts_with(remote_folder)
+        && remote_subfolder[remote_folder.len()..]
+            .chars()
+            .next()
+            .map_or(true, |c| c == '\\');
+    if is_subfolder {
+        for component in remote_subfolder[remote_folder.len()..].split('\\') {
+            if let Some(component) = sanitize_component(component) {
+                local_path.push(component);
+            }
+        }
+    }
+
+    local_path.to_string_lossy().into_owned()
+}
+
+impl Download {
+    /// Creates a new queued download of the given file from the given user,
+    /// to be saved in the given directory.
+    pub fn new(user_name: String, remote_path: String, size: u64, local_dir: &str) -> Self {
+        let file_name = match sanitize_component(remote_file_name(&remote_path)) {
+            Some(file_name) => file_name,
+            None => "_".to_string(),
+        };
+        let local_path = path::Path::new(local_dir).join(file_name);
+
+        Download {
+            user_name: user_name,
+            remote_path: remote_path,
+            size: size,
+            local_path: local_path.to_string_lossy().into_owned(),
+            bytes_received: 0,
+            state: State::Queued,
+            place_in_queue: None,
+        }
+    }
+
+    /// Returns the path, in the given directory, of the file in which the
+    /// download is stored until it is complete.
+    /// The name is derived from both the user name and the remote path, so
+    /// that downloads of files with the same name never share partial files.
+    pub fn incomplete_path(&self, incomplete_dir: &str) -> path::PathBuf {
+        let mut hasher = Md5::new();
+        hasher.input_str(&self.user_name);
+        hasher.input_str("\\");
+        hasher.input_str(&self.remote_path);
+        let file_name = format!(
+            "{}.{}",
+            hasher.result_str(),
+            sanitize_component(remote_file_name(&self.remote_path))
+                .unwrap_or_else(|| "_".to_string())
+        );
+        path::Path::new(incomplete_dir).join(file_name)
+    }
+
+    /// Opens the partial file of the download in the given directory, for
+    /// appending. Creates it if needed.
+    pub fn open_incomplete_file(&self, incomplete_dir: &str) -> io::Result<fs::File> {
+        fs::create_dir_all(incomplete_dir)?;
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.incomplete_path(incomplete_dir))
+    }
+
+    /// Moves the partial file of the download in the given directory to the
+    /// download's local path, or next to it if a file already exists there.
+    /// Returns the path to which the file was moved.
+    pub fn move_incomplete_file(&self, incomplete_dir: &str) -> io::Result<String> {
+        let local_path = path::Path::new(&self.local_path);
+        if let Some(local_dir) = local_path.parent() {
+            fs::create_dir_all(local_dir)?;
+        }
+        let local_path = reserve_path(local_path)?;
+        fs::rename(self.incomplete_path(incomplete_dir), &local_path)?;
+        Ok(local_path.to_string_lossy().into_owned())
+    }
+}
+
+/// Creates an empty file at the given path, or at the first path of the form
+/// `stem (n).extension` at which no file exists yet, and returns that path.
+/// Creating the file reserves the name, which a rename then safely replaces.
+fn reserve_path(path: &path::Path) -> io::Result<path::PathBuf> {
+    let stem = match path.file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => String::new(),
+    };
+    let extension = match path.extension() {
+        Some(extension) => format!(".{}", extension.to_string_lossy()),
+        None => String::new(),
+    };
+
+    let mut candidate = path.to_path_buf();
+    let mut i = 0;
+    loop {
+        let result = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate);
+        match result {
+            Ok(_) => return Ok(candidate),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(err),
+        }
+        i += 1;
+        candidate = path.with_file_name(format!("{} ({}){}", stem, i, extension));
+    }
+}
+
+/// The error returned by DownloadMap functions.
+#[derive(Debug)]
+pub enum Error {
+    /// No download of the given file from the given user is known.
+    DownloadNotFound(String, String),
+    /// A download of the given file from the given user is already queued.
+    DownloadAlreadyQueued(String, String),
+    /// Error reading or writing the download queue file.
+    IOError(io::Error),
+    /// Error decoding the download queue file.
+    JSONDecoderError(json::DecoderError),
+    /// Error encoding the download queue.
+    JSONEncoderError(json::EncoderError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::DownloadNotFound(ref user_name, ref remote_path) => write!(
+                f,
+                "download of {:?} from user {:?} not found",
+                remote_path, user_name
+            ),
+
+            Error::DownloadAlreadyQueued(ref user_name, ref remote_path) => write!(
+                f,
+                "download of {:?} from user {:?} already queued",
+                remote_path, user_name
+            ),
+
+            Error::IOError(ref err) => write!(f, "IOError: {}", err),
+            Error::JSONDecoderError(ref err) => write!(f, "JSONDecoderError: {}", err),
+            Error::JSONEncoderError(ref err) => write!(f, "JSONEncoderError: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::DownloadNotFound(_, _) => "download not found",
+            Error::DownloadAlreadyQueued(_, _) => "download already queued",
+            Error::IOError(_) => "IOError",
+            Error::JSONDecoderError(_) => "JSONDecoderError",
+            Error::JSONEncoderError(_) => "JSONEncoderError",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::DownloadNotFound(_, _) => None,
+            Error::DownloadAlreadyQueued(_, _) => None,
+            Error::IOError(ref err) => Some(err),
+            Error::JSONDecoderError(ref err) => Some(err),
+            Error::JSONEncoderError(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl From<json::DecoderError> for Error {
+    fn from(err: json::DecoderError) -> Self {
+        Error::JSONDecoderError(err)
+    }
+}
+
+impl From<json::EncoderError> for Error {
+    fn from(err: json::EncoderError) -> Self {
+        Error::JSONEncoderError(err)
+    }
+}
+
+/// Contains the download queue, in the order in which downloads were
+/// requested, and provides a clean interface to interact with it.
+/// Every change to the queue is persisted to disk, so that downloads survive
+/// restarts.
+#[derive(Debug)]
+pub struct DownloadMap {
+    /// The queued downloads, in chronological order.
+    downloads: Vec<Download>,
+    /// The path of the file in which the queue is persisted.
+    file_path: String,
+    /// For each download whose transfer was interrupted, the number of bytes
+    /// received by then, and the number of interruptions in a row since any
+    /// more were received. This is not persisted, so that every download
+    /// gets a fresh start after a restart.
+    stalls: collections::HashMap<(String, String), (u64, u32)>,
+}
+
+impl DownloadMap {
+    /// Creates an empty queue, persisted to the given file.
+    pub fn new(file_path: &str) -> Self {
+        DownloadMap {
+            downloads: Vec::new(),
+            file_path: file_path.to_string(),
+            stalls: collections::HashMap::new(),
+        }
+    }
+
+    /// Loads the queue persisted in the given file.
+    /// If the file does not exist, returns an empty queue.
+    /// Downloads that were in progress when the queue was last saved are
+    /// queued again, as the connections they depended on are gone.
+    pub fn load(file_path: &str) -> Result<Self, Error> {
+        let mut map = Self::new(file_path);
+
+        let mut file = match fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(map),
+            Err(err) => return Err(Error::from(err)),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let mut downloads: Vec<Download> = json::decode(&contents)?;
+        for download in downloads.iter_mut() {
+            match download.state {
+                State::Requested | State::Transferring => download.state = State::Queued,
+                _ => (),
+            }
+        }
+
+        map.downloads = downloads;
+        Ok(map)
+    }
+
+    /// Writes the queue to disk.
+    /// The queue is first written to a temporary file, which then replaces
+    /// the previous version, so that a crash never leaves a truncated file.
+    fn save(&self) -> Result<(), Error> {
+        let encoded = json::encode(&self.downloads)?;
+
+        let tmp_path = format!("{}.tmp", self.file_path);
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(encoded.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.file_path)?;
+        Ok(())
+    }
+
+    /// Persists the queue, logging any error that arises.
+    fn persist(&self) {
+        if let Err(err) = self.save() {
+            error!(
+                "Cannot save download queue to {:?}: {}",
+                self.file_path, err
+            );
+        }
+    }
+
+    /// Looks up the given download, returning a mutable reference to it if
+    /// found, or an error if not found.
+    fn get_mut_strict(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+    ) -> Result<&mut Download, Error> {
+        let result = self.downloads.iter_mut().find(|download| {
+            download.user_name == user_name && download.remote_path == remote_path
+        });
+        match result {
+            Some(download) => Ok(download),
+            None => Err(Error::DownloadNotFound(
+                user_name.to_string(),
+                remote_path.to_string(),
+            )),
+        }
+    }
+
+    /// Looks up the given download, returning an immutable reference to it if
+    /// found.
+    pub fn get(&self, user_name: &str, remote_path: &str) -> Option<&Download> {
+        self.downloads
+            .iter()
+            .find(|download| download.user_name == user_name && download.remote_path == remote_path)
+    }
+
+    /// Adds the given download at the end of the queue.
+    /// If the same file was already downloaded from the same user but the
+    /// download did not succeed, it is replaced.
+    /// Returns an error if the same file is already queued from the same user.
+    pub fn enqueue(&mut self, download: Download) -> Result<(), Error> {
+        let state_opt = self
+            .get(&download.user_name, &download.remote_path)
+            .map(|download| download.state.clone());
+        match state_opt {
+            None => (),
+            Some(State::Failed(_)) | Some(State::Denied(_)) => {
+                self.downloads.retain(|other| {
+                    other.user_name != download.user_name
+                        || other.remote_path != download.remote_path
+                });
+            }
+            Some(_) => {
+                return Err(Error::DownloadAlreadyQueued(
+                    download.user_name,
+                    download.remote_path,
+                ))
+            }
+        }
+        self.downloads.push(download);
+        self.persist();
+        Ok(())
+    }
+
+    /// Sets the state of the given download.
+    pub fn set_state(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+        state: State,
+    ) -> Result<(), Error> {
+        {
+            let download = self.get_mut_strict(user_name, remote_path)?;
+            download.state = state;
+        }
+        if self
+            .get(user_name, remote_path)
+            .map_or(false, |download| download.state.is_finished())
+        {
+            self.stalls
+                .remove(&(user_name.to_string(), remote_path.to_string()));
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Sets the path at which the given download was saved.
+    pub fn set_local_path(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+        local_path: String,
+    ) -> Result<(), Error> {
+        {
+            let download = self.get_mut_strict(user_name, remote_path)?;
+            download.local_path = local_path;
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Records that the transfer of the given download was interrupted once
+    /// the given number of bytes had been received in total.
+    /// Returns the number of interruptions in a row since the download last
+    /// made progress.
+    pub fn record_interruption(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+        bytes_received: u64,
+    ) -> u32 {
+        let stall = self
+            .stalls
+            .entry((user_name.to_string(), remote_path.to_string()))
+            .or_insert((bytes_received, 0));
+        if bytes_received > stall.0 {
+            *stall = (bytes_received, 0);
+        } else {
+            stall.1 += 1;
+        }
+        stall.1
+    }
+
+    /// Sets the size of the given download, as advertised by the remote user
+    /// when starting the transfer.
+    pub fn set_size(&mut self, user_name: &str, remote_path: &str, size: u64) -> Result<(), Error> {
+        {
+            let download = self.get_mut_strict(user_name, remote_path)?;
+            download.size = size;
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Marks the given download as transferring, starting from the given
+    /// offset.
+    pub fn start_transferring(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+        offset: u64,
+    ) -> Result<(), Error> {
+        {
+            let download = self.get_mut_strict(user_name, remote_path)?;
+            download.bytes_received = offset;
+            download.state = State::Transferring;
+            download.place_in_queue = None;
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Sets the number of bytes received so far for the given download.
+    /// This is not persisted, as the partial file on disk tells us as much.
+    pub fn set_bytes_received(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+        bytes_received: u64,
+    ) -> Result<(), Error> {
+        let download = self.get_mut_strict(user_name, remote_path)?;
+        download.bytes_received = bytes_received;
+        Ok(())
+    }
+
+    /// Sets the position of the given download in the remote user's upload
+    /// queue.
+    pub fn set_place_in_queue(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+        place: u32,
+    ) -> Result<(), Error> {
+        {
+            let download = self.get_mut_strict(user_name, remote_path)?;
+            download.place_in_queue = Some(place);
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Marks all of the given user's queued downloads as requested, and
+    /// returns their remote paths.
+    pub fn start_requesting(&mut self, user_name: &str) -> Vec<String> {
+        let mut remote_paths = Vec::new();
+        for download in self.downloads.iter_mut() {
+            if download.user_name == user_name && download.state == State::Queued {
+                download.state = State::Requested;
+                download.place_in_queue = None;
+                remote_paths.push(download.remote_path.clone());
+            }
+        }
+        if !remote_paths.is_empty() {
+            self.persist();
+        }
+        remote_paths
+    }
+
+    /// Queues all of the given user's requested downloads again, for example
+    /// when the request could not be delivered.
+    pub fn requeue(&mut self, user_name: &str) {
+        let mut changed = false;
+        for download in self.downloads.iter_mut() {
+            if download.user_name == user_name && download.state == State::Requested {
+                download.state = State::Queued;
+                changed = true;
+            }
+        }
+        if changed {
+            self.persist();
+        }
+    }
+
+    /// Marks all of the given user's unfinished downloads as failed, for the
+    /// given reason.
+    pub fn fail_all(&mut self, user_name: &str, reason: &str) {
+        let mut changed = false;
+        for download in self.downloads.iter_mut() {
+            if download.user_name != user_name {
+                continue;
+            }
+            if !download.state.is_finished() {
+                download.state = State::Failed(reason.to_string());
+                changed = true;
+            }
+        }
+        if changed {
+            self.persist();
+        }
+    }
+
+    /// Returns the names of the users from whom downloads are still queued.
+    pub fn queued_user_names(&self) -> collections::HashSet<String> {
+        let mut user_names = collections::HashSet::new();
+        for download in self.downloads.iter() {
+            if download.state == State::Queued {
+                user_names.insert(download.user_name.clone());
+            }
+        }
+        user_names
+    }
+
+    /// Returns the user names and remote paths of the downloads that the
+    /// remote users have been asked to queue, but not yet started uploading.
+    pub fn requested(&self) -> Vec<(String, String)> {
+        self.downloads
+            .iter()
+            .filter(|download| download.state == State::Requested)
+            .map(|download| (download.user_name.clone(), download.remote_path.clone()))
+            .collect()
+    }
+
+    /// Returns the list of all downloads from the given user, in queue order.
+    pub fn get_user_list(&self, user_name: &str) -> Vec<Download> {
+        self.downloads
+            .iter()
+            .filter(|download| download.user_name == user_name)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the list of all downloads, in queue order.
+    pub fn get_list(&self) -> Vec<Download> {
+        self.downloads.clone()
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use super::{local_folder_path, sanitize_component, Download};
+
+    /// Returns the given components joined under the "downloads" directory.
+    fn local_path(components: &[&str]) -> String {
+        let mut local_path = path::PathBuf::from("downloads");
+        for component in components {
+            local_path.push(component);
+        }
+        local_path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn sanitize_component_skipped() {
+        assert_eq!(sanitize_component(""), None);
+        assert_eq!(sanitize_component("."), None);
+        assert_eq!(sanitize_component(".."), None);
+    }
+
+    #[test]
+    fn sanitize_component_kept() {
+        assert_eq!(sanitize_component("song.mp3"), Some("song.mp3".to_string()));
+        assert_eq!(sanitize_component("..."), Some("...".to_string()));
+        assert_eq!(sanitize_component("a/b"), Some("a_b".to_string()));
+        assert_eq!(sanitize_component("../.."), Some(".._..".to_string()));
+    }
+
+    #[test]
+    fn local_folder_path_folder() {
+        assert_eq!(
+            local_folder_path("downloads", "Music\\Album", "Music\\Album"),
+            local_path(&["Album"])
+        );
+        assert_eq!(
+            local_folder_path("downloads", "Music\\Album", "Music\\Album\\CD 1"),
+            local_path(&["Album", "CD 1"])
+        );
+    }
+
+    #[test]
+    fn local_folder_path_dot_components() {
+        assert_eq!(
+            local_folder_path("downloads", "Music\\..", "Music\\..\\..\\etc"),
+            local_path(&["etc"])
+        );
+        assert_eq!(
+            local_folder_path("downloads", "Music\\Album", "Music\\Album\\..\\.\\..\\etc"),
+            local_path(&["Album", "etc"])
+        );
+    }
+
+    #[test]
+    fn local_folder_path_empty_components() {
+        assert_eq!(
+            local_folder_path("downloads", "Music\\Album", "Music\\Album\\\\CD 1\\"),
+            local_path(&["Album", "CD 1"])
+        );
+        assert_eq!(local_folder_path("downloads", "", ""), local_path(&[]));
+    }
+
+    #[test]
+    fn local_folder_path_embedded_slash() {
+        assert_eq!(
+            local_folder_path("downloads", "Music\\/etc", "Music\\/etc\\a/../b"),
+            local_path(&["_etc", "a_.._b"])
+        );
+    }
+
+    #[test]
+    fn local_folder_path_not_a_subfolder() {
+        assert_eq!(
+            local_folder_path("downloads", "Music\\Album", "Music\\Albums\\CD 1"),
+            local_path(&["Album"])
+        );
+        assert_eq!(
+            local_folder_path("downloads", "Music\\Album", "Other\\Album\\CD 1"),
+            local_path(&["Album"])
+        );
+    }
+
+    #[test]
+    fn download_paths_stay_in_their_directory() {
+        let download = Download::new(
+            "alice".to_string(),
+            "Music\\a/../..".to_string(),
+            42,
+            "downloads",
+        );
+        assert_eq!(download.local_path, local_path(&["a_.._.."]));
+        assert_eq!(
+            download.incomplete_path("incomplete").parent(),
+            Some(path::Path::new("incomplete"))
+        );
+
+        let download = Download::new(
+            "alice".to_string(),
+            "Music\\..".to_string(),
+            42,
+            "downloads",
+        );
+        assert_eq!(download.local_path, local_path(&["_"]));
+    }
+}
@@ -0,0 +1,73 @@
+use regex;
+
+/// Decides which chat messages deserve the user's attention: those that
+/// mention the user's name or one of the configured keywords, or that match
+/// one of the configured patterns.
+#[derive(Debug)]
+pub struct Highlighter {
+    patterns: Vec<regex::Regex>,
+}
+
+/// Returns a case-insensitive pattern matching the given word, as long as it
+/// is not part of a longer word.
+fn word_pattern(word: &str) -> String {
+    format!(r"(?i)(?:^|\W){}(?:$|\W)", regex::escape(word))
+}
+
+impl Highlighter {
+    /// Creates a highlighter for messages mentioning the given user name or
+    /// keywords, or matching the given regular expressions.
+    /// Invalid regular expressions are logged and ignored.
+    pub fn new(user_name: &str, keywords: &[&str], patterns: &[&str]) -> Self {
+        let mut compiled = Vec::new();
+
+        let words = Some(user_name).into_iter().chain(keywords.iter().cloned());
+        for word in words.filter(|word| !word.is_empty()) {
+            // Escaped words always make valid patterns.
+            compiled.push(regex::Regex::new(&word_pattern(word)).unwrap());
+        }
+
+        for pattern in patterns {
+            match regex::Regex::new(pattern) {
+                Ok(regex) => compiled.push(regex),
+                Err(err) => error!("Invalid highlight pattern {:?}: {}", pattern, err),
+            }
+        }
+
+        Highlighter { patterns: compiled }
+    }
+
+    /// Returns true if the given message should be highlighted.
+    pub fn is_match(&self, message: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.is_match(message))
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use super::Highlighter;
+
+    #[test]
+    fn match_user_name_and_keywords() {
+        let highlighter = Highlighter::new("alice", &["solstice", "c++"], &[]);
+        assert!(highlighter.is_match("hey Alice, how are you?"));
+        assert!(highlighter.is_match("alice"));
+        assert!(highlighter.is_match("who uses Solstice?"));
+        assert!(highlighter.is_match("c++ is fine"));
+        assert!(!highlighter.is_match("malice aforethought"));
+        assert!(!highlighter.is_match("solstices"));
+    }
+
+    #[test]
+    fn match_patterns() {
+        let highlighter = Highlighter::new("", &[], &[r"\bflac\b.*wanted", "(invalid"]);
+        assert!(highlighter.is_match("flac of that album wanted"));
+        assert!(!highlighter.is_match("wanted: flac"));
+    }
+}
@@ -1,8 +1,15 @@
+mod audio;
+mod chat_log;
 mod client;
 mod config;
 mod control;
+mod download;
+mod highlight;
 mod proto;
 mod room;
+mod search;
+mod share;
+mod upload;
 mod user;
 
 extern crate byteorder;
@@ -10,11 +17,15 @@ extern crate bytes;
 extern crate core;
 extern crate crypto;
 extern crate encoding;
+extern crate flate2;
 extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 extern crate mio;
+extern crate notify;
+extern crate openssl;
+extern crate regex;
 extern crate rustc_serialize;
 extern crate slab;
 extern crate tokio_core;
@@ -1,11 +1,17 @@
 use std::fmt;
 use std::io;
+use std::io::{Read, Write};
 use std::net;
 use std::u16;
 
 use bytes::{Buf, BufMut, BytesMut, LittleEndian};
 use encoding::all::WINDOWS_1252;
 use encoding::{DecoderTrap, EncoderTrap, Encoding};
+use flate2;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use super::constants::MAX_DECOMPRESSED_SIZE;
 
 // Constants
 // ---------
@@ -13,6 +19,9 @@ use encoding::{DecoderTrap, EncoderTrap, Encoding};
 /// Length of an encoded 32-bit integer in bytes.
 const U32_BYTE_LEN: usize = 4;
 
+/// Length of an encoded 64-bit integer in bytes.
+const U64_BYTE_LEN: usize = 8;
+
 /*===================================*
  * BASIC TYPES ENCODING AND DECODING *
  *===================================*/
@@ -20,7 +29,9 @@ const U32_BYTE_LEN: usize = 4;
 // The protocol is pretty basic, though quirky. Base types are serialized in
 // the following way:
 //
+//   * 8-bit integers are serialized in a single byte.
 //   * 32-bit integers are serialized in 4 bytes, little-endian.
+//   * 64-bit integers are serialized in 8 bytes, little-endian.
 //   * 16-bit integers are serialized as 32-bit integers with upper bytes set
 //     to 0.
 //   * Booleans are serialized as single bytes, containing either 0 or 1.
@@ -29,6 +40,9 @@ const U32_BYTE_LEN: usize = 4;
 //     encoded characters.
 //   * Pairs are serialized as two consecutive values.
 //   * Vectors are serialized as length-prefixed arrays of serialized values.
+//
+// Some messages are compressed with zlib, in which case everything after the
+// message code is compressed.
 
 pub trait Decode<T> {
     /// Attempts to decode an istance of `T` from `self`.
@@ -88,6 +102,20 @@ impl<T: Buf> Decode<u32> for T {
     }
 }
 
+impl<T: Buf> Decode<u8> for T {
+    fn decode(&mut self) -> io::Result<u8> {
+        self.expect_remaining("u8", 1)?;
+        Ok(self.get_u8())
+    }
+}
+
+impl<T: Buf> Decode<u64> for T {
+    fn decode(&mut self) -> io::Result<u64> {
+        self.expect_remaining("u64", U64_BYTE_LEN)?;
+        Ok(self.get_u64::<LittleEndian>())
+    }
+}
+
 impl<T: Buf> Decode<u16> for T {
     fn decode(&mut self) -> io::Result<u16> {
         let n = self.decode_u32_generic("u16")?;
@@ -167,6 +195,35 @@ pub trait ProtoEncode {
     fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()>;
 }
 
+/// Decodes a value from the rest of the given buffer, which must be
+/// zlib-compressed.
+/// Fails if the value takes up more than `MAX_DECOMPRESSED_SIZE` bytes once
+/// decompressed, as a small packet could otherwise exhaust our memory.
+pub fn decode_compressed<B, T>(buf: &mut B) -> io::Result<T>
+where
+    B: Buf,
+    io::Cursor<BytesMut>: Decode<T>,
+{
+    let mut compressed = vec![0; buf.remaining()];
+    buf.copy_to_slice(&mut compressed);
+
+    let mut bytes = Vec::new();
+    ZlibDecoder::new(&compressed[..])
+        .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() > MAX_DECOMPRESSED_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "compressed value larger than {} bytes",
+                MAX_DECOMPRESSED_SIZE
+            ),
+        ));
+    }
+
+    io::Cursor::new(BytesMut::from(bytes)).decode()
+}
+
 // A `ProtoEncoder` knows how to encode various types of values into protocol
 // messages.
 pub struct ProtoEncoder<'a> {
@@ -189,6 +246,22 @@ impl<'a> ProtoEncoder<'a> {
         Ok(())
     }
 
+    pub fn encode_u8(&mut self, val: u8) -> io::Result<()> {
+        if !self.inner.has_remaining_mut() {
+            self.inner.reserve(1);
+        }
+        self.inner.put_u8(val);
+        Ok(())
+    }
+
+    pub fn encode_u64(&mut self, val: u64) -> io::Result<()> {
+        if self.inner.remaining_mut() < U64_BYTE_LEN {
+            self.inner.reserve(U64_BYTE_LEN);
+        }
+        self.inner.put_u64::<LittleEndian>(val);
+        Ok(())
+    }
+
     pub fn encode_u16(&mut self, val: u16) -> io::Result<()> {
         self.encode_u32(val as u32)
     }
@@ -231,6 +304,17 @@ impl<'a> ProtoEncoder<'a> {
         pair.1.encode(self)
     }
 
+    pub fn encode_compressed<T: ProtoEncode>(&mut self, val: &T) -> io::Result<()> {
+        let mut bytes = BytesMut::new();
+        val.encode(&mut ProtoEncoder::new(&mut bytes))?;
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&bytes)?;
+        let compressed = encoder.finish()?;
+        self.inner.extend(compressed);
+        Ok(())
+    }
+
     pub fn encode_vec<T: ProtoEncode>(&mut self, vec: &[T]) -> io::Result<()> {
         self.encode_u32(vec.len() as u32)?;
         for ref item in vec {
@@ -246,6 +330,18 @@ impl ProtoEncode for u32 {
     }
 }
 
+impl ProtoEncode for u8 {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_u8(*self)
+    }
+}
+
+impl ProtoEncode for u64 {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_u64(*self)
+    }
+}
+
 impl ProtoEncode for bool {
     fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
         encoder.encode_bool(*self)
@@ -319,10 +415,15 @@ pub mod tests {
     use std::net;
     use std::u16;
     use std::u32;
+    use std::u64;
+
+    use std::io::Write;
 
     use bytes::{Buf, BytesMut};
+    use flate2;
+    use flate2::write::ZlibEncoder;
 
-    use super::{Decode, ProtoEncode, ProtoEncoder};
+    use super::{decode_compressed, Decode, ProtoEncode, ProtoEncoder, MAX_DECOMPRESSED_SIZE};
 
     pub fn roundtrip<T>(input: T)
     where
@@ -441,6 +542,71 @@ pub mod tests {
         expect_io_error(result, io::ErrorKind::UnexpectedEof, "reading u32");
     }
 
+    #[test]
+    fn roundtrip_u8() {
+        roundtrip(0u8);
+        roundtrip(255u8);
+    }
+
+    #[test]
+    fn roundtrip_compressed_string() {
+        let mut bytes = BytesMut::new();
+        ProtoEncoder::new(&mut bytes)
+            .encode_compressed(&"hello hello hello".to_string())
+            .unwrap();
+
+        let mut cursor = io::Cursor::new(bytes);
+        let output: String = decode_compressed(&mut cursor).unwrap();
+        assert_eq!(output, "hello hello hello");
+        assert_eq!(cursor.remaining(), 0);
+    }
+
+    #[test]
+    fn decode_compressed_too_large() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        let zeros = vec![0u8; 1 << 20];
+        let mut num_written = 0;
+        while num_written <= MAX_DECOMPRESSED_SIZE {
+            encoder.write_all(&zeros).unwrap();
+            num_written += zeros.len();
+        }
+        let compressed = encoder.finish().unwrap();
+
+        let mut cursor = io::Cursor::new(BytesMut::from(compressed));
+        let result: io::Result<u32> = decode_compressed(&mut cursor);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encode_u64() {
+        let mut bytes = BytesMut::from(vec![13]);
+        ProtoEncoder::new(&mut bytes)
+            .encode_u64(0x0807060504030201)
+            .unwrap();
+        assert_eq!(bytes, vec![13, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn decode_u64() {
+        let mut cursor = new_cursor(vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        let val: u64 = cursor.decode().unwrap();
+        assert_eq!(val, 0x0807060504030201);
+        assert_eq!(cursor.remaining(), 0);
+    }
+
+    #[test]
+    fn decode_u64_unexpected_eof() {
+        let result: io::Result<u64> = new_cursor(vec![1, 2, 3, 4]).decode();
+        expect_io_error(result, io::ErrorKind::UnexpectedEof, "reading u64");
+    }
+
+    #[test]
+    fn roundtrip_u64() {
+        roundtrip(0u64);
+        roundtrip(u32::MAX as u64 + 1);
+        roundtrip(u64::MAX);
+    }
+
     #[test]
     fn encode_bool() {
         let mut bytes = BytesMut::from(vec![13]);
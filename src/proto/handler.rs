@@ -1,17 +1,21 @@
 use std::fmt;
+use std::fs;
 use std::io;
+use std::mem;
 use std::net;
 use std::net::ToSocketAddrs;
 use std::sync::mpsc;
+use std::time;
 
 use mio;
 use slab;
 
 use config;
 
+use super::limiter::{BandwidthLimits, Limiter, Throughput};
 use super::peer;
 use super::server::*;
-use super::{Intent, SendPacket, Stream};
+use super::{Intent, ReceiveTransfer, SendPacket, Stream, TransferMode, TransferStream};
 
 /*===========*
  * CONSTANTS *
@@ -33,6 +37,21 @@ pub enum Request {
     PeerConnect(usize, net::Ipv4Addr, u16),
     PeerMessage(usize, peer::Message),
     ServerRequest(ServerRequest),
+    /// Set the limits on the rates at which files are transferred.
+    SetBandwidthLimits(BandwidthLimits),
+    /// Open a file transfer connection with the given id, to receive a file
+    /// from or send a file to the given user at the given address. Peer
+    /// messages sent to that id are written to the connection until the
+    /// transfer starts.
+    TransferConnect(usize, String, TransferMode, net::Ipv4Addr, u16),
+    /// Start receiving the file on the given transfer connection, appending
+    /// it to the given file, from the given offset up to the given size.
+    TransferReceive(usize, fs::File, u64, u64),
+    /// Start sending the given file, of the given size, on the given
+    /// transfer connection, as the transfer identified by the given token.
+    TransferSend(usize, fs::File, u32, u64),
+    /// Close the given transfer connection.
+    TransferClose(usize),
 }
 
 #[derive(Debug)]
@@ -41,6 +60,21 @@ pub enum Response {
     PeerConnectionOpen(usize),
     PeerMessage(usize, peer::Message),
     ServerResponse(ServerResponse),
+    /// The connection to the server was lost. Server requests are dropped
+    /// until it is open again.
+    ServerConnectionClosed,
+    /// A new connection to the server is being opened, on which server
+    /// requests can be sent.
+    ServerConnectionOpen,
+    /// The uploader on the given transfer connection sent the given token.
+    TransferToken(usize, u32),
+    /// The file transferred on the given transfer connection has been
+    /// written to disk or sent up to the given position.
+    TransferProgress(usize, u64),
+    /// Sent every config::TICK_INTERVAL_SECS seconds, for periodic tasks.
+    Tick,
+    /// The rates at which files were transferred since the last tick.
+    Throughput(Throughput),
 }
 
 /*========================*
@@ -84,6 +118,47 @@ impl SendPacket for PeerResponseSender {
     }
 }
 
+/*=========*
+ * TIMEOUT *
+ *=========*/
+
+#[derive(Clone, Copy, Debug)]
+enum Timeout {
+    /// Time to send the client a tick.
+    Tick,
+    /// Time to resume throttled transfers.
+    Unthrottle,
+    /// Time to connect to the server again.
+    ServerReconnect,
+}
+
+/*==========================*
+ * TRANSFER RESPONSE SENDER *
+ *==========================*/
+
+pub struct TransferResponseSender {
+    sender: mpsc::Sender<Response>,
+    peer_id: usize,
+}
+
+impl ReceiveTransfer for TransferResponseSender {
+    type Error = mpsc::SendError<Response>;
+
+    fn notify_open(&mut self) -> Result<(), Self::Error> {
+        self.sender.send(Response::PeerConnectionOpen(self.peer_id))
+    }
+
+    fn notify_token(&mut self, token: u32) -> Result<(), Self::Error> {
+        self.sender
+            .send(Response::TransferToken(self.peer_id, token))
+    }
+
+    fn notify_progress(&mut self, position: u64) -> Result<(), Self::Error> {
+        self.sender
+            .send(Response::TransferProgress(self.peer_id, position))
+    }
+}
+
 /*=========*
  * HANDLER *
  *=========*/
@@ -93,8 +168,20 @@ impl SendPacket for PeerResponseSender {
 struct Handler {
     server_stream: Stream<ServerResponseSender>,
 
+    /// Whether the server stream is registered with the event loop, i.e. has
+    /// not been closed.
+    is_server_connected: bool,
+
     peer_streams: slab::Slab<Stream<PeerResponseSender>, usize>,
 
+    // Transfer streams share the peer id space with peer streams.
+    transfer_streams: slab::Slab<TransferStream<TransferResponseSender>, usize>,
+
+    limiter: Limiter,
+
+    // The transfer streams waiting to be unthrottled.
+    throttled_transfers: Vec<usize>,
+
     listener: mio::tcp::TcpListener,
 
     client_tx: mpsc::Sender<Response>,
@@ -115,19 +202,51 @@ where
     ))
 }
 
+/// Returns a new stream to the server, registered with the given event loop.
+fn connect_to_server(
+    client_tx: &mpsc::Sender<Response>,
+    event_loop: &mut mio::deprecated::EventLoop<Handler>,
+) -> io::Result<Stream<ServerResponseSender>> {
+    let host = config::SERVER_HOST;
+    let port = config::SERVER_PORT;
+    let server_stream = try!(Stream::new(
+        (host, port),
+        ServerResponseSender(client_tx.clone()),
+    ));
+
+    info!("Connected to server at {}:{}", host, port);
+
+    try!(event_loop.register(
+        server_stream.evented(),
+        mio::Token(SERVER_TOKEN),
+        mio::Ready::all(),
+        mio::PollOpt::edge() | mio::PollOpt::oneshot(),
+    ));
+    Ok(server_stream)
+}
+
+/// Schedules the next attempt to connect to the server.
+fn schedule_server_reconnect(event_loop: &mut mio::deprecated::EventLoop<Handler>) {
+    let delay = time::Duration::from_secs(config::SERVER_RECONNECT_DELAY_SECS);
+    if let Err(err) = event_loop.timeout(Timeout::ServerReconnect, delay) {
+        error!("Cannot schedule server reconnection: {:?}", err);
+    }
+}
+
+/// Schedules the next tick.
+fn schedule_tick(event_loop: &mut mio::deprecated::EventLoop<Handler>) {
+    let delay = time::Duration::from_secs(config::TICK_INTERVAL_SECS);
+    if let Err(err) = event_loop.timeout(Timeout::Tick, delay) {
+        error!("Cannot schedule tick: {:?}", err);
+    }
+}
+
 impl Handler {
     fn new(
         client_tx: mpsc::Sender<Response>,
         event_loop: &mut mio::deprecated::EventLoop<Self>,
     ) -> io::Result<Self> {
-        let host = config::SERVER_HOST;
-        let port = config::SERVER_PORT;
-        let server_stream = try!(Stream::new(
-            (host, port),
-            ServerResponseSender(client_tx.clone()),
-        ));
-
-        info!("Connected to server at {}:{}", host, port);
+        let server_stream = try!(connect_to_server(&client_tx, event_loop));
 
         let listener = try!(listener_bind((config::LISTEN_HOST, config::LISTEN_PORT)));
         info!(
@@ -136,13 +255,6 @@ impl Handler {
             config::LISTEN_PORT
         );
 
-        try!(event_loop.register(
-            server_stream.evented(),
-            mio::Token(SERVER_TOKEN),
-            mio::Ready::all(),
-            mio::PollOpt::edge() | mio::PollOpt::oneshot(),
-        ));
-
         try!(event_loop.register(
             &listener,
             mio::Token(LISTEN_TOKEN),
@@ -150,11 +262,21 @@ impl Handler {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         ));
 
+        schedule_tick(event_loop);
+
         Ok(Handler {
             server_stream: server_stream,
 
+            is_server_connected: true,
+
             peer_streams: slab::Slab::new(config::MAX_PEERS),
 
+            transfer_streams: slab::Slab::new(config::MAX_PEERS),
+
+            limiter: Limiter::new(BandwidthLimits::from_config()),
+
+            throttled_transfers: Vec::new(),
+
             listener: listener,
 
             client_tx: client_tx,
@@ -205,6 +327,59 @@ impl Handler {
         Ok(())
     }
 
+    fn connect_transfer(
+        &mut self,
+        peer_id: usize,
+        user_name: &str,
+        mode: TransferMode,
+        ip: net::Ipv4Addr,
+        port: u16,
+        event_loop: &mut mio::deprecated::EventLoop<Self>,
+    ) -> Result<(), String> {
+        if self.peer_streams.contains(peer_id) {
+            return Err("id already taken".to_string());
+        }
+
+        let vacant_entry = match self.transfer_streams.entry(peer_id) {
+            None => return Err("id out of range".to_string()),
+
+            Some(slab::Entry::Occupied(_)) => return Err("id already taken".to_string()),
+
+            Some(slab::Entry::Vacant(vacant_entry)) => vacant_entry,
+        };
+
+        info!("Opening transfer connection {} to {}:{}", peer_id, ip, port);
+
+        let receiver = TransferResponseSender {
+            sender: self.client_tx.clone(),
+            peer_id: peer_id,
+        };
+
+        let buckets = match mode {
+            TransferMode::Receive => self.limiter.download_buckets(user_name),
+            TransferMode::Send => self.limiter.upload_buckets(user_name),
+        };
+
+        let transfer_stream = match TransferStream::new((ip, port), mode, receiver, buckets) {
+            Ok(transfer_stream) => transfer_stream,
+
+            Err(err) => return Err(format!("i/o error: {}", err)),
+        };
+
+        event_loop
+            .register(
+                transfer_stream.evented(),
+                mio::Token(peer_id),
+                mio::Ready::all(),
+                mio::PollOpt::edge() | mio::PollOpt::oneshot(),
+            )
+            .unwrap();
+
+        vacant_entry.insert(transfer_stream);
+
+        Ok(())
+    }
+
     fn process_server_intent(
         &mut self,
         intent: Intent,
@@ -212,8 +387,18 @@ impl Handler {
     ) {
         match intent {
             Intent::Done => {
-                error!("Server connection closed");
-                // TODO notify client and shut down
+                error!(
+                    "Server connection closed, reconnecting in {} seconds",
+                    config::SERVER_RECONNECT_DELAY_SECS
+                );
+                if let Err(err) = event_loop.deregister(self.server_stream.evented()) {
+                    error!("Cannot deregister server connection: {}", err);
+                }
+                self.is_server_connected = false;
+                self.client_tx
+                    .send(Response::ServerConnectionClosed)
+                    .unwrap();
+                schedule_server_reconnect(event_loop);
             }
             Intent::Continue(event_set) => {
                 event_loop
@@ -256,10 +441,76 @@ impl Handler {
             }
         }
     }
+
+    fn process_transfer_intent(
+        &mut self,
+        intent: Intent,
+        token: mio::Token,
+        event_loop: &mut mio::deprecated::EventLoop<Self>,
+    ) {
+        match intent {
+            Intent::Done => {
+                self.transfer_streams.remove(token.0);
+                self.throttled_transfers
+                    .retain(|&peer_id| peer_id != token.0);
+                self.client_tx
+                    .send(Response::PeerConnectionClosed(token.0))
+                    .unwrap();
+            }
+
+            Intent::Continue(event_set) => {
+                let is_throttled = match self.transfer_streams.get(token.0) {
+                    Some(transfer_stream) => transfer_stream.is_throttled(),
+                    None => return,
+                };
+                if is_throttled {
+                    self.throttle_transfer(token.0, event_loop);
+                    return;
+                }
+                if let Some(transfer_stream) = self.transfer_streams.get_mut(token.0) {
+                    event_loop
+                        .reregister(
+                            transfer_stream.evented(),
+                            token,
+                            event_set,
+                            mio::PollOpt::edge() | mio::PollOpt::oneshot(),
+                        )
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    /// Leaves the given throttled transfer stream unregistered until
+    /// transfers are resumed a little later.
+    fn throttle_transfer(
+        &mut self,
+        peer_id: usize,
+        event_loop: &mut mio::deprecated::EventLoop<Self>,
+    ) {
+        if self.throttled_transfers.is_empty() {
+            let delay = time::Duration::from_millis(config::THROTTLE_DELAY_MS);
+            if let Err(err) = event_loop.timeout(Timeout::Unthrottle, delay) {
+                error!("Cannot schedule unthrottling: {:?}", err);
+            }
+        }
+        self.throttled_transfers.push(peer_id);
+    }
+
+    fn unthrottle_transfers(&mut self, event_loop: &mut mio::deprecated::EventLoop<Self>) {
+        let peer_ids = mem::replace(&mut self.throttled_transfers, Vec::new());
+        for peer_id in peer_ids {
+            let intent = match self.transfer_streams.get_mut(peer_id) {
+                Some(transfer_stream) => transfer_stream.on_unthrottle(),
+                None => continue,
+            };
+            self.process_transfer_intent(intent, mio::Token(peer_id), event_loop);
+        }
+    }
 }
 
 impl mio::deprecated::Handler for Handler {
-    type Timeout = ();
+    type Timeout = Timeout;
     type Message = Request;
 
     fn ready(
@@ -299,16 +550,52 @@ impl mio::deprecated::Handler for Handler {
             }
 
             mio::Token(peer_id) => {
-                let intent = match self.peer_streams.get_mut(peer_id) {
-                    Some(peer_stream) => peer_stream.on_ready(event_set),
+                let peer_intent = self
+                    .peer_streams
+                    .get_mut(peer_id)
+                    .map(|peer_stream| peer_stream.on_ready(event_set));
+                if let Some(intent) = peer_intent {
+                    self.process_peer_intent(intent, token, event_loop);
+                    return;
+                }
+
+                let intent = match self.transfer_streams.get_mut(peer_id) {
+                    Some(transfer_stream) => transfer_stream.on_ready(event_set),
 
                     None => unreachable!("Unknown peer {} is ready", peer_id),
                 };
-                self.process_peer_intent(intent, token, event_loop);
+                self.process_transfer_intent(intent, token, event_loop);
             }
         }
     }
 
+    fn timeout(&mut self, event_loop: &mut mio::deprecated::EventLoop<Self>, timeout: Timeout) {
+        match timeout {
+            Timeout::Tick => {
+                self.client_tx.send(Response::Tick).unwrap();
+                let throughput = self.limiter.measure();
+                self.client_tx
+                    .send(Response::Throughput(throughput))
+                    .unwrap();
+                schedule_tick(event_loop);
+            }
+
+            Timeout::Unthrottle => self.unthrottle_transfers(event_loop),
+
+            Timeout::ServerReconnect => match connect_to_server(&self.client_tx, event_loop) {
+                Ok(server_stream) => {
+                    self.server_stream = server_stream;
+                    self.is_server_connected = true;
+                    self.client_tx.send(Response::ServerConnectionOpen).unwrap();
+                }
+                Err(err) => {
+                    error!("Cannot connect to server: {}", err);
+                    schedule_server_reconnect(event_loop);
+                }
+            },
+        }
+    }
+
     fn notify(&mut self, event_loop: &mut mio::deprecated::EventLoop<Self>, request: Request) {
         match request {
             Request::PeerConnect(peer_id, ip, port) => {
@@ -324,6 +611,15 @@ impl mio::deprecated::Handler for Handler {
             }
 
             Request::PeerMessage(peer_id, message) => {
+                let transfer_intent = self
+                    .transfer_streams
+                    .get_mut(peer_id)
+                    .map(|transfer_stream| transfer_stream.on_notify(&message));
+                if let Some(intent) = transfer_intent {
+                    self.process_transfer_intent(intent, mio::Token(peer_id), event_loop);
+                    return;
+                }
+
                 let intent = match self.peer_streams.get_mut(peer_id) {
                     Some(peer_stream) => peer_stream.on_notify(&message),
                     None => {
@@ -338,9 +634,62 @@ impl mio::deprecated::Handler for Handler {
             }
 
             Request::ServerRequest(server_request) => {
+                if !self.is_server_connected {
+                    debug!(
+                        "Dropping server request while disconnected: {:?}",
+                        server_request
+                    );
+                    return;
+                }
                 let intent = self.server_stream.on_notify(&server_request);
                 self.process_server_intent(intent, event_loop);
             }
+
+            Request::SetBandwidthLimits(limits) => {
+                info!("Setting bandwidth limits: {:?}", limits);
+                self.limiter.set_limits(limits);
+            }
+
+            Request::TransferConnect(peer_id, user_name, mode, ip, port) => {
+                let result = self.connect_transfer(peer_id, &user_name, mode, ip, port, event_loop);
+                if let Err(err) = result {
+                    error!(
+                        "Cannot open transfer connection {} to {}:{}: {}",
+                        peer_id, ip, port, err
+                    );
+                    self.client_tx
+                        .send(Response::PeerConnectionClosed(peer_id))
+                        .unwrap();
+                }
+            }
+
+            Request::TransferReceive(peer_id, file, offset, size) => {
+                let intent = match self.transfer_streams.get_mut(peer_id) {
+                    Some(transfer_stream) => transfer_stream.on_receive(file, offset, size),
+                    None => {
+                        error!("Cannot receive file: unknown transfer id {}", peer_id);
+                        return;
+                    }
+                };
+                self.process_transfer_intent(intent, mio::Token(peer_id), event_loop);
+            }
+
+            Request::TransferSend(peer_id, file, token, size) => {
+                let intent = match self.transfer_streams.get_mut(peer_id) {
+                    Some(transfer_stream) => transfer_stream.on_send(file, token, size),
+                    None => {
+                        error!("Cannot send file: unknown transfer id {}", peer_id);
+                        return;
+                    }
+                };
+                self.process_transfer_intent(intent, mio::Token(peer_id), event_loop);
+            }
+
+            Request::TransferClose(peer_id) => {
+                if self.transfer_streams.contains(peer_id) {
+                    self.process_transfer_intent(Intent::Done, mio::Token(peer_id), event_loop);
+                }
+            }
         }
     }
 }
@@ -0,0 +1,385 @@
+use std::cmp;
+use std::collections;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use config;
+
+/*==================*
+ * BANDWIDTH LIMITS *
+ *==================*/
+
+/// The maximum rates at which files are transferred, in bytes per second.
+/// No limit applies where a rate is None.
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct BandwidthLimits {
+    /// The maximum total download rate.
+    pub download: Option<u64>,
+    /// The maximum total upload rate.
+    pub upload: Option<u64>,
+    /// The maximum download rate from any single user.
+    pub user_download: Option<u64>,
+    /// The maximum upload rate to any single user.
+    pub user_upload: Option<u64>,
+}
+
+impl BandwidthLimits {
+    /// Returns the limits set in the configuration.
+    pub fn from_config() -> Self {
+        BandwidthLimits {
+            download: config::DOWNLOAD_RATE_LIMIT,
+            upload: config::UPLOAD_RATE_LIMIT,
+            user_download: config::USER_DOWNLOAD_RATE_LIMIT,
+            user_upload: config::USER_UPLOAD_RATE_LIMIT,
+        }
+    }
+
+    /// Returns true if any of the rates is zero, which would stall the
+    /// transfers it applies to forever.
+    pub fn has_zero_rate(&self) -> bool {
+        [
+            self.download,
+            self.upload,
+            self.user_download,
+            self.user_upload,
+        ]
+        .iter()
+        .any(|&rate| rate == Some(0))
+    }
+}
+
+/// The rates at which files were actually transferred recently, in bytes per
+/// second.
+#[derive(Clone, Debug, Default, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Throughput {
+    pub download: u64,
+    pub upload: u64,
+    /// The rates for each user with whom files are being transferred.
+    pub users: Vec<UserThroughput>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct UserThroughput {
+    pub user_name: String,
+    pub download: u64,
+    pub upload: u64,
+}
+
+/*==============*
+ * TOKEN BUCKET *
+ *==============*/
+
+/// Returns the given duration in milliseconds.
+fn as_millis(duration: time::Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// A token bucket, holding at most a second's worth of bytes at the given
+/// rate. Also counts the bytes that go through it, to measure throughput.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Option<u64>,
+    tokens: u64,
+    last_refill: time::Instant,
+    bytes_counted: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: Option<u64>) -> Self {
+        TokenBucket {
+            rate: rate,
+            tokens: rate.unwrap_or(0),
+            last_refill: time::Instant::now(),
+            bytes_counted: 0,
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        self.refill();
+        self.rate = rate;
+        if let Some(rate) = rate {
+            self.tokens = cmp::min(self.tokens, rate);
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = time::Instant::now();
+        if let Some(rate) = self.rate {
+            let millis = as_millis(now.duration_since(self.last_refill));
+            let new_tokens = rate.saturating_mul(millis) / 1000;
+            if new_tokens == 0 {
+                // Keep accumulating time until it is worth at least a token.
+                return;
+            }
+            self.tokens = cmp::min(self.tokens.saturating_add(new_tokens), rate);
+        }
+        self.last_refill = now;
+    }
+
+    /// Returns the number of bytes that can go through right now, or None if
+    /// there is no limit.
+    fn available(&mut self) -> Option<u64> {
+        self.refill();
+        self.rate.map(|_| self.tokens)
+    }
+
+    fn consume(&mut self, num_bytes: u64) {
+        self.tokens = self.tokens.saturating_sub(num_bytes);
+        self.bytes_counted += num_bytes;
+    }
+
+    /// Returns the number of bytes counted since the last call.
+    fn take_bytes_counted(&mut self) -> u64 {
+        let bytes_counted = self.bytes_counted;
+        self.bytes_counted = 0;
+        bytes_counted
+    }
+}
+
+/*=========*
+ * BUCKETS *
+ *=========*/
+
+/// The token buckets that all apply to a given transfer.
+#[derive(Debug)]
+pub struct Buckets(Vec<Arc<Mutex<TokenBucket>>>);
+
+impl Buckets {
+    /// Returns how many bytes, at most the given number, can be transferred
+    /// right now.
+    pub fn allowance(&self, max: usize) -> usize {
+        let mut allowance = max as u64;
+        for bucket in self.0.iter() {
+            if let Some(available) = bucket.lock().unwrap().available() {
+                allowance = cmp::min(allowance, available);
+            }
+        }
+        allowance as usize
+    }
+
+    /// Records that the given number of bytes was transferred.
+    pub fn consume(&self, num_bytes: usize) {
+        for bucket in self.0.iter() {
+            bucket.lock().unwrap().consume(num_bytes as u64);
+        }
+    }
+}
+
+/*=========*
+ * LIMITER *
+ *=========*/
+
+#[derive(Debug)]
+struct UserBuckets {
+    download: Arc<Mutex<TokenBucket>>,
+    upload: Arc<Mutex<TokenBucket>>,
+}
+
+/// Hands out the token buckets that limit each transfer, and measures the
+/// resulting throughput.
+#[derive(Debug)]
+pub struct Limiter {
+    limits: BandwidthLimits,
+    download: Arc<Mutex<TokenBucket>>,
+    upload: Arc<Mutex<TokenBucket>>,
+    users: collections::HashMap<String, UserBuckets>,
+    last_measure: time::Instant,
+}
+
+impl Limiter {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Limiter {
+            download: Arc::new(Mutex::new(TokenBucket::new(limits.download))),
+            upload: Arc::new(Mutex::new(TokenBucket::new(limits.upload))),
+            users: collections::HashMap::new(),
+            last_measure: time::Instant::now(),
+            limits: limits,
+        }
+    }
+
+    /// Applies the given limits, including to ongoing transfers.
+    pub fn set_limits(&mut self, limits: BandwidthLimits) {
+        self.download.lock().unwrap().set_rate(limits.download);
+        self.upload.lock().unwrap().set_rate(limits.upload);
+        for user_buckets in self.users.values() {
+            user_buckets
+                .download
+                .lock()
+                .unwrap()
+                .set_rate(limits.user_download);
+            user_buckets
+                .upload
+                .lock()
+                .unwrap()
+                .set_rate(limits.user_upload);
+        }
+        self.limits = limits;
+    }
+
+    fn user_buckets(&mut self, user_name: &str) -> &UserBuckets {
+        let limits = &self.limits;
+        self.users
+            .entry(user_name.to_string())
+            .or_insert_with(|| UserBuckets {
+                download: Arc::new(Mutex::new(TokenBucket::new(limits.user_download))),
+                upload: Arc::new(Mutex::new(TokenBucket::new(limits.user_upload))),
+            })
+    }
+
+    /// Returns the buckets limiting downloads from the given user.
+    pub fn download_buckets(&mut self, user_name: &str) -> Buckets {
+        let user_bucket = self.user_buckets(user_name).download.clone();
+        Buckets(vec![self.download.clone(), user_bucket])
+    }
+
+    /// Returns the buckets limiting uploads to the given user.
+    pub fn upload_buckets(&mut self, user_name: &str) -> Buckets {
+        let user_bucket = self.user_buckets(user_name).upload.clone();
+        Buckets(vec![self.upload.clone(), user_bucket])
+    }
+
+    /// Returns the throughput since the last call.
+    pub fn measure(&mut self) -> Throughput {
+        let now = time::Instant::now();
+        let millis = cmp::max(as_millis(now.duration_since(self.last_measure)), 1);
+        self.last_measure = now;
+        let rate = |num_bytes: u64| num_bytes * 1000 / millis;
+
+        let mut users = Vec::new();
+        for (user_name, user_buckets) in self.users.iter() {
+            let download = rate(user_buckets.download.lock().unwrap().take_bytes_counted());
+            let upload = rate(user_buckets.upload.lock().unwrap().take_bytes_counted());
+            if download > 0 || upload > 0 {
+                users.push(UserThroughput {
+                    user_name: user_name.clone(),
+                    download: download,
+                    upload: upload,
+                });
+            }
+        }
+
+        // Forget about users with whom no transfers remain.
+        self.users.retain(|_, user_buckets| {
+            Arc::strong_count(&user_buckets.download) > 1
+                || Arc::strong_count(&user_buckets.upload) > 1
+        });
+
+        Throughput {
+            download: rate(self.download.lock().unwrap().take_bytes_counted()),
+            upload: rate(self.upload.lock().unwrap().take_bytes_counted()),
+            users: users,
+        }
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{BandwidthLimits, Limiter, TokenBucket};
+
+    fn limits(user_download: Option<u64>) -> BandwidthLimits {
+        BandwidthLimits {
+            download: None,
+            upload: None,
+            user_download: user_download,
+            user_upload: None,
+        }
+    }
+
+    fn ago(millis: u64) -> time::Instant {
+        time::Instant::now() - time::Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn bucket_unlimited() {
+        let mut bucket = TokenBucket::new(None);
+        bucket.consume(1 << 20);
+        assert_eq!(bucket.available(), None);
+    }
+
+    #[test]
+    fn bucket_refill() {
+        let mut bucket = TokenBucket::new(Some(1000));
+        bucket.consume(1000);
+        bucket.last_refill = ago(500);
+        let available = bucket.available().unwrap();
+        assert!(available >= 500 && available < 600, "{}", available);
+    }
+
+    #[test]
+    fn bucket_refill_capped_at_one_second() {
+        let mut bucket = TokenBucket::new(Some(1000));
+        bucket.consume(1000);
+        bucket.last_refill = ago(5000);
+        assert_eq!(bucket.available(), Some(1000));
+    }
+
+    #[test]
+    fn bucket_set_rate() {
+        let mut bucket = TokenBucket::new(Some(1000));
+        bucket.set_rate(Some(100));
+        assert_eq!(bucket.available(), Some(100));
+        bucket.set_rate(None);
+        assert_eq!(bucket.available(), None);
+    }
+
+    #[test]
+    fn limiter_set_limits_applies_to_live_buckets() {
+        let mut limiter = Limiter::new(limits(Some(1000)));
+        let buckets = limiter.download_buckets("alice");
+        assert_eq!(buckets.allowance(1 << 20), 1000);
+
+        limiter.set_limits(limits(Some(10)));
+        assert_eq!(buckets.allowance(1 << 20), 10);
+
+        limiter.set_limits(limits(None));
+        assert_eq!(buckets.allowance(1 << 20), 1 << 20);
+    }
+
+    #[test]
+    fn limiter_measure() {
+        let mut limiter = Limiter::new(limits(None));
+        let buckets = limiter.download_buckets("alice");
+        buckets.consume(1000);
+        limiter.last_measure = ago(1000);
+
+        let throughput = limiter.measure();
+        assert!(throughput.download > 900 && throughput.download <= 1000);
+        assert_eq!(throughput.upload, 0);
+        assert_eq!(throughput.users.len(), 1);
+        assert_eq!(throughput.users[0].user_name, "alice");
+        assert_eq!(throughput.users[0].download, throughput.download);
+
+        // Bytes are only counted once.
+        let throughput = limiter.measure();
+        assert_eq!(throughput.download, 0);
+        assert!(throughput.users.is_empty());
+    }
+
+    #[test]
+    fn limiter_measure_prunes_idle_users() {
+        let mut limiter = Limiter::new(limits(None));
+        let buckets = limiter.download_buckets("alice");
+        limiter.upload_buckets("bob");
+
+        limiter.measure();
+        assert!(limiter.users.contains_key("alice"));
+        assert!(!limiter.users.contains_key("bob"));
+
+        drop(buckets);
+        limiter.measure();
+        assert!(limiter.users.is_empty());
+    }
+
+    #[test]
+    fn limits_has_zero_rate() {
+        assert!(!limits(None).has_zero_rate());
+        assert!(!limits(Some(1)).has_zero_rate());
+        assert!(limits(Some(0)).has_zero_rate());
+    }
+}
@@ -1,16 +1,20 @@
 mod codec;
 mod constants;
 mod handler;
+mod limiter;
 mod packet;
 pub mod peer;
 pub mod server;
 mod stream;
+mod transfer;
 mod transport;
 mod user;
 
-pub use self::codec::{Decode, ProtoEncode, ProtoEncoder};
+pub use self::codec::{decode_compressed, Decode, ProtoEncode, ProtoEncoder};
 pub use self::handler::*;
+pub use self::limiter::{BandwidthLimits, Throughput, UserThroughput};
 pub use self::packet::*;
 pub use self::server::{ServerRequest, ServerResponse};
 pub use self::stream::*;
+pub use self::transfer::*;
 pub use self::user::{User, UserStatus};
@@ -8,6 +8,9 @@ use std::net;
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use encoding::all::ISO_8859_1;
 use encoding::{DecoderTrap, EncoderTrap, Encoding};
+use flate2;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
 use mio::deprecated::TryRead;
 
 use super::constants::*;
@@ -58,6 +61,30 @@ impl Packet {
     pub fn bytes_remaining(&self) -> usize {
         self.bytes.len() - self.cursor
     }
+
+    /// Decompresses the unread bytes of the packet, which must be
+    /// zlib-compressed, and returns them as a new packet.
+    /// Fails if they take up more than `MAX_DECOMPRESSED_SIZE` bytes once
+    /// decompressed.
+    pub fn decompress(&mut self) -> Result<Packet, PacketReadError> {
+        let mut bytes = Vec::new();
+        {
+            let decoder = ZlibDecoder::new(&self.bytes[self.cursor..]);
+            try!(decoder
+                .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                .read_to_end(&mut bytes));
+        }
+        if bytes.len() > MAX_DECOMPRESSED_SIZE {
+            return Err(PacketReadError::DecompressedTooLargeError(
+                MAX_DECOMPRESSED_SIZE,
+            ));
+        }
+        self.cursor = self.bytes.len();
+        Ok(Packet {
+            cursor: 0,
+            bytes: bytes,
+        })
+    }
 }
 
 /*===================*
@@ -86,6 +113,20 @@ impl MutPacket {
         val.write_to_packet(self)
     }
 
+    /// Writes the given value to the packet, zlib-compressed.
+    pub fn write_compressed_value<T>(&mut self, val: &T) -> io::Result<()>
+    where
+        T: WriteToPacket,
+    {
+        let mut inner = MutPacket { bytes: Vec::new() };
+        try!(inner.write_value(val));
+
+        let mut encoder = ZlibEncoder::new(&mut self.bytes, flate2::Compression::default());
+        try!(encoder.write_all(&inner.bytes));
+        try!(encoder.finish());
+        Ok(())
+    }
+
     /// Consumes the mutable packet and returns its wire representation.
     pub fn into_bytes(mut self) -> Vec<u8> {
         let length = (self.bytes.len() - U32_SIZE) as u32;
@@ -124,6 +165,12 @@ pub enum PacketReadError {
     /// Attempted to read a user::Status, but the value was not a valid
     /// representation of an enum variant.
     InvalidUserStatusError(u32),
+    /// Attempted to read a peer::TransferDirection, but the value was not a
+    /// valid representation of an enum variant.
+    InvalidTransferDirectionError(u32),
+    /// Attempted to decompress a packet, but it would take up more than the
+    /// given number of bytes.
+    DecompressedTooLargeError(usize),
     /// Encountered an I/O error while reading.
     IOError(io::Error),
 }
@@ -139,6 +186,12 @@ impl fmt::Display for PacketReadError {
             PacketReadError::InvalidUserStatusError(n) => {
                 write!(fmt, "InvalidUserStatusError: {}", n)
             }
+            PacketReadError::InvalidTransferDirectionError(n) => {
+                write!(fmt, "InvalidTransferDirectionError: {}", n)
+            }
+            PacketReadError::DecompressedTooLargeError(n) => {
+                write!(fmt, "DecompressedTooLargeError: {}", n)
+            }
             PacketReadError::IOError(ref err) => write!(fmt, "IOError: {}", err),
         }
     }
@@ -151,6 +204,8 @@ impl error::Error for PacketReadError {
             PacketReadError::InvalidU16Error(_) => "InvalidU16Error",
             PacketReadError::InvalidStringError(_) => "InvalidStringError",
             PacketReadError::InvalidUserStatusError(_) => "InvalidUserStatusError",
+            PacketReadError::InvalidTransferDirectionError(_) => "InvalidTransferDirectionError",
+            PacketReadError::DecompressedTooLargeError(_) => "DecompressedTooLargeError",
             PacketReadError::IOError(_) => "IOError",
         }
     }
@@ -161,6 +216,8 @@ impl error::Error for PacketReadError {
             PacketReadError::InvalidU16Error(_) => None,
             PacketReadError::InvalidStringError(_) => None,
             PacketReadError::InvalidUserStatusError(_) => None,
+            PacketReadError::InvalidTransferDirectionError(_) => None,
+            PacketReadError::DecompressedTooLargeError(_) => None,
             PacketReadError::IOError(ref err) => Some(err),
         }
     }
@@ -189,6 +246,20 @@ impl ReadFromPacket for u32 {
     }
 }
 
+/// 8-bit integers are serialized in a single byte.
+impl ReadFromPacket for u8 {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        Ok(packet.read_u8()?)
+    }
+}
+
+/// 64-bit integers are serialized in 8 bytes, little-endian.
+impl ReadFromPacket for u64 {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        Ok(packet.read_u64::<LittleEndian>()?)
+    }
+}
+
 /// For convenience, usize's are deserialized as u32's then casted.
 impl ReadFromPacket for usize {
     fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
@@ -256,6 +327,15 @@ impl<T: ReadFromPacket> ReadFromPacket for Vec<T> {
     }
 }
 
+/// Pairs are serialized as two consecutive values.
+impl<T: ReadFromPacket, U: ReadFromPacket> ReadFromPacket for (T, U) {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let first = try!(T::read_from_packet(packet));
+        let second = try!(U::read_from_packet(packet));
+        Ok((first, second))
+    }
+}
+
 /*=================*
  * WRITE TO PACKET *
  *=================*/
@@ -273,6 +353,20 @@ impl WriteToPacket for u32 {
     }
 }
 
+/// 8-bit integers are serialized in a single byte.
+impl WriteToPacket for u8 {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        packet.write_u8(*self)
+    }
+}
+
+/// 64-bit integers are serialized in 8 bytes, little-endian.
+impl WriteToPacket for u64 {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        packet.write_u64::<LittleEndian>(*self)
+    }
+}
+
 /// Booleans are serialized as single bytes, containing either 0 or 1.
 impl WriteToPacket for bool {
     fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
@@ -314,6 +408,25 @@ impl WriteToPacket for String {
     }
 }
 
+/// Vectors are serialized as length-prefixed arrays of values.
+impl<T: WriteToPacket> WriteToPacket for Vec<T> {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!((self.len() as u32).write_to_packet(packet));
+        for item in self {
+            try!(item.write_to_packet(packet));
+        }
+        Ok(())
+    }
+}
+
+/// Pairs are serialized as two consecutive values.
+impl<T: WriteToPacket, U: WriteToPacket> WriteToPacket for (T, U) {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(self.0.write_to_packet(packet));
+        self.1.write_to_packet(packet)
+    }
+}
+
 /*========*
  * PARSER *
  *========*/
@@ -1,2 +1,14 @@
 pub const CODE_PIERCE_FIREWALL: u32 = 0;
 pub const CODE_PEER_INIT: u32 = 1;
+pub const CODE_SHARED_FILE_LIST_REQUEST: u32 = 4;
+pub const CODE_SHARED_FILE_LIST_RESPONSE: u32 = 5;
+pub const CODE_FILE_SEARCH_RESULT: u32 = 9;
+pub const CODE_FOLDER_CONTENTS_REQUEST: u32 = 36;
+pub const CODE_FOLDER_CONTENTS_RESPONSE: u32 = 37;
+pub const CODE_TRANSFER_REQUEST: u32 = 40;
+pub const CODE_TRANSFER_RESPONSE: u32 = 41;
+pub const CODE_QUEUE_UPLOAD: u32 = 43;
+pub const CODE_PLACE_IN_QUEUE_RESPONSE: u32 = 44;
+pub const CODE_UPLOAD_FAILED: u32 = 46;
+pub const CODE_UPLOAD_DENIED: u32 = 50;
+pub const CODE_PLACE_IN_QUEUE_REQUEST: u32 = 51;
@@ -4,8 +4,8 @@ use bytes;
 
 use proto::peer::constants::*;
 use proto::{
-    Decode, MutPacket, Packet, PacketReadError, ProtoEncode, ProtoEncoder, ReadFromPacket,
-    WriteToPacket,
+    decode_compressed, Decode, MutPacket, Packet, PacketReadError, ProtoEncode, ProtoEncoder,
+    ReadFromPacket, WriteToPacket,
 };
 
 /*=========*
@@ -15,8 +15,20 @@ use proto::{
 /// This enum contains all the possible messages peers can exchange.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Message {
+    FileSearchResult(FileSearchResult),
+    FolderContentsRequest(FolderContentsRequest),
+    FolderContentsResponse(FolderContentsResponse),
     PierceFirewall(u32),
     PeerInit(PeerInit),
+    PlaceInQueueRequest(PlaceInQueueRequest),
+    PlaceInQueueResponse(PlaceInQueueResponse),
+    QueueUpload(QueueUpload),
+    SharedFileListRequest,
+    SharedFileListResponse(SharedFileListResponse),
+    TransferRequest(TransferRequest),
+    TransferResponse(TransferResponse),
+    UploadDenied(UploadDenied),
+    UploadFailed(UploadFailed),
     Unknown(u32),
 }
 
@@ -24,10 +36,44 @@ impl ReadFromPacket for Message {
     fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
         let code: u32 = try!(packet.read_value());
         let message = match code {
+            CODE_FILE_SEARCH_RESULT => {
+                Message::FileSearchResult(try!(try!(packet.decompress()).read_value()))
+            }
+
+            CODE_FOLDER_CONTENTS_REQUEST => {
+                Message::FolderContentsRequest(try!(packet.read_value()))
+            }
+
+            CODE_FOLDER_CONTENTS_RESPONSE => {
+                Message::FolderContentsResponse(try!(try!(packet.decompress()).read_value()))
+            }
+
             CODE_PIERCE_FIREWALL => Message::PierceFirewall(try!(packet.read_value())),
 
             CODE_PEER_INIT => Message::PeerInit(try!(packet.read_value())),
 
+            CODE_QUEUE_UPLOAD => Message::QueueUpload(try!(packet.read_value())),
+
+            CODE_SHARED_FILE_LIST_REQUEST => Message::SharedFileListRequest,
+
+            CODE_SHARED_FILE_LIST_RESPONSE => {
+                Message::SharedFileListResponse(try!(try!(packet.decompress()).read_value()))
+            }
+
+            CODE_TRANSFER_REQUEST => Message::TransferRequest(try!(packet.read_value())),
+
+            CODE_TRANSFER_RESPONSE => Message::TransferResponse(try!(packet.read_value())),
+
+            CODE_PLACE_IN_QUEUE_REQUEST => Message::PlaceInQueueRequest(try!(packet.read_value())),
+
+            CODE_PLACE_IN_QUEUE_RESPONSE => {
+                Message::PlaceInQueueResponse(try!(packet.read_value()))
+            }
+
+            CODE_UPLOAD_DENIED => Message::UploadDenied(try!(packet.read_value())),
+
+            CODE_UPLOAD_FAILED => Message::UploadFailed(try!(packet.read_value())),
+
             code => Message::Unknown(code),
         };
 
@@ -47,6 +93,18 @@ impl<T: bytes::Buf> Decode<Message> for T {
     fn decode(&mut self) -> io::Result<Message> {
         let code: u32 = self.decode()?;
         let message = match code {
+            CODE_FILE_SEARCH_RESULT => {
+                let result = decode_compressed(self)?;
+                Message::FileSearchResult(result)
+            }
+            CODE_FOLDER_CONTENTS_REQUEST => {
+                let request = self.decode()?;
+                Message::FolderContentsRequest(request)
+            }
+            CODE_FOLDER_CONTENTS_RESPONSE => {
+                let response = decode_compressed(self)?;
+                Message::FolderContentsResponse(response)
+            }
             CODE_PIERCE_FIREWALL => {
                 let val = self.decode()?;
                 Message::PierceFirewall(val)
@@ -55,6 +113,39 @@ impl<T: bytes::Buf> Decode<Message> for T {
                 let peer_init = self.decode()?;
                 Message::PeerInit(peer_init)
             }
+            CODE_QUEUE_UPLOAD => {
+                let queue_upload = self.decode()?;
+                Message::QueueUpload(queue_upload)
+            }
+            CODE_SHARED_FILE_LIST_REQUEST => Message::SharedFileListRequest,
+            CODE_SHARED_FILE_LIST_RESPONSE => {
+                let response = decode_compressed(self)?;
+                Message::SharedFileListResponse(response)
+            }
+            CODE_TRANSFER_REQUEST => {
+                let transfer_request = self.decode()?;
+                Message::TransferRequest(transfer_request)
+            }
+            CODE_TRANSFER_RESPONSE => {
+                let transfer_response = self.decode()?;
+                Message::TransferResponse(transfer_response)
+            }
+            CODE_PLACE_IN_QUEUE_REQUEST => {
+                let place_in_queue_request = self.decode()?;
+                Message::PlaceInQueueRequest(place_in_queue_request)
+            }
+            CODE_PLACE_IN_QUEUE_RESPONSE => {
+                let place_in_queue_response = self.decode()?;
+                Message::PlaceInQueueResponse(place_in_queue_response)
+            }
+            CODE_UPLOAD_DENIED => {
+                let upload_denied = self.decode()?;
+                Message::UploadDenied(upload_denied)
+            }
+            CODE_UPLOAD_FAILED => {
+                let upload_failed = self.decode()?;
+                Message::UploadFailed(upload_failed)
+            }
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -69,6 +160,18 @@ impl<T: bytes::Buf> Decode<Message> for T {
 impl ProtoEncode for Message {
     fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
         match *self {
+            Message::FileSearchResult(ref result) => {
+                encoder.encode_u32(CODE_FILE_SEARCH_RESULT)?;
+                encoder.encode_compressed(result)?;
+            }
+            Message::FolderContentsRequest(ref request) => {
+                encoder.encode_u32(CODE_FOLDER_CONTENTS_REQUEST)?;
+                request.encode(encoder)?;
+            }
+            Message::FolderContentsResponse(ref response) => {
+                encoder.encode_u32(CODE_FOLDER_CONTENTS_RESPONSE)?;
+                encoder.encode_compressed(response)?;
+            }
             Message::PierceFirewall(token) => {
                 encoder.encode_u32(CODE_PIERCE_FIREWALL)?;
                 encoder.encode_u32(token)?;
@@ -77,6 +180,41 @@ impl ProtoEncode for Message {
                 encoder.encode_u32(CODE_PEER_INIT)?;
                 request.encode(encoder)?;
             }
+            Message::QueueUpload(ref request) => {
+                encoder.encode_u32(CODE_QUEUE_UPLOAD)?;
+                request.encode(encoder)?;
+            }
+            Message::SharedFileListRequest => {
+                encoder.encode_u32(CODE_SHARED_FILE_LIST_REQUEST)?;
+            }
+            Message::SharedFileListResponse(ref response) => {
+                encoder.encode_u32(CODE_SHARED_FILE_LIST_RESPONSE)?;
+                encoder.encode_compressed(response)?;
+            }
+            Message::TransferRequest(ref request) => {
+                encoder.encode_u32(CODE_TRANSFER_REQUEST)?;
+                request.encode(encoder)?;
+            }
+            Message::TransferResponse(ref response) => {
+                encoder.encode_u32(CODE_TRANSFER_RESPONSE)?;
+                response.encode(encoder)?;
+            }
+            Message::PlaceInQueueRequest(ref request) => {
+                encoder.encode_u32(CODE_PLACE_IN_QUEUE_REQUEST)?;
+                request.encode(encoder)?;
+            }
+            Message::PlaceInQueueResponse(ref response) => {
+                encoder.encode_u32(CODE_PLACE_IN_QUEUE_RESPONSE)?;
+                response.encode(encoder)?;
+            }
+            Message::UploadDenied(ref request) => {
+                encoder.encode_u32(CODE_UPLOAD_DENIED)?;
+                request.encode(encoder)?;
+            }
+            Message::UploadFailed(ref request) => {
+                encoder.encode_u32(CODE_UPLOAD_FAILED)?;
+                request.encode(encoder)?;
+            }
             Message::Unknown(_) => unreachable!(),
         }
         Ok(())
@@ -86,6 +224,21 @@ impl ProtoEncode for Message {
 impl WriteToPacket for Message {
     fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
         match *self {
+            Message::FileSearchResult(ref result) => {
+                try!(packet.write_value(&CODE_FILE_SEARCH_RESULT));
+                try!(packet.write_compressed_value(result));
+            }
+
+            Message::FolderContentsRequest(ref request) => {
+                try!(packet.write_value(&CODE_FOLDER_CONTENTS_REQUEST));
+                try!(packet.write_value(request));
+            }
+
+            Message::FolderContentsResponse(ref response) => {
+                try!(packet.write_value(&CODE_FOLDER_CONTENTS_RESPONSE));
+                try!(packet.write_compressed_value(response));
+            }
+
             Message::PierceFirewall(ref token) => {
                 try!(packet.write_value(&CODE_PIERCE_FIREWALL));
                 try!(packet.write_value(token));
@@ -96,6 +249,50 @@ impl WriteToPacket for Message {
                 try!(packet.write_value(request));
             }
 
+            Message::QueueUpload(ref request) => {
+                try!(packet.write_value(&CODE_QUEUE_UPLOAD));
+                try!(packet.write_value(request));
+            }
+
+            Message::SharedFileListRequest => {
+                try!(packet.write_value(&CODE_SHARED_FILE_LIST_REQUEST));
+            }
+
+            Message::SharedFileListResponse(ref response) => {
+                try!(packet.write_value(&CODE_SHARED_FILE_LIST_RESPONSE));
+                try!(packet.write_compressed_value(response));
+            }
+
+            Message::TransferRequest(ref request) => {
+                try!(packet.write_value(&CODE_TRANSFER_REQUEST));
+                try!(packet.write_value(request));
+            }
+
+            Message::TransferResponse(ref response) => {
+                try!(packet.write_value(&CODE_TRANSFER_RESPONSE));
+                try!(packet.write_value(response));
+            }
+
+            Message::PlaceInQueueRequest(ref request) => {
+                try!(packet.write_value(&CODE_PLACE_IN_QUEUE_REQUEST));
+                try!(packet.write_value(request));
+            }
+
+            Message::PlaceInQueueResponse(ref response) => {
+                try!(packet.write_value(&CODE_PLACE_IN_QUEUE_RESPONSE));
+                try!(packet.write_value(response));
+            }
+
+            Message::UploadDenied(ref request) => {
+                try!(packet.write_value(&CODE_UPLOAD_DENIED));
+                try!(packet.write_value(request));
+            }
+
+            Message::UploadFailed(ref request) => {
+                try!(packet.write_value(&CODE_UPLOAD_FAILED));
+                try!(packet.write_value(request));
+            }
+
             Message::Unknown(_) => unreachable!(),
         }
         Ok(())
@@ -153,6 +350,792 @@ impl<T: bytes::Buf> Decode<PeerInit> for T {
     }
 }
 
+/*==============*
+ * QUEUE UPLOAD *
+ *==============*/
+
+/// Sent by a downloader to ask the uploader to queue the given file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueueUpload {
+    pub file_name: String,
+}
+
+impl ReadFromPacket for QueueUpload {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let file_name = try!(packet.read_value());
+        Ok(QueueUpload { file_name })
+    }
+}
+
+impl WriteToPacket for QueueUpload {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.file_name));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for QueueUpload {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.file_name)
+    }
+}
+
+impl<T: bytes::Buf> Decode<QueueUpload> for T {
+    fn decode(&mut self) -> io::Result<QueueUpload> {
+        let file_name = self.decode()?;
+        Ok(QueueUpload { file_name })
+    }
+}
+
+/*======*
+ * FILE *
+ *======*/
+
+/// Describes a shared file, in file listings and search results.
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct File {
+    /// The name of the file. In folder listings, this is relative to the
+    /// folder, otherwise it is the full path of the file.
+    pub name: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The extension of the file, unused by most clients.
+    pub extension: String,
+    /// The attributes of the file, as (type, value) pairs.
+    pub attributes: Vec<(u32, u32)>,
+}
+
+/// Every file entry starts with this code, which carries no information.
+const FILE_CODE: u8 = 1;
+
+// The types of file attributes.
+/// The bitrate in kilobits per second.
+pub const ATTRIBUTE_BITRATE: u32 = 0;
+/// The duration in seconds.
+pub const ATTRIBUTE_DURATION: u32 = 1;
+/// 1 if the bitrate is variable, 0 otherwise.
+pub const ATTRIBUTE_VBR: u32 = 2;
+/// The sample rate in hertz.
+pub const ATTRIBUTE_SAMPLE_RATE: u32 = 4;
+/// The number of bits per sample.
+pub const ATTRIBUTE_BIT_DEPTH: u32 = 5;
+
+impl ReadFromPacket for File {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let _code: u8 = try!(packet.read_value());
+        let name = try!(packet.read_value());
+        let size = try!(packet.read_value());
+        let extension = try!(packet.read_value());
+        let attributes = try!(packet.read_value());
+        Ok(File {
+            name,
+            size,
+            extension,
+            attributes,
+        })
+    }
+}
+
+impl WriteToPacket for File {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&FILE_CODE));
+        try!(packet.write_value(&self.name));
+        try!(packet.write_value(&self.size));
+        try!(packet.write_value(&self.extension));
+        try!(packet.write_value(&self.attributes));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for File {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_u8(FILE_CODE)?;
+        encoder.encode_string(&self.name)?;
+        encoder.encode_u64(self.size)?;
+        encoder.encode_string(&self.extension)?;
+        encoder.encode_vec(&self.attributes)?;
+        Ok(())
+    }
+}
+
+impl<T: bytes::Buf> Decode<File> for T {
+    fn decode(&mut self) -> io::Result<File> {
+        let _code: u8 = self.decode()?;
+        let name = self.decode()?;
+        let size = self.decode()?;
+        let extension = self.decode()?;
+        let attributes = self.decode()?;
+        Ok(File {
+            name,
+            size,
+            extension,
+            attributes,
+        })
+    }
+}
+
+/*========*
+ * FOLDER *
+ *========*/
+
+/// Describes a shared folder and the files it contains directly.
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Folder {
+    /// The full path of the folder.
+    pub name: String,
+    pub files: Vec<File>,
+}
+
+impl ReadFromPacket for Folder {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let name = try!(packet.read_value());
+        let files = try!(packet.read_value());
+        Ok(Folder { name, files })
+    }
+}
+
+impl WriteToPacket for Folder {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.name));
+        try!(packet.write_value(&self.files));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for Folder {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.name)?;
+        encoder.encode_vec(&self.files)?;
+        Ok(())
+    }
+}
+
+impl<T: bytes::Buf> Decode<Folder> for T {
+    fn decode(&mut self) -> io::Result<Folder> {
+        let name = self.decode()?;
+        let files = self.decode()?;
+        Ok(Folder { name, files })
+    }
+}
+
+/*===========================*
+ * SHARED FILE LIST RESPONSE *
+ *===========================*/
+
+/// Sent in response to a shared file list request, which has no contents.
+/// Compressed with zlib.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SharedFileListResponse {
+    /// All the shared folders.
+    pub folders: Vec<Folder>,
+}
+
+impl ReadFromPacket for SharedFileListResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let folders = try!(packet.read_value());
+        Ok(SharedFileListResponse { folders })
+    }
+}
+
+impl WriteToPacket for SharedFileListResponse {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.folders));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for SharedFileListResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_vec(&self.folders)
+    }
+}
+
+impl<T: bytes::Buf> Decode<SharedFileListResponse> for T {
+    fn decode(&mut self) -> io::Result<SharedFileListResponse> {
+        let folders = self.decode()?;
+        Ok(SharedFileListResponse { folders })
+    }
+}
+
+/*====================*
+ * FILE SEARCH RESULT *
+ *====================*/
+
+/// Sent to a user who searched for files, listing the files matching their
+/// query. Compressed with zlib.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileSearchResult {
+    /// The name of the user sending the results.
+    pub user_name: String,
+    /// The ticket of the search.
+    pub ticket: u32,
+    pub files: Vec<File>,
+    /// Whether an upload slot is free right now.
+    pub has_free_slot: bool,
+    /// The average upload speed of the user, in bytes per second.
+    pub average_speed: u32,
+    /// The number of uploads queued by the user.
+    pub queue_length: u32,
+}
+
+impl ReadFromPacket for FileSearchResult {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let user_name = try!(packet.read_value());
+        let ticket = try!(packet.read_value());
+        let files = try!(packet.read_value());
+        let has_free_slot = try!(packet.read_value());
+        let average_speed = try!(packet.read_value());
+        let queue_length = try!(packet.read_value());
+        Ok(FileSearchResult {
+            user_name,
+            ticket,
+            files,
+            has_free_slot,
+            average_speed,
+            queue_length,
+        })
+    }
+}
+
+impl WriteToPacket for FileSearchResult {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.user_name));
+        try!(packet.write_value(&self.ticket));
+        try!(packet.write_value(&self.files));
+        try!(packet.write_value(&self.has_free_slot));
+        try!(packet.write_value(&self.average_speed));
+        try!(packet.write_value(&self.queue_length));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for FileSearchResult {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.user_name)?;
+        encoder.encode_u32(self.ticket)?;
+        encoder.encode_vec(&self.files)?;
+        encoder.encode_bool(self.has_free_slot)?;
+        encoder.encode_u32(self.average_speed)?;
+        encoder.encode_u32(self.queue_length)?;
+        Ok(())
+    }
+}
+
+impl<T: bytes::Buf> Decode<FileSearchResult> for T {
+    fn decode(&mut self) -> io::Result<FileSearchResult> {
+        let user_name = self.decode()?;
+        let ticket = self.decode()?;
+        let files = self.decode()?;
+        let has_free_slot = self.decode()?;
+        let average_speed = self.decode()?;
+        let queue_length = self.decode()?;
+        Ok(FileSearchResult {
+            user_name,
+            ticket,
+            files,
+            has_free_slot,
+            average_speed,
+            queue_length,
+        })
+    }
+}
+
+/*=========================*
+ * FOLDER CONTENTS REQUEST *
+ *=========================*/
+
+/// Sent to ask a peer for the contents of the given shared folder, including
+/// its subfolders.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FolderContentsRequest {
+    pub token: u32,
+    pub folder_name: String,
+}
+
+impl ReadFromPacket for FolderContentsRequest {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let token = try!(packet.read_value());
+        let folder_name = try!(packet.read_value());
+        Ok(FolderContentsRequest { token, folder_name })
+    }
+}
+
+impl WriteToPacket for FolderContentsRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.token));
+        try!(packet.write_value(&self.folder_name));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for FolderContentsRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_u32(self.token)?;
+        encoder.encode_string(&self.folder_name)?;
+        Ok(())
+    }
+}
+
+impl<T: bytes::Buf> Decode<FolderContentsRequest> for T {
+    fn decode(&mut self) -> io::Result<FolderContentsRequest> {
+        let token = self.decode()?;
+        let folder_name = self.decode()?;
+        Ok(FolderContentsRequest { token, folder_name })
+    }
+}
+
+/*==========================*
+ * FOLDER CONTENTS RESPONSE *
+ *==========================*/
+
+/// Sent in response to a folder contents request. Compressed with zlib.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FolderContentsResponse {
+    pub token: u32,
+    pub folder_name: String,
+    /// The requested folder and its subfolders.
+    pub folders: Vec<Folder>,
+}
+
+impl ReadFromPacket for FolderContentsResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let token = try!(packet.read_value());
+        let folder_name = try!(packet.read_value());
+        let folders = try!(packet.read_value());
+        Ok(FolderContentsResponse {
+            token,
+            folder_name,
+            folders,
+        })
+    }
+}
+
+impl WriteToPacket for FolderContentsResponse {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.token));
+        try!(packet.write_value(&self.folder_name));
+        try!(packet.write_value(&self.folders));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for FolderContentsResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_u32(self.token)?;
+        encoder.encode_string(&self.folder_name)?;
+        encoder.encode_vec(&self.folders)?;
+        Ok(())
+    }
+}
+
+impl<T: bytes::Buf> Decode<FolderContentsResponse> for T {
+    fn decode(&mut self) -> io::Result<FolderContentsResponse> {
+        let token = self.decode()?;
+        let folder_name = self.decode()?;
+        let folders = self.decode()?;
+        Ok(FolderContentsResponse {
+            token,
+            folder_name,
+            folders,
+        })
+    }
+}
+
+/*========================*
+ * PLACE IN QUEUE REQUEST *
+ *========================*/
+
+/// Sent by a downloader to ask the uploader where the given file stands in
+/// its upload queue.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PlaceInQueueRequest {
+    pub file_name: String,
+}
+
+impl ReadFromPacket for PlaceInQueueRequest {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let file_name = try!(packet.read_value());
+        Ok(PlaceInQueueRequest { file_name })
+    }
+}
+
+impl WriteToPacket for PlaceInQueueRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.file_name));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for PlaceInQueueRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.file_name)
+    }
+}
+
+impl<T: bytes::Buf> Decode<PlaceInQueueRequest> for T {
+    fn decode(&mut self) -> io::Result<PlaceInQueueRequest> {
+        let file_name = self.decode()?;
+        Ok(PlaceInQueueRequest { file_name })
+    }
+}
+
+/*=========================*
+ * PLACE IN QUEUE RESPONSE *
+ *=========================*/
+
+/// Sent by an uploader to tell the downloader where the given file stands in
+/// its upload queue.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PlaceInQueueResponse {
+    pub file_name: String,
+    /// The position of the file in the queue, starting at 1.
+    pub place: u32,
+}
+
+impl ReadFromPacket for PlaceInQueueResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let file_name = try!(packet.read_value());
+        let place = try!(packet.read_value());
+        Ok(PlaceInQueueResponse { file_name, place })
+    }
+}
+
+impl WriteToPacket for PlaceInQueueResponse {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.file_name));
+        try!(packet.write_value(&self.place));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for PlaceInQueueResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.file_name)?;
+        encoder.encode_u32(self.place)
+    }
+}
+
+impl<T: bytes::Buf> Decode<PlaceInQueueResponse> for T {
+    fn decode(&mut self) -> io::Result<PlaceInQueueResponse> {
+        let file_name = self.decode()?;
+        let place = self.decode()?;
+        Ok(PlaceInQueueResponse { file_name, place })
+    }
+}
+
+/*===============*
+ * UPLOAD DENIED *
+ *===============*/
+
+/// Sent by an uploader to tell the downloader that it will not upload the
+/// given file, for the given reason.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UploadDenied {
+    pub file_name: String,
+    pub reason: String,
+}
+
+impl ReadFromPacket for UploadDenied {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let file_name = try!(packet.read_value());
+        let reason = try!(packet.read_value());
+        Ok(UploadDenied { file_name, reason })
+    }
+}
+
+impl WriteToPacket for UploadDenied {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.file_name));
+        try!(packet.write_value(&self.reason));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for UploadDenied {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.file_name)?;
+        encoder.encode_string(&self.reason)
+    }
+}
+
+impl<T: bytes::Buf> Decode<UploadDenied> for T {
+    fn decode(&mut self) -> io::Result<UploadDenied> {
+        let file_name = self.decode()?;
+        let reason = self.decode()?;
+        Ok(UploadDenied { file_name, reason })
+    }
+}
+
+/*===============*
+ * UPLOAD FAILED *
+ *===============*/
+
+/// Sent by an uploader to tell the downloader that uploading the given file
+/// failed on its end.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UploadFailed {
+    pub file_name: String,
+}
+
+impl ReadFromPacket for UploadFailed {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let file_name = try!(packet.read_value());
+        Ok(UploadFailed { file_name })
+    }
+}
+
+impl WriteToPacket for UploadFailed {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.file_name));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for UploadFailed {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.file_name)
+    }
+}
+
+impl<T: bytes::Buf> Decode<UploadFailed> for T {
+    fn decode(&mut self) -> io::Result<UploadFailed> {
+        let file_name = self.decode()?;
+        Ok(UploadFailed { file_name })
+    }
+}
+
+/*====================*
+ * TRANSFER DIRECTION *
+ *====================*/
+
+/// The direction of a file transfer, from the point of view of the peer
+/// sending the transfer request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferDirection {
+    Download,
+    Upload,
+}
+
+impl TransferDirection {
+    fn to_u32(&self) -> u32 {
+        match *self {
+            TransferDirection::Download => 0,
+            TransferDirection::Upload => 1,
+        }
+    }
+}
+
+impl ReadFromPacket for TransferDirection {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let n: u32 = try!(packet.read_value());
+        match n {
+            0 => Ok(TransferDirection::Download),
+            1 => Ok(TransferDirection::Upload),
+            _ => Err(PacketReadError::InvalidTransferDirectionError(n)),
+        }
+    }
+}
+
+impl WriteToPacket for TransferDirection {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        packet.write_value(&self.to_u32())
+    }
+}
+
+impl ProtoEncode for TransferDirection {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_u32(self.to_u32())
+    }
+}
+
+impl<T: bytes::Buf> Decode<TransferDirection> for T {
+    fn decode(&mut self) -> io::Result<TransferDirection> {
+        let n: u32 = self.decode()?;
+        match n {
+            0 => Ok(TransferDirection::Download),
+            1 => Ok(TransferDirection::Upload),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid transfer direction: {}", n),
+            )),
+        }
+    }
+}
+
+/*==================*
+ * TRANSFER REQUEST *
+ *==================*/
+
+/// Sent by a peer to announce that it wants to start transferring the given
+/// file. The token identifies the transfer on the file connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransferRequest {
+    pub direction: TransferDirection,
+    pub token: u32,
+    pub file_name: String,
+    /// The size of the file, only sent along with upload requests.
+    pub file_size: Option<u64>,
+}
+
+impl ReadFromPacket for TransferRequest {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let direction = try!(packet.read_value());
+        let token = try!(packet.read_value());
+        let file_name = try!(packet.read_value());
+        let file_size = match direction {
+            TransferDirection::Upload => Some(try!(packet.read_value())),
+            TransferDirection::Download => None,
+        };
+        Ok(TransferRequest {
+            direction,
+            token,
+            file_name,
+            file_size,
+        })
+    }
+}
+
+impl WriteToPacket for TransferRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.direction));
+        try!(packet.write_value(&self.token));
+        try!(packet.write_value(&self.file_name));
+        if let Some(file_size) = self.file_size {
+            try!(packet.write_value(&file_size));
+        }
+        Ok(())
+    }
+}
+
+impl ProtoEncode for TransferRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        self.direction.encode(encoder)?;
+        encoder.encode_u32(self.token)?;
+        encoder.encode_string(&self.file_name)?;
+        if let Some(file_size) = self.file_size {
+            encoder.encode_u64(file_size)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: bytes::Buf> Decode<TransferRequest> for T {
+    fn decode(&mut self) -> io::Result<TransferRequest> {
+        let direction = self.decode()?;
+        let token = self.decode()?;
+        let file_name = self.decode()?;
+        let file_size = match direction {
+            TransferDirection::Upload => Some(self.decode()?),
+            TransferDirection::Download => None,
+        };
+        Ok(TransferRequest {
+            direction,
+            token,
+            file_name,
+            file_size,
+        })
+    }
+}
+
+/*===================*
+ * TRANSFER RESPONSE *
+ *===================*/
+
+/// Sent in response to a transfer request, to accept or refuse it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TransferResponse {
+    Allowed {
+        token: u32,
+        /// The size of the file, only sent in response to download requests.
+        file_size: Option<u64>,
+    },
+    Denied {
+        token: u32,
+        reason: String,
+    },
+}
+
+impl ReadFromPacket for TransferResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let token = try!(packet.read_value());
+        let allowed: bool = try!(packet.read_value());
+        if !allowed {
+            let reason = try!(packet.read_value());
+            return Ok(TransferResponse::Denied { token, reason });
+        }
+        let file_size = if packet.bytes_remaining() > 0 {
+            Some(try!(packet.read_value()))
+        } else {
+            None
+        };
+        Ok(TransferResponse::Allowed { token, file_size })
+    }
+}
+
+impl WriteToPacket for TransferResponse {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        match *self {
+            TransferResponse::Allowed {
+                ref token,
+                ref file_size,
+            } => {
+                try!(packet.write_value(token));
+                try!(packet.write_value(&true));
+                if let Some(ref file_size) = *file_size {
+                    try!(packet.write_value(file_size));
+                }
+            }
+
+            TransferResponse::Denied {
+                ref token,
+                ref reason,
+            } => {
+                try!(packet.write_value(token));
+                try!(packet.write_value(&false));
+                try!(packet.write_value(reason));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProtoEncode for TransferResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        match *self {
+            TransferResponse::Allowed { token, file_size } => {
+                encoder.encode_u32(token)?;
+                encoder.encode_bool(true)?;
+                if let Some(file_size) = file_size {
+                    encoder.encode_u64(file_size)?;
+                }
+            }
+            TransferResponse::Denied { token, ref reason } => {
+                encoder.encode_u32(token)?;
+                encoder.encode_bool(false)?;
+                encoder.encode_string(reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: bytes::Buf> Decode<TransferResponse> for T {
+    fn decode(&mut self) -> io::Result<TransferResponse> {
+        let token = self.decode()?;
+        let allowed: bool = self.decode()?;
+        if !allowed {
+            let reason = self.decode()?;
+            return Ok(TransferResponse::Denied { token, reason });
+        }
+        let file_size = if self.has_remaining() {
+            Some(self.decode()?)
+        } else {
+            None
+        };
+        Ok(TransferResponse::Allowed { token, file_size })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io;
@@ -191,4 +1174,145 @@ mod tests {
             token: 1337,
         }));
     }
+
+    #[test]
+    fn roundtrip_queue_upload() {
+        roundtrip(Message::QueueUpload(QueueUpload {
+            file_name: "music\\foo.mp3".to_string(),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_transfer_request_download() {
+        roundtrip(Message::TransferRequest(TransferRequest {
+            direction: TransferDirection::Download,
+            token: 1337,
+            file_name: "music\\foo.mp3".to_string(),
+            file_size: None,
+        }));
+    }
+
+    #[test]
+    fn roundtrip_transfer_request_upload() {
+        roundtrip(Message::TransferRequest(TransferRequest {
+            direction: TransferDirection::Upload,
+            token: 1337,
+            file_name: "music\\foo.mp3".to_string(),
+            file_size: Some(123456789012),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_transfer_response_allowed() {
+        roundtrip(Message::TransferResponse(TransferResponse::Allowed {
+            token: 1337,
+            file_size: Some(123456789012),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_transfer_response_denied() {
+        roundtrip(Message::TransferResponse(TransferResponse::Denied {
+            token: 1337,
+            reason: "Cancelled".to_string(),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_place_in_queue_request() {
+        roundtrip(Message::PlaceInQueueRequest(PlaceInQueueRequest {
+            file_name: "music\\foo.mp3".to_string(),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_place_in_queue_response() {
+        roundtrip(Message::PlaceInQueueResponse(PlaceInQueueResponse {
+            file_name: "music\\foo.mp3".to_string(),
+            place: 42,
+        }));
+    }
+
+    #[test]
+    fn roundtrip_upload_denied() {
+        roundtrip(Message::UploadDenied(UploadDenied {
+            file_name: "music\\foo.mp3".to_string(),
+            reason: "File not shared.".to_string(),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_upload_failed() {
+        roundtrip(Message::UploadFailed(UploadFailed {
+            file_name: "music\\foo.mp3".to_string(),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_folder_contents_request() {
+        roundtrip(Message::FolderContentsRequest(FolderContentsRequest {
+            token: 1337,
+            folder_name: "music\\album".to_string(),
+        }));
+    }
+
+    #[test]
+    fn roundtrip_folder_contents_response() {
+        roundtrip(Message::FolderContentsResponse(FolderContentsResponse {
+            token: 1337,
+            folder_name: "music\\album".to_string(),
+            folders: vec![
+                Folder {
+                    name: "music\\album".to_string(),
+                    files: vec![File {
+                        name: "01 - foo.mp3".to_string(),
+                        size: 4567890,
+                        extension: "mp3".to_string(),
+                        attributes: vec![(0, 320), (1, 215)],
+                    }],
+                },
+                Folder {
+                    name: "music\\album\\bonus".to_string(),
+                    files: vec![],
+                },
+            ],
+        }));
+    }
+
+    #[test]
+    fn roundtrip_shared_file_list_request() {
+        roundtrip(Message::SharedFileListRequest);
+    }
+
+    #[test]
+    fn roundtrip_shared_file_list_response() {
+        roundtrip(Message::SharedFileListResponse(SharedFileListResponse {
+            folders: vec![Folder {
+                name: "music\\album".to_string(),
+                files: vec![File {
+                    name: "01 - foo.flac".to_string(),
+                    size: 34567890,
+                    extension: "".to_string(),
+                    attributes: vec![(0, 1011), (1, 215), (4, 44100), (5, 16)],
+                }],
+            }],
+        }));
+    }
+
+    #[test]
+    fn roundtrip_file_search_result() {
+        roundtrip(Message::FileSearchResult(FileSearchResult {
+            user_name: "alice".to_string(),
+            ticket: 1337,
+            files: vec![File {
+                name: "music\\album\\01 - foo.mp3".to_string(),
+                size: 4567890,
+                extension: "".to_string(),
+                attributes: vec![(0, 192), (1, 215), (2, 1)],
+            }],
+            has_free_slot: true,
+            average_speed: 123456,
+            queue_length: 3,
+        }));
+    }
 }
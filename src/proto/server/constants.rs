@@ -1,6 +1,7 @@
 pub const CODE_LOGIN: u32 = 1;
 pub const CODE_SET_LISTEN_PORT: u32 = 2;
 pub const CODE_PEER_ADDRESS: u32 = 3;
+pub const CODE_WATCH_USER: u32 = 5;
 pub const CODE_USER_STATUS: u32 = 7;
 pub const CODE_ROOM_MESSAGE: u32 = 13;
 pub const CODE_ROOM_JOIN: u32 = 14;
@@ -8,7 +9,11 @@ pub const CODE_ROOM_LEAVE: u32 = 15;
 pub const CODE_ROOM_USER_JOINED: u32 = 16;
 pub const CODE_ROOM_USER_LEFT: u32 = 17;
 pub const CODE_CONNECT_TO_PEER: u32 = 18;
+pub const CODE_MESSAGE_USER: u32 = 22;
+pub const CODE_MESSAGE_ACKED: u32 = 23;
 pub const CODE_FILE_SEARCH: u32 = 26;
+pub const CODE_SET_STATUS: u32 = 28;
+pub const CODE_SHARED_FOLDERS_FILES: u32 = 35;
 pub const CODE_USER_INFO: u32 = 36;
 pub const CODE_ROOM_LIST: u32 = 64;
 pub const CODE_PRIVILEGED_USERS: u32 = 69;
@@ -16,4 +21,8 @@ pub const CODE_PARENT_MIN_SPEED: u32 = 83;
 pub const CODE_PARENT_SPEED_RATIO: u32 = 84;
 pub const CODE_WISHLIST_INTERVAL: u32 = 104;
 pub const CODE_ROOM_TICKERS: u32 = 113;
+pub const CODE_ENABLE_PUBLIC_CHAT: u32 = 150;
+pub const CODE_DISABLE_PUBLIC_CHAT: u32 = 151;
+pub const CODE_PUBLIC_CHAT_MESSAGE: u32 = 152;
+pub const CODE_EXCLUDED_SEARCH_PHRASES: u32 = 160;
 pub const CODE_CANNOT_CONNECT: u32 = 1001;
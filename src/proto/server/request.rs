@@ -6,7 +6,7 @@ use crypto::md5::Md5;
 
 use proto::packet::{MutPacket, WriteToPacket};
 use proto::server::constants::*;
-use proto::{Decode, ProtoEncode, ProtoEncoder};
+use proto::{Decode, ProtoEncode, ProtoEncoder, UserStatus};
 
 /* ------- *
  * Helpers *
@@ -26,15 +26,23 @@ fn md5_str(string: &str) -> String {
 pub enum ServerRequest {
     CannotConnectRequest(CannotConnectRequest),
     ConnectToPeerRequest(ConnectToPeerRequest),
+    DisablePublicChatRequest,
+    EnablePublicChatRequest,
     FileSearchRequest(FileSearchRequest),
     LoginRequest(LoginRequest),
+    MessageAckedRequest(MessageAckedRequest),
+    MessageUserRequest(MessageUserRequest),
     PeerAddressRequest(PeerAddressRequest),
     RoomJoinRequest(RoomJoinRequest),
     RoomLeaveRequest(RoomLeaveRequest),
     RoomListRequest,
     RoomMessageRequest(RoomMessageRequest),
     SetListenPortRequest(SetListenPortRequest),
+    SetStatusRequest(SetStatusRequest),
+    SharedFoldersFilesRequest(SharedFoldersFilesRequest),
+    UserInfoRequest(UserInfoRequest),
     UserStatusRequest(UserStatusRequest),
+    WatchUserRequest(WatchUserRequest),
 }
 
 impl WriteToPacket for ServerRequest {
@@ -50,6 +58,14 @@ impl WriteToPacket for ServerRequest {
                 try!(packet.write_value(request));
             }
 
+            ServerRequest::DisablePublicChatRequest => {
+                try!(packet.write_value(&CODE_DISABLE_PUBLIC_CHAT));
+            }
+
+            ServerRequest::EnablePublicChatRequest => {
+                try!(packet.write_value(&CODE_ENABLE_PUBLIC_CHAT));
+            }
+
             ServerRequest::FileSearchRequest(ref request) => {
                 try!(packet.write_value(&CODE_FILE_SEARCH));
                 try!(packet.write_value(request));
@@ -60,6 +76,16 @@ impl WriteToPacket for ServerRequest {
                 try!(packet.write_value(request));
             }
 
+            ServerRequest::MessageAckedRequest(ref request) => {
+                try!(packet.write_value(&CODE_MESSAGE_ACKED));
+                try!(packet.write_value(request));
+            }
+
+            ServerRequest::MessageUserRequest(ref request) => {
+                try!(packet.write_value(&CODE_MESSAGE_USER));
+                try!(packet.write_value(request));
+            }
+
             ServerRequest::PeerAddressRequest(ref request) => {
                 try!(packet.write_value(&CODE_PEER_ADDRESS));
                 try!(packet.write_value(request));
@@ -89,10 +115,30 @@ impl WriteToPacket for ServerRequest {
                 try!(packet.write_value(request));
             }
 
+            ServerRequest::SetStatusRequest(ref request) => {
+                try!(packet.write_value(&CODE_SET_STATUS));
+                try!(packet.write_value(request));
+            }
+
+            ServerRequest::SharedFoldersFilesRequest(ref request) => {
+                try!(packet.write_value(&CODE_SHARED_FOLDERS_FILES));
+                try!(packet.write_value(request));
+            }
+
+            ServerRequest::UserInfoRequest(ref request) => {
+                try!(packet.write_value(&CODE_USER_INFO));
+                try!(packet.write_value(request));
+            }
+
             ServerRequest::UserStatusRequest(ref request) => {
                 try!(packet.write_value(&CODE_USER_STATUS));
                 try!(packet.write_value(request));
             }
+
+            ServerRequest::WatchUserRequest(ref request) => {
+                try!(packet.write_value(&CODE_WATCH_USER));
+                try!(packet.write_value(request));
+            }
         }
         Ok(())
     }
@@ -109,6 +155,12 @@ impl ProtoEncode for ServerRequest {
                 encoder.encode_u32(CODE_CONNECT_TO_PEER)?;
                 request.encode(encoder)?;
             }
+            ServerRequest::DisablePublicChatRequest => {
+                encoder.encode_u32(CODE_DISABLE_PUBLIC_CHAT)?;
+            }
+            ServerRequest::EnablePublicChatRequest => {
+                encoder.encode_u32(CODE_ENABLE_PUBLIC_CHAT)?;
+            }
             ServerRequest::FileSearchRequest(ref request) => {
                 encoder.encode_u32(CODE_FILE_SEARCH)?;
                 request.encode(encoder)?;
@@ -117,6 +169,14 @@ impl ProtoEncode for ServerRequest {
                 encoder.encode_u32(CODE_LOGIN)?;
                 request.encode(encoder)?;
             }
+            ServerRequest::MessageAckedRequest(ref request) => {
+                encoder.encode_u32(CODE_MESSAGE_ACKED)?;
+                request.encode(encoder)?;
+            }
+            ServerRequest::MessageUserRequest(ref request) => {
+                encoder.encode_u32(CODE_MESSAGE_USER)?;
+                request.encode(encoder)?;
+            }
             ServerRequest::PeerAddressRequest(ref request) => {
                 encoder.encode_u32(CODE_PEER_ADDRESS)?;
                 request.encode(encoder)?;
@@ -140,10 +200,26 @@ impl ProtoEncode for ServerRequest {
                 encoder.encode_u32(CODE_SET_LISTEN_PORT)?;
                 request.encode(encoder)?;
             }
+            ServerRequest::SetStatusRequest(ref request) => {
+                encoder.encode_u32(CODE_SET_STATUS)?;
+                request.encode(encoder)?;
+            }
+            ServerRequest::SharedFoldersFilesRequest(ref request) => {
+                encoder.encode_u32(CODE_SHARED_FOLDERS_FILES)?;
+                request.encode(encoder)?;
+            }
+            ServerRequest::UserInfoRequest(ref request) => {
+                encoder.encode_u32(CODE_USER_INFO)?;
+                request.encode(encoder)?;
+            }
             ServerRequest::UserStatusRequest(ref request) => {
                 encoder.encode_u32(CODE_USER_STATUS)?;
                 request.encode(encoder)?;
             }
+            ServerRequest::WatchUserRequest(ref request) => {
+                encoder.encode_u32(CODE_WATCH_USER)?;
+                request.encode(encoder)?;
+            }
         }
         Ok(())
     }
@@ -161,6 +237,8 @@ impl<T: bytes::Buf> Decode<ServerRequest> for T {
                 let request = self.decode()?;
                 ServerRequest::ConnectToPeerRequest(request)
             }
+            CODE_DISABLE_PUBLIC_CHAT => ServerRequest::DisablePublicChatRequest,
+            CODE_ENABLE_PUBLIC_CHAT => ServerRequest::EnablePublicChatRequest,
             CODE_FILE_SEARCH => {
                 let request = self.decode()?;
                 ServerRequest::FileSearchRequest(request)
@@ -169,6 +247,14 @@ impl<T: bytes::Buf> Decode<ServerRequest> for T {
                 let request = self.decode()?;
                 ServerRequest::LoginRequest(request)
             }
+            CODE_MESSAGE_ACKED => {
+                let request = self.decode()?;
+                ServerRequest::MessageAckedRequest(request)
+            }
+            CODE_MESSAGE_USER => {
+                let request = self.decode()?;
+                ServerRequest::MessageUserRequest(request)
+            }
             CODE_PEER_ADDRESS => {
                 let request = self.decode()?;
                 ServerRequest::PeerAddressRequest(request)
@@ -190,10 +276,26 @@ impl<T: bytes::Buf> Decode<ServerRequest> for T {
                 let request = self.decode()?;
                 ServerRequest::SetListenPortRequest(request)
             }
+            CODE_SET_STATUS => {
+                let request = self.decode()?;
+                ServerRequest::SetStatusRequest(request)
+            }
+            CODE_SHARED_FOLDERS_FILES => {
+                let request = self.decode()?;
+                ServerRequest::SharedFoldersFilesRequest(request)
+            }
+            CODE_USER_INFO => {
+                let request = self.decode()?;
+                ServerRequest::UserInfoRequest(request)
+            }
             CODE_USER_STATUS => {
                 let request = self.decode()?;
                 ServerRequest::UserStatusRequest(request)
             }
+            CODE_WATCH_USER => {
+                let request = self.decode()?;
+                ServerRequest::WatchUserRequest(request)
+            }
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -393,6 +495,68 @@ impl<T: bytes::Buf> Decode<LoginRequest> for T {
     }
 }
 
+/*===============*
+ * MESSAGE ACKED *
+ *===============*/
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct MessageAckedRequest {
+    pub message_id: u32,
+}
+
+impl WriteToPacket for MessageAckedRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.message_id));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for MessageAckedRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> Result<(), io::Error> {
+        encoder.encode_u32(self.message_id)
+    }
+}
+
+impl<T: bytes::Buf> Decode<MessageAckedRequest> for T {
+    fn decode(&mut self) -> io::Result<MessageAckedRequest> {
+        let message_id = self.decode()?;
+        Ok(MessageAckedRequest { message_id })
+    }
+}
+
+/*==============*
+ * MESSAGE USER *
+ *==============*/
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct MessageUserRequest {
+    pub user_name: String,
+    pub message: String,
+}
+
+impl WriteToPacket for MessageUserRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.user_name));
+        try!(packet.write_value(&self.message));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for MessageUserRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> Result<(), io::Error> {
+        encoder.encode_string(&self.user_name)?;
+        encoder.encode_string(&self.message)
+    }
+}
+
+impl<T: bytes::Buf> Decode<MessageUserRequest> for T {
+    fn decode(&mut self) -> io::Result<MessageUserRequest> {
+        let user_name = self.decode()?;
+        let message = self.decode()?;
+        Ok(MessageUserRequest { user_name, message })
+    }
+}
+
 /*==============*
  * PEER ADDRESS *
  *==============*/
@@ -546,6 +710,100 @@ impl<T: bytes::Buf> Decode<SetListenPortRequest> for T {
     }
 }
 
+/*============*
+ * SET STATUS *
+ *============*/
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SetStatusRequest {
+    pub status: UserStatus,
+}
+
+impl WriteToPacket for SetStatusRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.status));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for SetStatusRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> Result<(), io::Error> {
+        self.status.encode(encoder)
+    }
+}
+
+impl<T: bytes::Buf> Decode<SetStatusRequest> for T {
+    fn decode(&mut self) -> io::Result<SetStatusRequest> {
+        let status = self.decode()?;
+        Ok(SetStatusRequest { status })
+    }
+}
+
+/*======================*
+ * SHARED FOLDERS FILES *
+ *======================*/
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SharedFoldersFilesRequest {
+    pub num_folders: u32,
+    pub num_files: u32,
+}
+
+impl WriteToPacket for SharedFoldersFilesRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.num_folders));
+        try!(packet.write_value(&self.num_files));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for SharedFoldersFilesRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> Result<(), io::Error> {
+        encoder.encode_u32(self.num_folders)?;
+        encoder.encode_u32(self.num_files)
+    }
+}
+
+impl<T: bytes::Buf> Decode<SharedFoldersFilesRequest> for T {
+    fn decode(&mut self) -> io::Result<SharedFoldersFilesRequest> {
+        let num_folders = self.decode()?;
+        let num_files = self.decode()?;
+        Ok(SharedFoldersFilesRequest {
+            num_folders,
+            num_files,
+        })
+    }
+}
+
+/*===========*
+ * USER INFO *
+ *===========*/
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct UserInfoRequest {
+    pub user_name: String,
+}
+
+impl WriteToPacket for UserInfoRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.user_name));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for UserInfoRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> Result<(), io::Error> {
+        encoder.encode_string(&self.user_name)
+    }
+}
+
+impl<T: bytes::Buf> Decode<UserInfoRequest> for T {
+    fn decode(&mut self) -> io::Result<UserInfoRequest> {
+        let user_name = self.decode()?;
+        Ok(UserInfoRequest { user_name })
+    }
+}
+
 /*=============*
  * USER STATUS *
  *=============*/
@@ -577,6 +835,35 @@ impl<T: bytes::Buf> Decode<UserStatusRequest> for T {
     }
 }
 
+/*============*
+ * WATCH USER *
+ *============*/
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct WatchUserRequest {
+    pub user_name: String,
+}
+
+impl WriteToPacket for WatchUserRequest {
+    fn write_to_packet(&self, packet: &mut MutPacket) -> io::Result<()> {
+        try!(packet.write_value(&self.user_name));
+        Ok(())
+    }
+}
+
+impl ProtoEncode for WatchUserRequest {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> Result<(), io::Error> {
+        encoder.encode_string(&self.user_name)
+    }
+}
+
+impl<T: bytes::Buf> Decode<WatchUserRequest> for T {
+    fn decode(&mut self) -> io::Result<WatchUserRequest> {
+        let user_name = self.decode()?;
+        Ok(WatchUserRequest { user_name })
+    }
+}
+
 /*=======*
  * TESTS *
  *=======*/
@@ -622,6 +909,16 @@ mod tests {
         }))
     }
 
+    #[test]
+    fn roundtrip_disable_public_chat_request() {
+        roundtrip(ServerRequest::DisablePublicChatRequest)
+    }
+
+    #[test]
+    fn roundtrip_enable_public_chat_request() {
+        roundtrip(ServerRequest::EnablePublicChatRequest)
+    }
+
     #[test]
     fn roundtrip_file_search_request() {
         roundtrip(ServerRequest::FileSearchRequest(FileSearchRequest {
@@ -649,6 +946,21 @@ mod tests {
         ))
     }
 
+    #[test]
+    fn roundtrip_message_acked_request() {
+        roundtrip(ServerRequest::MessageAckedRequest(MessageAckedRequest {
+            message_id: 42,
+        }))
+    }
+
+    #[test]
+    fn roundtrip_message_user_request() {
+        roundtrip(ServerRequest::MessageUserRequest(MessageUserRequest {
+            user_name: "alice".to_string(),
+            message: "hello world!".to_string(),
+        }))
+    }
+
     #[test]
     fn roundtrip_peer_address_request() {
         roundtrip(ServerRequest::PeerAddressRequest(PeerAddressRequest {
@@ -690,10 +1002,41 @@ mod tests {
         }))
     }
 
+    #[test]
+    fn roundtrip_set_status_request() {
+        roundtrip(ServerRequest::SetStatusRequest(SetStatusRequest {
+            status: UserStatus::Away,
+        }))
+    }
+
+    #[test]
+    fn roundtrip_shared_folders_files_request() {
+        roundtrip(ServerRequest::SharedFoldersFilesRequest(
+            SharedFoldersFilesRequest {
+                num_folders: 42,
+                num_files: 1337,
+            },
+        ))
+    }
+
+    #[test]
+    fn roundtrip_user_info_request() {
+        roundtrip(ServerRequest::UserInfoRequest(UserInfoRequest {
+            user_name: "alice".to_string(),
+        }))
+    }
+
     #[test]
     fn roundtrip_user_status_request() {
         roundtrip(ServerRequest::UserStatusRequest(UserStatusRequest {
             user_name: "alice".to_string(),
         }))
     }
+
+    #[test]
+    fn roundtrip_watch_user_request() {
+        roundtrip(ServerRequest::WatchUserRequest(WatchUserRequest {
+            user_name: "alice".to_string(),
+        }))
+    }
 }
@@ -14,10 +14,13 @@ use proto::{Decode, ProtoEncode, ProtoEncoder, User, UserStatus};
 #[derive(Debug, Eq, PartialEq)]
 pub enum ServerResponse {
     ConnectToPeerResponse(ConnectToPeerResponse),
+    ExcludedSearchPhrasesResponse(ExcludedSearchPhrasesResponse),
     FileSearchResponse(FileSearchResponse),
     LoginResponse(LoginResponse),
+    MessageUserResponse(MessageUserResponse),
     PeerAddressResponse(PeerAddressResponse),
     PrivilegedUsersResponse(PrivilegedUsersResponse),
+    PublicChatMessageResponse(PublicChatMessageResponse),
     RoomJoinResponse(RoomJoinResponse),
     RoomLeaveResponse(RoomLeaveResponse),
     RoomListResponse(RoomListResponse),
@@ -27,6 +30,7 @@ pub enum ServerResponse {
     RoomUserLeftResponse(RoomUserLeftResponse),
     UserInfoResponse(UserInfoResponse),
     UserStatusResponse(UserStatusResponse),
+    WatchUserResponse(WatchUserResponse),
     WishlistIntervalResponse(WishlistIntervalResponse),
 
     // Unknown purpose
@@ -44,16 +48,26 @@ impl ReadFromPacket for ServerResponse {
                 ServerResponse::ConnectToPeerResponse(try!(packet.read_value()))
             }
 
+            CODE_EXCLUDED_SEARCH_PHRASES => {
+                ServerResponse::ExcludedSearchPhrasesResponse(try!(packet.read_value()))
+            }
+
             CODE_FILE_SEARCH => ServerResponse::FileSearchResponse(try!(packet.read_value())),
 
             CODE_LOGIN => ServerResponse::LoginResponse(try!(packet.read_value())),
 
+            CODE_MESSAGE_USER => ServerResponse::MessageUserResponse(try!(packet.read_value())),
+
             CODE_PEER_ADDRESS => ServerResponse::PeerAddressResponse(try!(packet.read_value())),
 
             CODE_PRIVILEGED_USERS => {
                 ServerResponse::PrivilegedUsersResponse(try!(packet.read_value()))
             }
 
+            CODE_PUBLIC_CHAT_MESSAGE => {
+                ServerResponse::PublicChatMessageResponse(try!(packet.read_value()))
+            }
+
             CODE_ROOM_JOIN => ServerResponse::RoomJoinResponse(try!(packet.read_value())),
 
             CODE_ROOM_LEAVE => ServerResponse::RoomLeaveResponse(try!(packet.read_value())),
@@ -74,6 +88,8 @@ impl ReadFromPacket for ServerResponse {
 
             CODE_USER_STATUS => ServerResponse::UserStatusResponse(try!(packet.read_value())),
 
+            CODE_WATCH_USER => ServerResponse::WatchUserResponse(try!(packet.read_value())),
+
             CODE_WISHLIST_INTERVAL => {
                 ServerResponse::WishlistIntervalResponse(try!(packet.read_value()))
             }
@@ -106,6 +122,10 @@ impl ProtoEncode for ServerResponse {
                 encoder.encode_u32(CODE_CONNECT_TO_PEER)?;
                 response.encode(encoder)?;
             }
+            ServerResponse::ExcludedSearchPhrasesResponse(ref response) => {
+                encoder.encode_u32(CODE_EXCLUDED_SEARCH_PHRASES)?;
+                response.encode(encoder)?;
+            }
             ServerResponse::FileSearchResponse(ref response) => {
                 encoder.encode_u32(CODE_FILE_SEARCH)?;
                 response.encode(encoder)?;
@@ -114,6 +134,10 @@ impl ProtoEncode for ServerResponse {
                 encoder.encode_u32(CODE_LOGIN)?;
                 response.encode(encoder)?;
             }
+            ServerResponse::MessageUserResponse(ref response) => {
+                encoder.encode_u32(CODE_MESSAGE_USER)?;
+                response.encode(encoder)?;
+            }
             ServerResponse::ParentMinSpeedResponse(ref response) => {
                 encoder.encode_u32(CODE_PARENT_MIN_SPEED)?;
                 response.encode(encoder)?;
@@ -130,6 +154,10 @@ impl ProtoEncode for ServerResponse {
                 encoder.encode_u32(CODE_PRIVILEGED_USERS)?;
                 response.encode(encoder)?;
             }
+            ServerResponse::PublicChatMessageResponse(ref response) => {
+                encoder.encode_u32(CODE_PUBLIC_CHAT_MESSAGE)?;
+                response.encode(encoder)?;
+            }
             ServerResponse::RoomJoinResponse(ref response) => {
                 encoder.encode_u32(CODE_ROOM_JOIN)?;
                 response.encode(encoder)?;
@@ -166,6 +194,10 @@ impl ProtoEncode for ServerResponse {
                 encoder.encode_u32(CODE_USER_STATUS)?;
                 response.encode(encoder)?;
             }
+            ServerResponse::WatchUserResponse(ref response) => {
+                encoder.encode_u32(CODE_WATCH_USER)?;
+                response.encode(encoder)?;
+            }
             ServerResponse::WishlistIntervalResponse(ref response) => {
                 encoder.encode_u32(CODE_WISHLIST_INTERVAL)?;
                 response.encode(encoder)?;
@@ -186,6 +218,10 @@ impl<T: bytes::Buf> Decode<ServerResponse> for T {
                 let response = self.decode()?;
                 ServerResponse::ConnectToPeerResponse(response)
             }
+            CODE_EXCLUDED_SEARCH_PHRASES => {
+                let response = self.decode()?;
+                ServerResponse::ExcludedSearchPhrasesResponse(response)
+            }
             CODE_FILE_SEARCH => {
                 let response = self.decode()?;
                 ServerResponse::FileSearchResponse(response)
@@ -194,6 +230,10 @@ impl<T: bytes::Buf> Decode<ServerResponse> for T {
                 let response = self.decode()?;
                 ServerResponse::LoginResponse(response)
             }
+            CODE_MESSAGE_USER => {
+                let response = self.decode()?;
+                ServerResponse::MessageUserResponse(response)
+            }
             CODE_PARENT_MIN_SPEED => {
                 let response = self.decode()?;
                 ServerResponse::ParentMinSpeedResponse(response)
@@ -210,6 +250,10 @@ impl<T: bytes::Buf> Decode<ServerResponse> for T {
                 let response = self.decode()?;
                 ServerResponse::PrivilegedUsersResponse(response)
             }
+            CODE_PUBLIC_CHAT_MESSAGE => {
+                let response = self.decode()?;
+                ServerResponse::PublicChatMessageResponse(response)
+            }
             CODE_ROOM_JOIN => {
                 let response = self.decode()?;
                 ServerResponse::RoomJoinResponse(response)
@@ -246,6 +290,10 @@ impl<T: bytes::Buf> Decode<ServerResponse> for T {
                 let response = self.decode()?;
                 ServerResponse::UserStatusResponse(response)
             }
+            CODE_WATCH_USER => {
+                let response = self.decode()?;
+                ServerResponse::WatchUserResponse(response)
+            }
             CODE_WISHLIST_INTERVAL => {
                 let response = self.decode()?;
                 ServerResponse::WishlistIntervalResponse(response)
@@ -326,6 +374,37 @@ impl<T: bytes::Buf> Decode<ConnectToPeerResponse> for T {
     }
 }
 
+/*=========================*
+ * EXCLUDED SEARCH PHRASES *
+ *=========================*/
+
+/// The phrases that must not appear in the results we send in response to
+/// searches.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ExcludedSearchPhrasesResponse {
+    pub phrases: Vec<String>,
+}
+
+impl ReadFromPacket for ExcludedSearchPhrasesResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let phrases = try!(packet.read_value());
+        Ok(ExcludedSearchPhrasesResponse { phrases })
+    }
+}
+
+impl ProtoEncode for ExcludedSearchPhrasesResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> Result<(), io::Error> {
+        encoder.encode_vec(&self.phrases)
+    }
+}
+
+impl<T: bytes::Buf> Decode<ExcludedSearchPhrasesResponse> for T {
+    fn decode(&mut self) -> io::Result<ExcludedSearchPhrasesResponse> {
+        let phrases = self.decode()?;
+        Ok(ExcludedSearchPhrasesResponse { phrases })
+    }
+}
+
 /*=============*
  * FILE SEARCH *
  *=============*/
@@ -460,6 +539,76 @@ impl<T: bytes::Buf> Decode<LoginResponse> for T {
     }
 }
 
+/*==============*
+ * MESSAGE USER *
+ *==============*/
+
+/// A private message sent to us by another user. The server keeps sending
+/// it, even across logins, until we acknowledge it using its id.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MessageUserResponse {
+    pub id: u32,
+    /// When the message was sent, in seconds since the epoch.
+    pub timestamp: u32,
+    pub user_name: String,
+    pub message: String,
+    /// True if the message was sent by a server administrator.
+    pub is_admin: bool,
+}
+
+impl ReadFromPacket for MessageUserResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let id = try!(packet.read_value());
+        let timestamp = try!(packet.read_value());
+        let user_name = try!(packet.read_value());
+        let message = try!(packet.read_value());
+        // Older servers do not send this field.
+        let is_admin = if packet.bytes_remaining() > 0 {
+            try!(packet.read_value())
+        } else {
+            false
+        };
+        Ok(MessageUserResponse {
+            id,
+            timestamp,
+            user_name,
+            message,
+            is_admin,
+        })
+    }
+}
+
+impl ProtoEncode for MessageUserResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_u32(self.id)?;
+        encoder.encode_u32(self.timestamp)?;
+        encoder.encode_string(&self.user_name)?;
+        encoder.encode_string(&self.message)?;
+        encoder.encode_bool(self.is_admin)
+    }
+}
+
+impl<T: bytes::Buf> Decode<MessageUserResponse> for T {
+    fn decode(&mut self) -> io::Result<MessageUserResponse> {
+        let id = self.decode()?;
+        let timestamp = self.decode()?;
+        let user_name = self.decode()?;
+        let message = self.decode()?;
+        let is_admin = if self.has_remaining() {
+            self.decode()?
+        } else {
+            false
+        };
+        Ok(MessageUserResponse {
+            id,
+            timestamp,
+            user_name,
+            message,
+            is_admin,
+        })
+    }
+}
+
 /*==================*
  * PARENT MIN SPEED *
  *==================*/
@@ -524,9 +673,9 @@ impl<T: bytes::Buf> Decode<ParentSpeedRatioResponse> for T {
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct PeerAddressResponse {
-    username: String,
-    ip: net::Ipv4Addr,
-    port: u16,
+    pub username: String,
+    pub ip: net::Ipv4Addr,
+    pub port: u16,
 }
 
 impl ReadFromPacket for PeerAddressResponse {
@@ -585,6 +734,53 @@ impl<T: bytes::Buf> Decode<PrivilegedUsersResponse> for T {
     }
 }
 
+/*=====================*
+ * PUBLIC CHAT MESSAGE *
+ *=====================*/
+
+/// A message said in a public room, sent to the clients that enabled public
+/// chat, whether or not they are members of the room.
+#[derive(Debug, Eq, PartialEq)]
+pub struct PublicChatMessageResponse {
+    pub room_name: String,
+    pub user_name: String,
+    pub message: String,
+}
+
+impl ReadFromPacket for PublicChatMessageResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let room_name = try!(packet.read_value());
+        let user_name = try!(packet.read_value());
+        let message = try!(packet.read_value());
+        Ok(PublicChatMessageResponse {
+            room_name,
+            user_name,
+            message,
+        })
+    }
+}
+
+impl ProtoEncode for PublicChatMessageResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.room_name)?;
+        encoder.encode_string(&self.user_name)?;
+        encoder.encode_string(&self.message)
+    }
+}
+
+impl<T: bytes::Buf> Decode<PublicChatMessageResponse> for T {
+    fn decode(&mut self) -> io::Result<PublicChatMessageResponse> {
+        let room_name = self.decode()?;
+        let user_name = self.decode()?;
+        let message = self.decode()?;
+        Ok(PublicChatMessageResponse {
+            room_name,
+            user_name,
+            message,
+        })
+    }
+}
+
 /*===========*
  * ROOM JOIN *
  *===========*/
@@ -1304,6 +1500,104 @@ impl<T: bytes::Buf> Decode<UserStatusResponse> for T {
     }
 }
 
+/*============*
+ * WATCH USER *
+ *============*/
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct WatchUserResponse {
+    pub user_name: String,
+    /// The last known information about the user, if the user exists.
+    pub user_info: Option<User>,
+}
+
+impl ReadFromPacket for WatchUserResponse {
+    fn read_from_packet(packet: &mut Packet) -> Result<Self, PacketReadError> {
+        let user_name: String = try!(packet.read_value());
+        let exists: bool = try!(packet.read_value());
+        if !exists {
+            return Ok(WatchUserResponse {
+                user_name,
+                user_info: None,
+            });
+        }
+
+        let status = try!(packet.read_value());
+
+        let average_speed = try!(packet.read_value());
+        let num_downloads = try!(packet.read_value());
+        let unknown = try!(packet.read_value());
+        let num_files = try!(packet.read_value());
+        let num_folders = try!(packet.read_value());
+
+        // The country code is only sent for users that are online.
+        let country = if packet.bytes_remaining() > 0 {
+            try!(packet.read_value())
+        } else {
+            String::new()
+        };
+
+        Ok(WatchUserResponse {
+            user_info: Some(User {
+                name: user_name.clone(),
+                status,
+                average_speed,
+                num_downloads,
+                unknown,
+                num_files,
+                num_folders,
+                num_free_slots: 0,
+                country,
+            }),
+            user_name,
+        })
+    }
+}
+
+impl ProtoEncode for WatchUserResponse {
+    fn encode(&self, encoder: &mut ProtoEncoder) -> io::Result<()> {
+        encoder.encode_string(&self.user_name)?;
+        match self.user_info {
+            None => encoder.encode_bool(false),
+            Some(ref user) => {
+                encoder.encode_bool(true)?;
+                user.status.encode(encoder)?;
+                UserInfo::from_user(user).encode(encoder)?;
+                if !user.country.is_empty() {
+                    encoder.encode_string(&user.country)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: bytes::Buf> Decode<WatchUserResponse> for T {
+    fn decode(&mut self) -> io::Result<WatchUserResponse> {
+        let user_name: String = self.decode()?;
+        let exists: bool = self.decode()?;
+        if !exists {
+            return Ok(WatchUserResponse {
+                user_name,
+                user_info: None,
+            });
+        }
+
+        let status = self.decode()?;
+        let info = self.decode()?;
+        let country = if self.has_remaining() {
+            self.decode()?
+        } else {
+            String::new()
+        };
+
+        Ok(WatchUserResponse {
+            user_info: Some(build_user(user_name.clone(), status, info, 0, country)),
+            user_name,
+        })
+    }
+}
+
 /*===================*
  * WISHLIST INTERVAL *
  *===================*/
@@ -1377,6 +1671,15 @@ mod tests {
         ))
     }
 
+    #[test]
+    fn roundtrip_excluded_search_phrases() {
+        roundtrip(ServerResponse::ExcludedSearchPhrasesResponse(
+            ExcludedSearchPhrasesResponse {
+                phrases: vec!["foo bar".to_string(), "baz".to_string()],
+            },
+        ))
+    }
+
     #[test]
     fn roundtrip_file_search() {
         roundtrip(ServerResponse::FileSearchResponse(FileSearchResponse {
@@ -1402,6 +1705,17 @@ mod tests {
         }))
     }
 
+    #[test]
+    fn roundtrip_message_user() {
+        roundtrip(ServerResponse::MessageUserResponse(MessageUserResponse {
+            id: 42,
+            timestamp: 1337,
+            user_name: "alice".to_string(),
+            message: "hello world!".to_string(),
+            is_admin: false,
+        }))
+    }
+
     #[test]
     fn roundtrip_parent_min_speed() {
         roundtrip(ServerResponse::ParentMinSpeedResponse(
@@ -1439,6 +1753,17 @@ mod tests {
         ))
     }
 
+    #[test]
+    fn roundtrip_public_chat_message() {
+        roundtrip(ServerResponse::PublicChatMessageResponse(
+            PublicChatMessageResponse {
+                room_name: "best room ever".to_string(),
+                user_name: "alice".to_string(),
+                message: "hello world!".to_string(),
+            },
+        ))
+    }
+
     #[test]
     fn roundtrip_room_join() {
         roundtrip(ServerResponse::RoomJoinResponse(RoomJoinResponse {
@@ -1567,6 +1892,32 @@ mod tests {
         }))
     }
 
+    #[test]
+    fn roundtrip_watch_user() {
+        roundtrip(ServerResponse::WatchUserResponse(WatchUserResponse {
+            user_name: "alice".to_string(),
+            user_info: Some(User {
+                name: "alice".to_string(),
+                status: UserStatus::Online,
+                average_speed: 1000,
+                num_downloads: 1001,
+                unknown: 1002,
+                num_files: 1003,
+                num_folders: 1004,
+                num_free_slots: 0,
+                country: "FR".to_string(),
+            }),
+        }))
+    }
+
+    #[test]
+    fn roundtrip_watch_user_not_found() {
+        roundtrip(ServerResponse::WatchUserResponse(WatchUserResponse {
+            user_name: "alice".to_string(),
+            user_info: None,
+        }))
+    }
+
     #[test]
     fn roundtrip_wishlist_interval() {
         roundtrip(ServerResponse::WishlistIntervalResponse(
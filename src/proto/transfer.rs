@@ -0,0 +1,487 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::mem;
+use std::net::ToSocketAddrs;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use mio;
+use mio::deprecated::{TryRead, TryWrite};
+
+use super::limiter::Buckets;
+use super::packet::{MutPacket, WriteToPacket};
+use super::Intent;
+
+/// The size of the buffer into which file contents are read.
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Length of the transfer token sent by the uploader, in bytes.
+const TOKEN_BYTE_LEN: usize = 4;
+
+/// Length of the offset sent by the downloader, in bytes.
+const OFFSET_BYTE_LEN: usize = 8;
+
+/// Whether a transfer connection is used to receive or send a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferMode {
+    Receive,
+    Send,
+}
+
+/*==========*
+ * RECEIVER *
+ *==========*/
+
+/// This trait is implemented by sinks to which a transfer stream reports the
+/// progress of the transfer.
+pub trait ReceiveTransfer {
+    type Error: error::Error;
+
+    /// The connection is open.
+    fn notify_open(&mut self) -> Result<(), Self::Error>;
+
+    /// The uploader sent the token identifying the transfer.
+    fn notify_token(&mut self, token: u32) -> Result<(), Self::Error>;
+
+    /// The file has been written, or sent, up to the given position.
+    fn notify_progress(&mut self, position: u64) -> Result<(), Self::Error>;
+}
+
+/*=================*
+ * TRANSFER STREAM *
+ *=================*/
+
+/// The state of a file transfer.
+#[derive(Debug)]
+enum State {
+    // Downloader states.
+    /// Waiting for the uploader to send the transfer token.
+    ReadingToken,
+    /// Waiting to be told which file to write to.
+    WaitingForFile,
+    /// Writing the received bytes to the given file.
+    Receiving {
+        file: fs::File,
+        position: u64,
+        size: u64,
+    },
+
+    // Uploader states.
+    /// Waiting to be told which file to send.
+    WaitingToSend,
+    /// The token was sent, waiting for the downloader to send the offset.
+    ReadingOffset { file: fs::File, size: u64 },
+    /// Sending the given file. The position is that up to which the file
+    /// has been read, which may be ahead of what was actually sent.
+    Sending {
+        file: fs::File,
+        position: u64,
+        size: u64,
+    },
+}
+
+/// This struct wraps around an mio tcp stream on which a file is received or
+/// sent.
+///
+/// Once the connection is open and the peer has been sent whichever peer
+/// init message is appropriate, the uploader sends the token of the transfer
+/// as a raw 32-bit integer. The downloader answers with the offset, as a raw
+/// 64-bit integer, from which the uploader should send the file. Everything
+/// after that is file contents.
+#[derive(Debug)]
+pub struct TransferStream<T: ReceiveTransfer> {
+    stream: mio::tcp::TcpStream,
+    receiver: T,
+    mode: TransferMode,
+    state: State,
+    /// The token buckets limiting the rate at which the file is transferred.
+    buckets: Buckets,
+    /// Whether the transfer has been suspended to respect the rate limits.
+    is_throttled: bool,
+    /// Bytes read before we knew what to do with them.
+    inbuf: Vec<u8>,
+    /// Bytes waiting to be written to the stream.
+    outbuf: Vec<u8>,
+
+    is_connected: bool,
+}
+
+impl<T: ReceiveTransfer> TransferStream<T> {
+    /// Returns a new transfer stream, asynchronously connected to the given
+    /// address, which reports to the given receiver and transfers the file
+    /// in the given mode no faster than the given buckets allow.
+    /// If an error occurs when connecting, returns an error.
+    pub fn new<U>(
+        addr_spec: U,
+        mode: TransferMode,
+        receiver: T,
+        buckets: Buckets,
+    ) -> io::Result<Self>
+    where
+        U: ToSocketAddrs + fmt::Debug,
+    {
+        let state = match mode {
+            TransferMode::Receive => State::ReadingToken,
+            TransferMode::Send => State::WaitingToSend,
+        };
+        for sock_addr in addr_spec.to_socket_addrs()? {
+            if let Ok(stream) = mio::tcp::TcpStream::connect(&sock_addr) {
+                return Ok(TransferStream {
+                    stream: stream,
+                    receiver: receiver,
+                    mode: mode,
+                    state: state,
+                    buckets: buckets,
+                    is_throttled: false,
+                    inbuf: Vec::new(),
+                    outbuf: Vec::new(),
+
+                    is_connected: false,
+                });
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Cannot connect to {:?}", addr_spec),
+        ))
+    }
+
+    /// Returns a reference to the underlying byte stream, to allow it to be
+    /// registered with an event loop.
+    pub fn evented(&self) -> &mio::tcp::TcpStream {
+        &self.stream
+    }
+
+    /// Handles bytes read from the stream. Returns true if the whole file has
+    /// been received.
+    fn on_bytes(&mut self, bytes: &[u8]) -> Result<bool, String> {
+        if let State::Receiving {
+            ref mut file,
+            ref mut position,
+            size,
+        } = self.state
+        {
+            if *position + bytes.len() as u64 > size {
+                return Err(format!("Received more than the advertised {} bytes", size));
+            }
+            if let Err(err) = file.write_all(bytes) {
+                return Err(format!("Error writing to file: {}", err));
+            }
+            *position += bytes.len() as u64;
+            return Ok(*position == size);
+        }
+
+        if let State::Sending { .. } = self.state {
+            // The downloader has nothing more to say.
+            return Ok(false);
+        }
+
+        self.inbuf.extend_from_slice(bytes);
+
+        if let State::ReadingToken = self.state {
+            if self.inbuf.len() >= TOKEN_BYTE_LEN {
+                let token = LittleEndian::read_u32(&self.inbuf[..TOKEN_BYTE_LEN]);
+                self.inbuf.drain(..TOKEN_BYTE_LEN);
+                self.state = State::WaitingForFile;
+                if let Err(err) = self.receiver.notify_token(token) {
+                    return Err(format!("Error sending token: {}", err));
+                }
+            }
+        }
+
+        if let State::ReadingOffset { .. } = self.state {
+            if self.inbuf.len() >= OFFSET_BYTE_LEN {
+                let offset = LittleEndian::read_u64(&self.inbuf[..OFFSET_BYTE_LEN]);
+                self.inbuf.drain(..OFFSET_BYTE_LEN);
+                self.start_sending(offset)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Starts sending the file from the given offset onwards.
+    fn start_sending(&mut self, offset: u64) -> Result<(), String> {
+        let (mut file, size) = match mem::replace(&mut self.state, State::WaitingToSend) {
+            State::ReadingOffset { file, size } => (file, size),
+            _ => unreachable!(),
+        };
+        if offset > size {
+            return Err(format!("Offset {} is past the end of the file", offset));
+        }
+        if let Err(err) = file.seek(io::SeekFrom::Start(offset)) {
+            return Err(format!("Error seeking in file: {}", err));
+        }
+        self.state = State::Sending {
+            file: file,
+            position: offset,
+            size: size,
+        };
+        Ok(())
+    }
+
+    /// The stream is ready to be read from. Returns true if the stream is
+    /// done, either because the whole file was received or because the
+    /// other end closed the connection.
+    /// When receiving, stops reading and marks the stream as throttled when
+    /// the rate limits are reached.
+    fn on_readable(&mut self) -> Result<bool, String> {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            let allowance = match self.mode {
+                TransferMode::Receive => self.buckets.allowance(READ_BUFFER_SIZE),
+                TransferMode::Send => READ_BUFFER_SIZE,
+            };
+            if allowance == 0 {
+                self.is_throttled = true;
+                return Ok(false);
+            }
+            let num_bytes = match self.stream.try_read(&mut buffer[..allowance]) {
+                Ok(Some(0)) => return Ok(true),
+                Ok(Some(num_bytes)) => num_bytes,
+                Ok(None) => return Ok(false),
+                Err(e) => return Err(format!("Error reading stream: {}", e)),
+            };
+            if self.mode == TransferMode::Receive {
+                self.buckets.consume(num_bytes);
+            }
+            if self.on_bytes(&buffer[..num_bytes])? {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Reads the next chunk of the file being sent into the output buffer.
+    /// Returns false if there is nothing more to send for now, in which case
+    /// the stream may have been marked as throttled.
+    fn fill_outbuf(&mut self) -> Result<bool, String> {
+        let (file, position, size) = match self.state {
+            State::Sending {
+                ref mut file,
+                ref mut position,
+                size,
+            } => (file, position, size),
+            _ => return Ok(false),
+        };
+        if *position == size {
+            return Ok(false);
+        }
+
+        let max = cmp::min(READ_BUFFER_SIZE as u64, size - *position) as usize;
+        let allowance = self.buckets.allowance(max);
+        if allowance == 0 {
+            self.is_throttled = true;
+            return Ok(false);
+        }
+
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        let num_bytes = match file.read(&mut buffer[..allowance]) {
+            Ok(0) => return Err(format!("File ended before its {} bytes were sent", size)),
+            Ok(num_bytes) => num_bytes,
+            Err(err) => return Err(format!("Error reading file: {}", err)),
+        };
+        self.buckets.consume(num_bytes);
+        *position += num_bytes as u64;
+        self.outbuf.extend_from_slice(&buffer[..num_bytes]);
+        Ok(true)
+    }
+
+    /// The stream is ready to be written to.
+    fn on_writable(&mut self) -> Result<(), String> {
+        loop {
+            if self.outbuf.is_empty() && !self.fill_outbuf()? {
+                return Ok(());
+            }
+            match self.stream.try_write(&self.outbuf) {
+                Ok(Some(num_bytes)) => {
+                    self.outbuf.drain(..num_bytes);
+                }
+                Ok(None) => return Ok(()),
+                Err(e) => return Err(format!("Error writing stream: {}", e)),
+            }
+        }
+    }
+
+    /// Returns the position up to which the file has been written to disk or
+    /// to the stream, if any.
+    fn position(&self) -> Option<u64> {
+        match self.state {
+            State::Receiving { position, .. } => Some(position),
+            State::Sending { position, .. } => Some(position - self.outbuf.len() as u64),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the whole file has been sent.
+    fn is_sent(&self) -> bool {
+        match self.state {
+            State::Sending { position, size, .. } => position == size && self.outbuf.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the transfer has been suspended to respect the rate
+    /// limits.
+    /// The stream should then not be registered for any events, and instead
+    /// be made to resume with `on_unthrottle()` a little later.
+    pub fn is_throttled(&self) -> bool {
+        self.is_throttled
+    }
+
+    /// Resumes a throttled stream.
+    pub fn on_unthrottle(&mut self) -> Intent {
+        self.is_throttled = false;
+        self.on_ready(mio::Ready::readable() | mio::Ready::writable())
+    }
+
+    /// Returns the events the stream is interested in.
+    fn intent(&self) -> Intent {
+        let mut event_set = mio::Ready::readable() | mio::Ready::hup() | mio::Ready::error();
+        let has_more_to_send = match self.state {
+            State::Sending { position, size, .. } => position < size,
+            _ => false,
+        };
+        if !self.outbuf.is_empty() || has_more_to_send {
+            event_set = event_set | mio::Ready::writable();
+        }
+        Intent::Continue(event_set)
+    }
+
+    /// The stream is ready to read, write, or both.
+    pub fn on_ready(&mut self, event_set: mio::Ready) -> Intent {
+        let position_before = self.position();
+        let intent = self.handle_ready(event_set);
+        let position_after = self.position();
+        if position_after != position_before {
+            if let Some(position) = position_after {
+                if let Err(err) = self.receiver.notify_progress(position) {
+                    error!("Cannot notify client of transfer progress: {}", err);
+                    return Intent::Done;
+                }
+            }
+        }
+        intent
+    }
+
+    fn handle_ready(&mut self, event_set: mio::Ready) -> Intent {
+        // Unlike packet streams, read whatever is left before handling a
+        // hang up, as the uploader closes the connection right after sending
+        // the end of the file.
+        if event_set.is_readable() {
+            match self.on_readable() {
+                Ok(true) => return Intent::Done,
+                Ok(false) => (),
+                Err(e) => {
+                    error!("Transfer input error: {}", e);
+                    return Intent::Done;
+                }
+            }
+        }
+        if self.is_sent() {
+            return Intent::Done;
+        }
+        // If we stopped reading early, the rest of the file is read once the
+        // stream is resumed, until the end of the stream is reached.
+        if event_set.is_error() || (event_set.is_hup() && !self.is_throttled) {
+            return Intent::Done;
+        }
+        if event_set.is_writable() {
+            if let Err(e) = self.on_writable() {
+                error!("Transfer output error: {}", e);
+                return Intent::Done;
+            }
+            if self.is_sent() {
+                return Intent::Done;
+            }
+        }
+
+        if !self.is_connected {
+            if let Err(err) = self.receiver.notify_open() {
+                error!("Cannot notify client that transfer is open: {}", err);
+                return Intent::Done;
+            }
+            self.is_connected = true;
+        }
+
+        self.intent()
+    }
+
+    /// Sends the given peer init message to the other end.
+    pub fn on_notify<V>(&mut self, payload: &V) -> Intent
+    where
+        V: WriteToPacket,
+    {
+        let mut packet = MutPacket::new();
+        if let Err(e) = packet.write_value(payload) {
+            error!("Error writing payload to packet: {}", e);
+            return Intent::Done;
+        }
+        self.outbuf.extend(packet.into_bytes());
+        self.intent()
+    }
+
+    /// Starts receiving the file, appending it to the given file from the
+    /// given offset onwards.
+    pub fn on_receive(&mut self, file: fs::File, offset: u64, size: u64) -> Intent {
+        match self.state {
+            State::WaitingForFile => (),
+            ref state => {
+                error!("Cannot start receiving file in state {:?}", state);
+                return Intent::Done;
+            }
+        }
+
+        if let Err(e) = self.outbuf.write_u64::<LittleEndian>(offset) {
+            error!("Error writing offset: {}", e);
+            return Intent::Done;
+        }
+        self.state = State::Receiving {
+            file: file,
+            position: offset,
+            size: size,
+        };
+        if offset == size {
+            return Intent::Done;
+        }
+
+        // The uploader should wait for the offset before sending anything,
+        // but handle early bytes anyway.
+        let inbuf = mem::replace(&mut self.inbuf, Vec::new());
+        if !inbuf.is_empty() {
+            match self.on_bytes(&inbuf) {
+                Ok(true) => return Intent::Done,
+                Ok(false) => (),
+                Err(e) => {
+                    error!("Transfer input error: {}", e);
+                    return Intent::Done;
+                }
+            }
+        }
+
+        self.intent()
+    }
+
+    /// Starts sending the given file, of the given size, as the transfer
+    /// identified by the given token.
+    pub fn on_send(&mut self, file: fs::File, token: u32, size: u64) -> Intent {
+        match self.state {
+            State::WaitingToSend => (),
+            ref state => {
+                error!("Cannot start sending file in state {:?}", state);
+                return Intent::Done;
+            }
+        }
+
+        if let Err(e) = self.outbuf.write_u32::<LittleEndian>(token) {
+            error!("Error writing token: {}", e);
+            return Intent::Done;
+        }
+        self.state = State::ReadingOffset {
+            file: file,
+            size: size,
+        };
+        self.intent()
+    }
+}
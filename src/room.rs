@@ -1,7 +1,13 @@
 use std::collections;
 use std::error;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
 use std::mem;
+use std::time;
+
+use rustc_serialize::json;
 
 use proto::{server, User};
 
@@ -35,8 +41,25 @@ pub enum Visibility {
 /// This structure contains a chat room message.
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct Message {
+    /// The position of the message in the room's history: the first message
+    /// has id 0, the second id 1, and so on.
+    pub id: u64,
+    /// When the message was received, in seconds since the epoch.
+    pub timestamp: u64,
     pub user_name: String,
     pub message: String,
+    /// True if the message mentions the user or matches one of their
+    /// highlight rules.
+    pub is_highlighted: bool,
+}
+
+/// This structure contains the most recent messages said in a chat room.
+#[derive(Debug)]
+struct History {
+    /// The messages, in chronological order.
+    messages: collections::VecDeque<Message>,
+    /// The id of the next message.
+    next_id: u64,
 }
 
 /// This structure contains the last known information about a chat room.
@@ -59,10 +82,12 @@ pub struct Room {
     pub operators: collections::HashSet<String>,
     /// The names of the room's members.
     pub members: collections::HashSet<String>,
-    /// The messages sent to this chat room, in chronological order.
-    pub messages: Vec<Message>,
     /// The tickers displayed in this room.
     pub tickers: Vec<(String, String)>,
+    /// The number of messages received since the controller last read the
+    /// room, and how many of them are highlighted.
+    pub num_unread: usize,
+    pub num_mentions: usize,
 }
 
 impl Room {
@@ -76,8 +101,9 @@ impl Room {
             owner: None,
             operators: collections::HashSet::new(),
             members: collections::HashSet::new(),
-            messages: Vec::new(),
             tickers: Vec::new(),
+            num_unread: 0,
+            num_mentions: 0,
         }
     }
 }
@@ -112,22 +138,177 @@ impl error::Error for Error {
     }
 }
 
+/// The error returned when the auto-join list cannot be loaded or saved.
+#[derive(Debug)]
+pub enum AutoJoinError {
+    IOError(io::Error),
+    JSONDecoderError(json::DecoderError),
+    JSONEncoderError(json::EncoderError),
+}
+
+impl fmt::Display for AutoJoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AutoJoinError::IOError(ref err) => write!(f, "IOError: {}", err),
+            AutoJoinError::JSONDecoderError(ref err) => write!(f, "JSONDecoderError: {}", err),
+            AutoJoinError::JSONEncoderError(ref err) => write!(f, "JSONEncoderError: {}", err),
+        }
+    }
+}
+
+impl error::Error for AutoJoinError {
+    fn description(&self) -> &str {
+        match *self {
+            AutoJoinError::IOError(_) => "IOError",
+            AutoJoinError::JSONDecoderError(_) => "JSONDecoderError",
+            AutoJoinError::JSONEncoderError(_) => "JSONEncoderError",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            AutoJoinError::IOError(ref err) => Some(err),
+            AutoJoinError::JSONDecoderError(ref err) => Some(err),
+            AutoJoinError::JSONEncoderError(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for AutoJoinError {
+    fn from(err: io::Error) -> Self {
+        AutoJoinError::IOError(err)
+    }
+}
+
+impl From<json::DecoderError> for AutoJoinError {
+    fn from(err: json::DecoderError) -> Self {
+        AutoJoinError::JSONDecoderError(err)
+    }
+}
+
+impl From<json::EncoderError> for AutoJoinError {
+    fn from(err: json::EncoderError) -> Self {
+        AutoJoinError::JSONEncoderError(err)
+    }
+}
+
+/// Contains the names of the rooms joined automatically upon logging in,
+/// which are the rooms the controller last asked to be in.
+/// Every change to the list is persisted to disk, so that it survives
+/// restarts.
+#[derive(Debug)]
+pub struct AutoJoinList {
+    room_names: collections::BTreeSet<String>,
+    /// The path of the file in which the list is persisted.
+    file_path: String,
+}
+
+impl AutoJoinList {
+    /// Creates a list of the given rooms, persisted to the given file.
+    pub fn new(file_path: &str, room_names: &[&str]) -> Self {
+        AutoJoinList {
+            room_names: room_names
+                .iter()
+                .map(|room_name| room_name.to_string())
+                .collect(),
+            file_path: file_path.to_string(),
+        }
+    }
+
+    /// Loads the list persisted in the given file.
+    /// If the file does not exist, returns a list of the given rooms.
+    pub fn load(file_path: &str, room_names: &[&str]) -> Result<Self, AutoJoinError> {
+        let mut list = Self::new(file_path, room_names);
+
+        let mut file = match fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(list),
+            Err(err) => return Err(AutoJoinError::from(err)),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        list.room_names = json::decode(&contents)?;
+        Ok(list)
+    }
+
+    /// Writes the list to disk.
+    /// The list is first written to a temporary file, which then replaces
+    /// the previous version, so that a crash never leaves a truncated file.
+    fn save(&self) -> Result<(), AutoJoinError> {
+        let encoded = json::encode(&self.room_names)?;
+
+        let tmp_path = format!("{}.tmp", self.file_path);
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(encoded.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.file_path)?;
+        Ok(())
+    }
+
+    /// Persists the list, logging any error that arises.
+    fn persist(&self) {
+        if let Err(err) = self.save() {
+            error!(
+                "Cannot save auto-join rooms to {:?}: {}",
+                self.file_path, err
+            );
+        }
+    }
+
+    /// Returns the names of the rooms to join, in alphabetical order.
+    pub fn room_names(&self) -> Vec<String> {
+        self.room_names.iter().cloned().collect()
+    }
+
+    /// Adds the given room to the list.
+    pub fn insert(&mut self, room_name: &str) {
+        if self.room_names.insert(room_name.to_string()) {
+            self.persist();
+        }
+    }
+
+    /// Removes the given room from the list.
+    pub fn remove(&mut self, room_name: &str) {
+        if self.room_names.remove(room_name) {
+            self.persist();
+        }
+    }
+}
+
 /// Contains the mapping from room names to room data and provides a clean
 /// interface to interact with it.
 #[derive(Debug)]
 pub struct RoomMap {
     /// The actual map from room names to room data.
     map: collections::HashMap<String, Room>,
+    /// The message history of each room, kept apart from the room data so
+    /// that it is only sent to the controller upon request.
+    histories: collections::HashMap<String, History>,
+    /// The maximum number of messages kept for each room.
+    max_messages: usize,
 }
 
 impl RoomMap {
-    /// Creates an empty mapping.
-    pub fn new() -> Self {
+    /// Creates an empty mapping, keeping at most the given number of
+    /// messages for each room.
+    pub fn new(max_messages: usize) -> Self {
         RoomMap {
             map: collections::HashMap::new(),
+            histories: collections::HashMap::new(),
+            max_messages: max_messages,
         }
     }
 
+    /// Looks up the given room name in the map, returning an immutable
+    /// reference to the associated data if found.
+    pub fn get(&self, room_name: &str) -> Option<&Room> {
+        self.map.get(room_name)
+    }
+
     /// Looks up the given room name in the map, returning an immutable
     /// reference to the associated data if found, or an error if not found.
     fn get_strict(&self, room_name: &str) -> Result<&Room, Error> {
@@ -207,6 +388,18 @@ impl RoomMap {
         rooms
     }
 
+    /// Returns the list of (room name, room data) representing the rooms the
+    /// user is a member of.
+    pub fn get_joined_rooms(&self) -> Vec<(String, Room)> {
+        let mut rooms = Vec::new();
+        for (room_name, room) in self.map.iter() {
+            if let Membership::Member = room.membership {
+                rooms.push((room_name.clone(), room.clone()));
+            }
+        }
+        rooms
+    }
+
     /// Records that we are now trying to join the given room.
     /// If the room is not found, or if its membership is not `NonMember`,
     /// returns an error.
@@ -266,6 +459,22 @@ impl RoomMap {
         Ok(())
     }
 
+    /// Records that we are no longer a member of any room, as happens when
+    /// the connection to the server is lost. Returns the names of the rooms
+    /// we were a member of, or were joining or leaving.
+    pub fn leave_all(&mut self) -> Vec<String> {
+        let mut room_names = Vec::new();
+        for (room_name, room) in self.map.iter_mut() {
+            if let Membership::NonMember = room.membership {
+                continue;
+            }
+            room.membership = Membership::NonMember;
+            room.members.clear();
+            room_names.push(room_name.clone());
+        }
+        room_names
+    }
+
     /// Records that we are now trying to leave the given room.
     /// If the room is not found, or if its membership status is not `Member`,
     /// returns an error.
@@ -302,13 +511,129 @@ impl RoomMap {
         Ok(())
     }
 
-    /// Saves the given message as the last one in the given room.
-    pub fn add_message(&mut self, room_name: &str, message: Message) -> Result<(), Error> {
+    /// Saves the given message, said by the given user, as the last one in
+    /// the given room, forgetting the oldest message if there are too many.
+    /// Unless the message is our own, it counts as unread, and as a mention
+    /// if highlighted.
+    /// Returns the saved message.
+    pub fn add_message(
+        &mut self,
+        room_name: &str,
+        user_name: String,
+        message: String,
+        is_own: bool,
+        is_highlighted: bool,
+    ) -> Result<Message, Error> {
+        {
+            let room = try!(self.get_mut_strict(room_name));
+            if !is_own {
+                room.num_unread += 1;
+                if is_highlighted {
+                    room.num_mentions += 1;
+                }
+            }
+        }
+
+        let timestamp = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0,
+        };
+        let history = self
+            .histories
+            .entry(room_name.to_string())
+            .or_insert_with(|| History {
+                messages: collections::VecDeque::new(),
+                next_id: 0,
+            });
+
+        let message = Message {
+            id: history.next_id,
+            timestamp: timestamp,
+            user_name: user_name,
+            message: message,
+            is_highlighted: is_highlighted,
+        };
+        history.next_id += 1;
+        history.messages.push_back(message.clone());
+        while history.messages.len() > self.max_messages {
+            history.messages.pop_front();
+        }
+        Ok(message)
+    }
+
+    /// Records that the controller read all the messages in the given room.
+    pub fn mark_read(&mut self, room_name: &str) -> Result<(), Error> {
         let room = try!(self.get_mut_strict(room_name));
-        room.messages.push(message);
+        room.num_unread = 0;
+        room.num_mentions = 0;
         Ok(())
     }
 
+    /// Restores the given messages, in chronological order, as the history
+    /// of the given room, unless the room already has one.
+    pub fn restore_messages(
+        &mut self,
+        room_name: &str,
+        messages: Vec<Message>,
+    ) -> Result<(), Error> {
+        try!(self.get_strict(room_name));
+
+        if self.histories.contains_key(room_name) {
+            return Ok(());
+        }
+        let next_id = match messages.last() {
+            Some(message) => message.id + 1,
+            None => 0,
+        };
+        let mut messages: collections::VecDeque<Message> = messages.into_iter().collect();
+        while messages.len() > self.max_messages {
+            messages.pop_front();
+        }
+        self.histories.insert(
+            room_name.to_string(),
+            History {
+                messages: messages,
+                next_id: next_id,
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns at most `limit` of the messages saved for the given room, in
+    /// chronological order, whose ids are lower than `before` and higher than
+    /// `after` when given.
+    /// If only `after` is given, returns the oldest such messages, otherwise
+    /// returns the most recent ones.
+    pub fn get_messages(
+        &self,
+        room_name: &str,
+        before: Option<u64>,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<Message>, Error> {
+        try!(self.get_strict(room_name));
+
+        let history = match self.histories.get(room_name) {
+            Some(history) => history,
+            None => return Ok(Vec::new()),
+        };
+        let matching: Vec<&Message> = history
+            .messages
+            .iter()
+            .filter(|message| {
+                before.map_or(true, |before| message.id < before)
+                    && after.map_or(true, |after| message.id > after)
+            })
+            .collect();
+
+        let page = if before.is_none() && after.is_some() {
+            &matching[..limit.min(matching.len())]
+        } else {
+            &matching[matching.len().saturating_sub(limit)..]
+        };
+        Ok(page.iter().map(|&message| message.clone()).collect())
+    }
+
     /// Inserts the given user in the given room's set of members.
     /// Returns an error if the room is not found.
     pub fn insert_member(&mut self, room_name: &str, user_name: String) -> Result<(), Error> {
@@ -0,0 +1,345 @@
+use std::collections;
+
+/// Splits the given text into lowercase words, on anything that is neither
+/// a letter nor a digit.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// This structure contains a parsed search query.
+///
+/// Queries are made of words separated by whitespace. Matching paths must
+/// contain every plain word. Words prefixed with `*` match any word that
+/// contains them, and words prefixed with `-` exclude paths that contain
+/// them.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Query {
+    /// The words that must appear as is.
+    pub words: Vec<String>,
+    /// The words that must appear as part of a longer word, or as is.
+    pub partial_words: Vec<String>,
+    /// The words that must not appear.
+    pub excluded_words: Vec<String>,
+}
+
+impl Query {
+    /// Parses the given query.
+    pub fn parse(query: &str) -> Self {
+        let mut parsed = Query::default();
+        for term in query.split_whitespace() {
+            if term.starts_with('-') {
+                parsed.excluded_words.extend(tokenize(&term[1..]));
+            } else if term.starts_with('*') {
+                parsed.partial_words.extend(tokenize(&term[1..]));
+            } else {
+                parsed.words.extend(tokenize(term));
+            }
+        }
+        parsed
+    }
+
+    /// Returns true if the query cannot match anything, having nothing to
+    /// look for.
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.partial_words.is_empty()
+    }
+}
+
+/// An inverted index over paths, mapping each word to the paths containing
+/// it, so that queries only look at the paths that might match.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    /// The indexed paths, by id. The ids of removed paths are reused.
+    paths: Vec<Option<String>>,
+    /// The ids of the indexed paths.
+    ids: collections::HashMap<String, u32>,
+    /// The ids that were freed by removing paths.
+    free_ids: Vec<u32>,
+    /// The ids of the paths containing each word.
+    postings: collections::HashMap<String, collections::BTreeSet<u32>>,
+}
+
+impl SearchIndex {
+    /// Creates an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the given path to the index, if it is not there already.
+    pub fn insert(&mut self, path: &str) {
+        if self.ids.contains_key(path) {
+            return;
+        }
+
+        let id = match self.free_ids.pop() {
+            Some(id) => {
+                self.paths[id as usize] = Some(path.to_string());
+                id
+            }
+            None => {
+                self.paths.push(Some(path.to_string()));
+                (self.paths.len() - 1) as u32
+            }
+        };
+        self.ids.insert(path.to_string(), id);
+
+        for word in tokenize(path) {
+            self.postings
+                .entry(word)
+                .or_insert_with(collections::BTreeSet::new)
+                .insert(id);
+        }
+    }
+
+    /// Removes the given path from the index, if it is there.
+    pub fn remove(&mut self, path: &str) {
+        let id = match self.ids.remove(path) {
+            Some(id) => id,
+            None => return,
+        };
+        self.paths[id as usize] = None;
+        self.free_ids.push(id);
+
+        for word in tokenize(path) {
+            let is_empty = match self.postings.get_mut(&word) {
+                Some(ids) => {
+                    ids.remove(&id);
+                    ids.is_empty()
+                }
+                None => false,
+            };
+            if is_empty {
+                self.postings.remove(&word);
+            }
+        }
+    }
+
+    /// Returns the ids of the paths containing each word that contains the
+    /// given word.
+    fn partial_postings(&self, partial_word: &str) -> Vec<&collections::BTreeSet<u32>> {
+        self.postings
+            .iter()
+            .filter(|&(word, _)| word.contains(partial_word))
+            .map(|(_, ids)| ids)
+            .collect()
+    }
+
+    /// Returns the paths matching the given query, in no particular order.
+    pub fn search(&self, query: &Query) -> Vec<&str> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        // Start from the rarest word, so as to look at as few paths as
+        // possible.
+        let mut word_postings = Vec::new();
+        for word in query.words.iter() {
+            match self.postings.get(word) {
+                Some(ids) => word_postings.push(ids),
+                None => return Vec::new(),
+            }
+        }
+        word_postings.sort_by_key(|ids| ids.len());
+
+        let partial_postings: Vec<Vec<&collections::BTreeSet<u32>>> = query
+            .partial_words
+            .iter()
+            .map(|partial_word| self.partial_postings(partial_word))
+            .collect();
+
+        let excluded_postings: Vec<&collections::BTreeSet<u32>> = query
+            .excluded_words
+            .iter()
+            .filter_map(|word| self.postings.get(word))
+            .collect();
+
+        // Only without any plain word to start from is it worth gathering
+        // all the paths matching a partial word.
+        let partial_candidates;
+        let candidates = match word_postings.first() {
+            Some(ids) => *ids,
+            None => {
+                partial_candidates = partial_postings[0]
+                    .iter()
+                    .flat_map(|ids| ids.iter().cloned())
+                    .collect::<collections::BTreeSet<u32>>();
+                &partial_candidates
+            }
+        };
+
+        candidates
+            .iter()
+            .filter(|id| {
+                word_postings.iter().all(|ids| ids.contains(id))
+                    && partial_postings
+                        .iter()
+                        .all(|postings| postings.iter().any(|ids| ids.contains(id)))
+                    && !excluded_postings.iter().any(|ids| ids.contains(id))
+            })
+            .filter_map(|&id| self.paths[id as usize].as_ref())
+            .map(|path| &**path)
+            .collect()
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{Query, SearchIndex};
+
+    fn build_index(paths: &[&str]) -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for path in paths {
+            index.insert(path);
+        }
+        index
+    }
+
+    fn search(index: &SearchIndex, query: &str) -> Vec<String> {
+        let mut paths: Vec<String> = index
+            .search(&Query::parse(query))
+            .into_iter()
+            .map(|path| path.to_string())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn parse_query() {
+        assert_eq!(
+            Query::parse("Foo *bar -baz.qux"),
+            Query {
+                words: vec!["foo".to_string()],
+                partial_words: vec!["bar".to_string()],
+                excluded_words: vec!["baz".to_string(), "qux".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn search_all_words() {
+        let index = build_index(&[
+            "music\\Artist\\Album\\01 Song.mp3",
+            "music\\Artist\\Other\\02 Song.flac",
+            "music\\Someone\\Album\\03 Tune.mp3",
+        ]);
+        assert_eq!(
+            search(&index, "artist song mp3"),
+            vec!["music\\Artist\\Album\\01 Song.mp3"]
+        );
+        assert_eq!(search(&index, "artist nothing"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn search_excluded_words() {
+        let index = build_index(&[
+            "music\\Artist\\Album\\01 Song.mp3",
+            "music\\Artist\\Live\\01 Song.mp3",
+        ]);
+        assert_eq!(
+            search(&index, "artist song -live"),
+            vec!["music\\Artist\\Album\\01 Song.mp3"]
+        );
+        assert_eq!(search(&index, "-live"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn search_partial_words() {
+        let index = build_index(&[
+            "music\\Artist\\Album\\01 Songbird.mp3",
+            "music\\Artist\\Album\\02 Mockingbird.mp3",
+            "music\\Artist\\Album\\03 Bird.mp3",
+            "music\\Artist\\Album\\04 Birdsong.mp3",
+        ]);
+        assert_eq!(
+            search(&index, "*bird"),
+            vec![
+                "music\\Artist\\Album\\01 Songbird.mp3",
+                "music\\Artist\\Album\\02 Mockingbird.mp3",
+                "music\\Artist\\Album\\03 Bird.mp3",
+                "music\\Artist\\Album\\04 Birdsong.mp3",
+            ]
+        );
+        assert_eq!(
+            search(&index, "bird"),
+            vec!["music\\Artist\\Album\\03 Bird.mp3"]
+        );
+        assert_eq!(
+            search(&index, "*bird *song -songbird"),
+            vec!["music\\Artist\\Album\\04 Birdsong.mp3"]
+        );
+    }
+
+    #[test]
+    fn search_after_remove() {
+        let mut index = build_index(&["a\\song.mp3", "b\\song.mp3"]);
+        index.remove("a\\song.mp3");
+        assert_eq!(search(&index, "song"), vec!["b\\song.mp3"]);
+        index.insert("c\\song.mp3");
+        assert_eq!(search(&index, "song"), vec!["b\\song.mp3", "c\\song.mp3"]);
+    }
+
+    /// Measures query latency over a large index. Run it with:
+    ///
+    ///     cargo test --release bench_search -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_search() {
+        const NUM_ARTISTS: usize = 1000;
+        const NUM_ALBUMS: usize = 20;
+        const NUM_TRACKS: usize = 15;
+        const NUM_QUERIES: u32 = 1000;
+
+        let mut index = SearchIndex::new();
+        let start = time::Instant::now();
+        for artist in 0..NUM_ARTISTS {
+            for album in 0..NUM_ALBUMS {
+                for track in 0..NUM_TRACKS {
+                    index.insert(&format!(
+                        "music\\Artist {}\\Album {} ({})\\{:02} Track {}.mp3",
+                        artist,
+                        album,
+                        1970 + album,
+                        track,
+                        artist * track
+                    ));
+                }
+            }
+        }
+        println!(
+            "Indexed {} paths in {:?}",
+            NUM_ARTISTS * NUM_ALBUMS * NUM_TRACKS,
+            start.elapsed()
+        );
+
+        let queries = [
+            "artist 42 album 7",
+            "1975 mp3 -flac",
+            "*rtist 99 track",
+            "nothing matches this",
+        ];
+        for query in queries.iter() {
+            let query = Query::parse(query);
+            let start = time::Instant::now();
+            let mut num_results = 0;
+            for _ in 0..NUM_QUERIES {
+                num_results = index.search(&query).len();
+            }
+            println!(
+                "{:?}: {} results in {:?} per query",
+                query,
+                num_results,
+                start.elapsed() / NUM_QUERIES
+            );
+        }
+    }
+}
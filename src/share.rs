@@ -0,0 +1,856 @@
+use std::collections;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time;
+
+use notify;
+use notify::Watcher as NotifyWatcher;
+use rustc_serialize::json;
+
+use audio;
+use proto::peer;
+use search;
+
+/// This enumeration is the list of possible visibilities for a shared
+/// directory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Visibility {
+    /// Everyone can see the directory.
+    Public,
+    /// Only our buddies can see the directory.
+    BuddyOnly,
+    /// Nobody can see the directory. Useful to hide a subdirectory of a
+    /// shared directory.
+    Excluded,
+}
+
+impl Visibility {
+    /// Returns true if a user can see what has this visibility, given whether
+    /// they are one of our buddies.
+    fn is_visible_to(&self, is_buddy: bool) -> bool {
+        match *self {
+            Visibility::Public => true,
+            Visibility::BuddyOnly => is_buddy,
+            Visibility::Excluded => false,
+        }
+    }
+}
+
+/// This structure contains what we know about a shared file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SharedFile {
+    /// The path of the file on the local filesystem.
+    pub local_path: path::PathBuf,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The audio properties of the file, if it is an audio file.
+    pub audio: Option<audio::AudioInfo>,
+    /// Who can see the file.
+    pub visibility: Visibility,
+}
+
+impl SharedFile {
+    /// Returns the description of the file sent to other users, under the
+    /// given name.
+    fn to_peer_file(&self, name: String) -> peer::File {
+        peer::File {
+            name: name,
+            size: self.size,
+            extension: String::new(),
+            attributes: match self.audio {
+                Some(ref audio) => audio.to_attributes(),
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
+/// Groups the given shared files by folder, as sent in shared file lists.
+fn group_by_folder<'a, I>(files: I) -> Vec<peer::Folder>
+where
+    I: Iterator<Item = (&'a String, &'a SharedFile)>,
+{
+    let mut folders: Vec<peer::Folder> = Vec::new();
+    let mut folder_indices = collections::HashMap::new();
+    for (remote_path, shared_file) in files {
+        let (folder_name, file_name) = split_remote_path(remote_path);
+        let index = *folder_indices.entry(folder_name).or_insert_with(|| {
+            folders.push(peer::Folder {
+                name: folder_name.to_string(),
+                files: Vec::new(),
+            });
+            folders.len() - 1
+        });
+        folders[index]
+            .files
+            .push(shared_file.to_peer_file(file_name.to_string()));
+    }
+    folders
+}
+
+/// Splits the given remote path into the path of its folder and the name of
+/// the file.
+fn split_remote_path(remote_path: &str) -> (&str, &str) {
+    match remote_path.rfind('\\') {
+        Some(index) => (&remote_path[..index], &remote_path[index + 1..]),
+        None => ("", remote_path),
+    }
+}
+
+/*=======*
+ * CACHE *
+ *=======*/
+
+/// This structure contains what we knew about a file in a shared directory
+/// as of the last scan.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+struct CachedFile {
+    /// The name of the file.
+    name: String,
+    /// The size of the file in bytes.
+    size: u64,
+    /// The modification time of the file, in milliseconds since the epoch.
+    mtime: u64,
+    /// The audio properties of the file, if it is an audio file.
+    audio: Option<audio::AudioInfo>,
+}
+
+/// This structure contains what we knew about a shared directory as of the
+/// last scan.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+struct CachedDir {
+    /// The files in the directory.
+    files: Vec<CachedFile>,
+    /// The names of the subdirectories.
+    subdirs: Vec<String>,
+}
+
+/// The listings of the shared directories, keyed by local path.
+type DirMap = collections::HashMap<String, CachedDir>;
+
+/// Returns the modification time in the given metadata, in milliseconds
+/// since the epoch.
+fn mtime_millis(metadata: &fs::Metadata) -> io::Result<u64> {
+    let duration = match metadata.modified()?.duration_since(time::UNIX_EPOCH) {
+        Ok(duration) => duration,
+        // Anything older than the epoch is as good as the epoch.
+        Err(_) => return Ok(0),
+    };
+    Ok(duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64)
+}
+
+/// Lists the contents of the given directory. The audio properties of files
+/// that have the same size and modification time as in the given previous
+/// listing are not read again.
+fn read_dir(dir_path: &path::Path, old_dir: Option<&CachedDir>) -> io::Result<CachedDir> {
+    let mut dir = CachedDir {
+        files: Vec::new(),
+        subdirs: Vec::new(),
+    };
+
+    for entry_result in fs::read_dir(dir_path)? {
+        let entry = match entry_result {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Cannot read entry in {:?}: {}", dir_path, err);
+                continue;
+            }
+        };
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                warn!("Cannot stat {:?}: {}", entry.path(), err);
+                continue;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if metadata.is_dir() {
+            dir.subdirs.push(name);
+        } else if metadata.is_file() {
+            let size = metadata.len();
+            let mtime = mtime_millis(&metadata)?;
+            let old_file = old_dir.and_then(|old_dir| {
+                old_dir
+                    .files
+                    .iter()
+                    .find(|file| file.name == name && file.size == size && file.mtime == mtime)
+            });
+            let audio = match old_file {
+                Some(old_file) => old_file.audio.clone(),
+                None => match audio::read_info(&entry.path()) {
+                    Ok(audio) => audio,
+                    Err(err) => {
+                        warn!(
+                            "Cannot read audio properties of {:?}: {}",
+                            entry.path(),
+                            err
+                        );
+                        None
+                    }
+                },
+            };
+            dir.files.push(CachedFile {
+                name: name,
+                size: size,
+                mtime: mtime,
+                audio: audio,
+            });
+        }
+    }
+    Ok(dir)
+}
+
+/*=======*
+ * ERROR *
+ *=======*/
+
+/// The error returned by ShareMap functions.
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    JSONEncoderError(json::EncoderError),
+    JSONDecoderError(json::DecoderError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IOError(ref err) => write!(f, "IOError: {}", err),
+            Error::JSONEncoderError(ref err) => write!(f, "JSONEncoderError: {}", err),
+            Error::JSONDecoderError(ref err) => write!(f, "JSONDecoderError: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::IOError(_) => "IOError",
+            Error::JSONEncoderError(_) => "JSONEncoderError",
+            Error::JSONDecoderError(_) => "JSONDecoderError",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::IOError(ref err) => Some(err),
+            Error::JSONEncoderError(ref err) => Some(err),
+            Error::JSONDecoderError(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IOError(err)
+    }
+}
+
+impl From<json::EncoderError> for Error {
+    fn from(err: json::EncoderError) -> Self {
+        Error::JSONEncoderError(err)
+    }
+}
+
+impl From<json::DecoderError> for Error {
+    fn from(err: json::DecoderError) -> Self {
+        Error::JSONDecoderError(err)
+    }
+}
+
+/*===========*
+ * SHARE MAP *
+ *===========*/
+
+/// Returns an owned copy of the given configured shared directories.
+fn to_owned_dirs(shared_dirs: &[(&str, Visibility)]) -> Vec<(String, Visibility)> {
+    shared_dirs
+        .iter()
+        .map(|&(shared_dir, visibility)| (shared_dir.to_string(), visibility))
+        .collect()
+}
+
+/// Contains the index of shared files, keyed by the paths under which they
+/// are visible to other users, and provides a clean interface to interact
+/// with it.
+///
+/// Each shared directory is visible under its own name, and paths use
+/// backslashes as separators, as Soulseek clients expect.
+///
+/// Shared directories may contain other configured directories, whose
+/// visibility then overrides theirs.
+#[derive(Debug)]
+pub struct ShareMap {
+    /// The shared directories and their visibilities, as configured.
+    shared_dirs: Vec<(String, Visibility)>,
+    /// The listings of the shared directories and all their subdirectories,
+    /// as of the last scan.
+    dirs: DirMap,
+    /// The shared files, sorted by remote path.
+    files: collections::BTreeMap<String, SharedFile>,
+    /// The index of the remote paths of the shared files, for searching.
+    search_index: search::SearchIndex,
+    /// The phrases, in lowercase, that the server forbids in the paths of
+    /// the files we let other users see.
+    excluded_phrases: Vec<String>,
+}
+
+impl ShareMap {
+    /// Creates an empty index of the given shared directories, which have yet
+    /// to be scanned.
+    pub fn new(shared_dirs: &[(&str, Visibility)]) -> Self {
+        Self::from_dirs(to_owned_dirs(shared_dirs), DirMap::new())
+    }
+
+    /// Creates the index of the given shared directories, from the given
+    /// listings.
+    fn from_dirs(shared_dirs: Vec<(String, Visibility)>, dirs: DirMap) -> Self {
+        let mut map = ShareMap {
+            shared_dirs: shared_dirs,
+            dirs: dirs,
+            files: collections::BTreeMap::new(),
+            search_index: search::SearchIndex::new(),
+            excluded_phrases: Vec::new(),
+        };
+        for root in map.roots() {
+            let dir_path = path::Path::new(&root);
+            match dir_path.file_name() {
+                Some(share_name) => {
+                    let share_name = share_name.to_string_lossy().into_owned();
+                    map.index_dir(dir_path, &share_name);
+                }
+                None => error!("Cannot share {:?}: it has no name", root),
+            }
+        }
+        map
+    }
+
+    /// Returns the configured directory closest to the given path among those
+    /// that contain it, strictly so if `is_strict` is true.
+    fn closest_configured(
+        &self,
+        dir_path: &path::Path,
+        is_strict: bool,
+    ) -> Option<&(String, Visibility)> {
+        self.shared_dirs
+            .iter()
+            .filter(|&&(ref shared_dir, _)| {
+                dir_path.starts_with(shared_dir)
+                    && !(is_strict && dir_path == path::Path::new(shared_dir))
+            })
+            .max_by_key(|&&(ref shared_dir, _)| path::Path::new(shared_dir).components().count())
+    }
+
+    /// Returns who can see the contents of the given directory.
+    fn visibility(&self, dir_path: &path::Path) -> Visibility {
+        match self.closest_configured(dir_path, false) {
+            Some(&(_, visibility)) => visibility,
+            None => Visibility::Excluded,
+        }
+    }
+
+    /// Returns the directories that are visible under their own names, that
+    /// is the configured directories that are not excluded and are not
+    /// contained in a directory that is visible.
+    fn roots(&self) -> Vec<String> {
+        self.shared_dirs
+            .iter()
+            .filter(|&&(ref shared_dir, visibility)| {
+                visibility != Visibility::Excluded
+                    && match self.closest_configured(path::Path::new(shared_dir), true) {
+                        Some(&(_, parent_visibility)) => parent_visibility == Visibility::Excluded,
+                        None => true,
+                    }
+            })
+            .map(|&(ref shared_dir, _)| shared_dir.clone())
+            .collect()
+    }
+
+    /// Returns the directories that should not be scanned at all.
+    fn excluded_dirs(&self) -> Vec<path::PathBuf> {
+        self.shared_dirs
+            .iter()
+            .filter(|&&(_, visibility)| visibility == Visibility::Excluded)
+            .map(|&(ref shared_dir, _)| path::PathBuf::from(shared_dir))
+            .collect()
+    }
+
+    /// Recursively adds the files listed in the given directory to the index,
+    /// visible under the given remote path.
+    fn index_dir(&mut self, dir_path: &path::Path, remote_dir: &str) {
+        let dir = match self.dirs.get(&*dir_path.to_string_lossy()) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let visibility = self.visibility(dir_path);
+        for file in dir.files {
+            let remote_path = format!("{}\\{}", remote_dir, file.name);
+            self.search_index.insert(&remote_path);
+            self.files.insert(
+                remote_path,
+                SharedFile {
+                    local_path: dir_path.join(&file.name),
+                    size: file.size,
+                    audio: file.audio,
+                    visibility: visibility,
+                },
+            );
+        }
+        for subdir in dir.subdirs {
+            let remote_subdir = format!("{}\\{}", remote_dir, subdir);
+            self.index_dir(&dir_path.join(&subdir), &remote_subdir);
+        }
+    }
+
+    /// Loads the index of the given shared directories from the cache
+    /// persisted in the given file, as of the last scan.
+    /// If the file does not exist, returns an empty index.
+    pub fn load(file_path: &str, shared_dirs: &[(&str, Visibility)]) -> Result<Self, Error> {
+        let mut file = match fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::new(shared_dirs));
+            }
+            Err(err) => return Err(Error::from(err)),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let dirs = json::decode(&contents)?;
+        Ok(Self::from_dirs(to_owned_dirs(shared_dirs), dirs))
+    }
+
+    /// Writes the cache to the given file.
+    /// The cache is first written to a temporary file, which then replaces
+    /// the previous version, so that a crash never leaves a truncated file.
+    pub fn save(&self, file_path: &str) -> Result<(), Error> {
+        let encoded = json::encode(&self.dirs)?;
+
+        let tmp_path = format!("{}.tmp", file_path);
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(encoded.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, file_path)?;
+        Ok(())
+    }
+
+    /// Returns the phrases that the server forbids in the paths of the files
+    /// we let other users see.
+    pub fn excluded_phrases(&self) -> Vec<String> {
+        self.excluded_phrases.clone()
+    }
+
+    /// Sets the phrases that the server forbids in the paths of the files we
+    /// let other users see. Phrases are matched ignoring case.
+    pub fn set_excluded_phrases(&mut self, phrases: Vec<String>) {
+        self.excluded_phrases = phrases
+            .into_iter()
+            .map(|phrase| phrase.to_lowercase())
+            .filter(|phrase| !phrase.is_empty())
+            .collect();
+    }
+
+    /// Returns true if the user asking can see the given shared file, given
+    /// whether they are one of our buddies.
+    fn is_visible(&self, remote_path: &str, shared_file: &SharedFile, is_buddy: bool) -> bool {
+        if !shared_file.visibility.is_visible_to(is_buddy) {
+            return false;
+        }
+        if self.excluded_phrases.is_empty() {
+            return true;
+        }
+        let remote_path = remote_path.to_lowercase();
+        !self
+            .excluded_phrases
+            .iter()
+            .any(|phrase| remote_path.contains(&**phrase))
+    }
+
+    /// Looks up the file shared under the given remote path, provided that
+    /// the user asking can see it, given whether they are one of our buddies.
+    pub fn get(&self, remote_path: &str, is_buddy: bool) -> Option<&SharedFile> {
+        self.files
+            .get(remote_path)
+            .filter(|shared_file| self.is_visible(remote_path, shared_file, is_buddy))
+    }
+
+    /// Returns the shared files that the user asking can see, given whether
+    /// they are one of our buddies, sorted by remote path.
+    fn visible_files<'a>(
+        &'a self,
+        is_buddy: bool,
+    ) -> Box<Iterator<Item = (&'a String, &'a SharedFile)> + 'a> {
+        Box::new(
+            self.files
+                .iter()
+                .filter(move |&(remote_path, shared_file)| {
+                    self.is_visible(remote_path, shared_file, is_buddy)
+                }),
+        )
+    }
+
+    /// Returns the number of folders containing files that everyone can
+    /// see. Buddy-only files are left out, lest everyone learn how much is
+    /// hidden from them.
+    pub fn num_folders(&self) -> usize {
+        self.visible_files(false)
+            .map(|(remote_path, _)| split_remote_path(remote_path).0)
+            .collect::<collections::HashSet<_>>()
+            .len()
+    }
+
+    /// Returns the number of files that everyone can see.
+    pub fn num_files(&self) -> usize {
+        self.visible_files(false).count()
+    }
+
+    /// Returns all the shared files that the user asking can see, given
+    /// whether they are one of our buddies, grouped by folder, as sent in
+    /// shared file lists.
+    pub fn folders(&self, is_buddy: bool) -> Vec<peer::Folder> {
+        group_by_folder(self.visible_files(is_buddy))
+    }
+
+    /// Returns the files in the given folder and all its subfolders that the
+    /// user asking can see, given whether they are one of our buddies,
+    /// grouped by folder.
+    pub fn folder_contents(&self, folder_name: &str, is_buddy: bool) -> Vec<peer::Folder> {
+        let prefix = format!("{}\\", folder_name);
+        group_by_folder(
+            self.files
+                .range(prefix.clone()..)
+                .take_while(|&(remote_path, _)| remote_path.starts_with(&prefix))
+                .filter(|&(remote_path, shared_file)| {
+                    self.is_visible(remote_path, shared_file, is_buddy)
+                }),
+        )
+    }
+
+    /// Returns at most the given number of shared files matching the given
+    /// search query, among those that the user asking can see, given whether
+    /// they are one of our buddies.
+    /// See search::Query for the syntax of queries.
+    pub fn search(&self, query: &str, max_results: usize, is_buddy: bool) -> Vec<peer::File> {
+        self.search_index
+            .search(&search::Query::parse(query))
+            .into_iter()
+            .filter_map(|remote_path| {
+                self.files
+                    .get(remote_path)
+                    .filter(|shared_file| self.is_visible(remote_path, shared_file, is_buddy))
+                    .map(|shared_file| shared_file.to_peer_file(remote_path.to_string()))
+            })
+            .take(max_results)
+            .collect()
+    }
+
+    /// Returns the path under which the given local directory is visible to
+    /// other users, if it is shared.
+    fn remote_dir(&self, dir_path: &path::Path) -> Option<String> {
+        if self.visibility(dir_path) == Visibility::Excluded {
+            return None;
+        }
+        let root = self
+            .roots()
+            .into_iter()
+            .filter(|root| dir_path.starts_with(root))
+            .max_by_key(|root| path::Path::new(root).components().count())?;
+        let relative_path = dir_path.strip_prefix(&root).ok()?;
+
+        let mut remote_dir = path::Path::new(&root)
+            .file_name()?
+            .to_string_lossy()
+            .into_owned();
+        for component in relative_path.iter() {
+            remote_dir.push('\\');
+            remote_dir.push_str(&component.to_string_lossy());
+        }
+        Some(remote_dir)
+    }
+
+    /// Removes the listings of the given directory and all its
+    /// subdirectories, and moves them to the given map.
+    fn take_dirs(&mut self, dir_path: &path::Path, taken: &mut DirMap) {
+        let key = dir_path.to_string_lossy().into_owned();
+        let dir = match self.dirs.remove(&key) {
+            Some(dir) => dir,
+            None => return,
+        };
+        for subdir in dir.subdirs.iter() {
+            self.take_dirs(&dir_path.join(subdir), taken);
+        }
+        taken.insert(key, dir);
+    }
+
+    /// Lists the given shared directory and its subdirectories again, and
+    /// updates the index accordingly.
+    pub fn refresh_dir(&mut self, dir_path: &path::Path) {
+        let remote_dir = match self.remote_dir(dir_path) {
+            Some(remote_dir) => remote_dir,
+            None => {
+                warn!("Cannot refresh {:?}: it is not shared", dir_path);
+                return;
+            }
+        };
+
+        let mut old_dirs = DirMap::new();
+        self.take_dirs(dir_path, &mut old_dirs);
+
+        let mut dirs = DirMap::new();
+        let progress = Mutex::new(ScanProgress::default());
+        let excluded_dirs = self.excluded_dirs();
+        scan_dir(dir_path, &excluded_dirs, &old_dirs, &mut dirs, &progress);
+        self.dirs.extend(dirs);
+
+        let prefix = format!("{}\\", remote_dir);
+        let stale_paths: Vec<String> = self
+            .files
+            .range(prefix.clone()..)
+            .take_while(|&(remote_path, _)| remote_path.starts_with(&prefix))
+            .map(|(remote_path, _)| remote_path.clone())
+            .collect();
+        for remote_path in stale_paths {
+            self.files.remove(&remote_path);
+            self.search_index.remove(&remote_path);
+        }
+        self.index_dir(dir_path, &remote_dir);
+    }
+}
+
+/*======*
+ * SCAN *
+ *======*/
+
+/// This structure describes how far a scan has got.
+#[derive(Clone, Debug, Default)]
+pub struct ScanProgress {
+    /// The number of folders scanned so far.
+    pub num_folders: usize,
+    /// The number of files found so far.
+    pub num_files: usize,
+}
+
+/// A scan of the shared directories, running in the background.
+///
+/// Every directory is listed again, as files modified in place do not change
+/// the modification time of their directory, but only the files whose size
+/// or modification time changed since the previous scan are read again.
+pub struct Scan {
+    /// How far the scan has got, updated by the scanning thread.
+    progress: Arc<Mutex<ScanProgress>>,
+    /// Receives the new index once the scan is over.
+    result_rx: mpsc::Receiver<ShareMap>,
+}
+
+impl Scan {
+    /// Starts scanning the directories shared in the given index, reusing
+    /// what it knows of the files that did not change.
+    pub fn start(previous: &ShareMap) -> Self {
+        let shared_dirs = previous.shared_dirs.clone();
+        let roots = previous.roots();
+        let excluded_dirs = previous.excluded_dirs();
+        let old_dirs = previous.dirs.clone();
+        let progress = Arc::new(Mutex::new(ScanProgress::default()));
+        let (result_tx, result_rx) = mpsc::channel();
+
+        let thread_progress = progress.clone();
+        thread::spawn(move || {
+            let mut dirs = DirMap::new();
+            for root in roots.iter() {
+                scan_dir(
+                    path::Path::new(root),
+                    &excluded_dirs,
+                    &old_dirs,
+                    &mut dirs,
+                    &thread_progress,
+                );
+            }
+            // If the receiver is gone, nobody is interested in the result.
+            let _ = result_tx.send(ShareMap::from_dirs(shared_dirs, dirs));
+        });
+
+        Scan {
+            progress: progress,
+            result_rx: result_rx,
+        }
+    }
+
+    /// Returns how far the scan has got.
+    pub fn progress(&self) -> ScanProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Returns the new index if the scan is over.
+    pub fn try_finish(&self) -> Option<ShareMap> {
+        self.result_rx.try_recv().ok()
+    }
+}
+
+/// Recursively scans the given directory, reusing the audio properties of
+/// the files that did not change since the old listings, and adds the new
+/// listings to the given map. The given excluded directories are skipped.
+/// Errors are logged, and the offending directories skipped.
+fn scan_dir(
+    dir_path: &path::Path,
+    excluded_dirs: &[path::PathBuf],
+    old_dirs: &DirMap,
+    dirs: &mut DirMap,
+    progress: &Mutex<ScanProgress>,
+) {
+    if excluded_dirs
+        .iter()
+        .any(|excluded_dir| dir_path == excluded_dir)
+    {
+        return;
+    }
+
+    let key = dir_path.to_string_lossy().into_owned();
+    let dir = match read_dir(dir_path, old_dirs.get(&key)) {
+        Ok(dir) => dir,
+        Err(err) => {
+            warn!("Cannot scan {:?}: {}", dir_path, err);
+            return;
+        }
+    };
+
+    {
+        let mut progress = progress.lock().unwrap();
+        progress.num_folders += 1;
+        progress.num_files += dir.files.len();
+    }
+
+    for subdir in dir.subdirs.iter() {
+        scan_dir(
+            &dir_path.join(subdir),
+            excluded_dirs,
+            old_dirs,
+            dirs,
+            progress,
+        );
+    }
+    dirs.insert(key, dir);
+}
+
+/*=========*
+ * WATCHER *
+ *=========*/
+
+/// This structure describes the changes made to the shared directories.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// The directories whose listings changed.
+    pub dirs: collections::BTreeSet<path::PathBuf>,
+    /// Whether changes were lost, in which case everything should be scanned
+    /// again.
+    pub needs_rescan: bool,
+}
+
+/// Watches the shared directories for changes, as notified by the
+/// filesystem.
+pub struct Watcher {
+    /// Watches for as long as it lives.
+    _watcher: notify::RecommendedWatcher,
+    /// The shared directories, as configured and as watched.
+    roots: Vec<(path::PathBuf, path::PathBuf)>,
+    /// Receives the notifications, once the given delay has passed without
+    /// further changes to the same paths.
+    event_rx: mpsc::Receiver<notify::DebouncedEvent>,
+}
+
+impl Watcher {
+    /// Starts watching the directories shared in the given index.
+    /// Notifications are delivered after the given delay, so that a file
+    /// being written is not read again after every write.
+    pub fn start(shares: &ShareMap, delay: time::Duration) -> notify::Result<Self> {
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut watcher = notify::watcher(event_tx, delay)?;
+
+        let mut roots = Vec::new();
+        for shared_dir in shares.roots() {
+            // Notifications carry the paths that are actually watched, which
+            // is not necessarily how the directories were configured.
+            let watched_dir = match fs::canonicalize(&shared_dir) {
+                Ok(watched_dir) => watched_dir,
+                Err(err) => {
+                    warn!("Cannot watch {:?}: {}", shared_dir, err);
+                    continue;
+                }
+            };
+            if let Err(err) = watcher.watch(&watched_dir, notify::RecursiveMode::Recursive) {
+                warn!("Cannot watch {:?}: {}", shared_dir, err);
+                continue;
+            }
+            roots.push((path::PathBuf::from(shared_dir), watched_dir));
+        }
+
+        Ok(Watcher {
+            _watcher: watcher,
+            roots: roots,
+            event_rx: event_rx,
+        })
+    }
+
+    /// Returns the shared directory whose listing contains the given watched
+    /// path, if any.
+    fn dir_containing(&self, watched_path: &path::Path) -> Option<path::PathBuf> {
+        for &(ref root, ref watched_dir) in self.roots.iter() {
+            let relative_path = match watched_path.strip_prefix(watched_dir) {
+                Ok(relative_path) => relative_path,
+                Err(_) => continue,
+            };
+            // The shared directories themselves have no listing to be in.
+            return match relative_path.parent() {
+                Some(relative_dir) if relative_dir.as_os_str().is_empty() => Some(root.clone()),
+                Some(relative_dir) => Some(root.join(relative_dir)),
+                None => Some(root.clone()),
+            };
+        }
+        None
+    }
+
+    /// Returns the changes notified since the last call.
+    pub fn poll(&self) -> Changes {
+        let mut changes = Changes::default();
+        let mut paths = Vec::new();
+        while let Ok(event) = self.event_rx.try_recv() {
+            match event {
+                notify::DebouncedEvent::Create(path)
+                | notify::DebouncedEvent::Write(path)
+                | notify::DebouncedEvent::Remove(path) => paths.push(path),
+
+                notify::DebouncedEvent::Rename(from_path, to_path) => {
+                    paths.push(from_path);
+                    paths.push(to_path);
+                }
+
+                notify::DebouncedEvent::Rescan => changes.needs_rescan = true,
+
+                notify::DebouncedEvent::Error(err, path_opt) => {
+                    warn!("Error watching {:?}: {}", path_opt, err);
+                    changes.needs_rescan = true;
+                }
+
+                notify::DebouncedEvent::NoticeWrite(_)
+                | notify::DebouncedEvent::NoticeRemove(_)
+                | notify::DebouncedEvent::Chmod(_) => (),
+            }
+        }
+
+        for path in paths {
+            if let Some(dir_path) = self.dir_containing(&path) {
+                changes.dirs.insert(dir_path);
+            }
+        }
+        changes
+    }
+}
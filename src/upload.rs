@@ -0,0 +1,572 @@
+use std::collections;
+use std::error;
+use std::fmt;
+use std::path;
+use std::time;
+
+/// The priority of a user's uploads. Uploads to users with a higher priority
+/// always go before uploads to users with a lower priority.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    /// The user has bought privileges from the server.
+    Privileged,
+    /// The user is one of our buddies.
+    Buddy,
+    /// Everyone else.
+    Regular,
+}
+
+/// This enumeration is the list of possible states for an upload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    /// The upload is waiting for a free upload slot.
+    Queued,
+    /// The downloader has been asked whether it is ready to receive the
+    /// file, as the transfer identified by the given token.
+    Requested { token: u32, since: time::Instant },
+    /// The file is being sent.
+    Transferring,
+}
+
+/// This structure contains the last known information about an upload.
+#[derive(Clone, Debug)]
+pub struct Upload {
+    /// The name of the user to whom the file is uploaded.
+    pub user_name: String,
+    /// The path of the file, as shared with other users.
+    pub remote_path: String,
+    /// The path of the file on the local filesystem.
+    pub local_path: path::PathBuf,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The number of bytes sent so far.
+    pub bytes_sent: u64,
+    /// The state of the upload.
+    pub state: State,
+}
+
+impl Upload {
+    /// Creates a new queued upload of the given file to the given user.
+    pub fn new(
+        user_name: String,
+        remote_path: String,
+        local_path: path::PathBuf,
+        size: u64,
+    ) -> Self {
+        Upload {
+            user_name: user_name,
+            remote_path: remote_path,
+            local_path: local_path,
+            size: size,
+            bytes_sent: 0,
+            state: State::Queued,
+        }
+    }
+}
+
+/// The error returned by UploadQueue functions.
+#[derive(Debug)]
+pub enum Error {
+    /// No upload of the given file to the given user is known.
+    UploadNotFound(String, String),
+    /// An upload of the given file to the given user is already queued.
+    UploadAlreadyQueued(String, String),
+    /// The user already has the given maximum number of files queued.
+    TooManyFiles(usize),
+    /// Queueing the file would take the user over the given maximum number
+    /// of bytes queued.
+    TooManyBytes(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UploadNotFound(ref user_name, ref remote_path) => write!(
+                f,
+                "upload of {:?} to user {:?} not found",
+                remote_path, user_name
+            ),
+
+            Error::UploadAlreadyQueued(ref user_name, ref remote_path) => write!(
+                f,
+                "upload of {:?} to user {:?} already queued",
+                remote_path, user_name
+            ),
+
+            Error::TooManyFiles(max) => write!(f, "more than {} files queued", max),
+
+            Error::TooManyBytes(max) => write!(f, "more than {} bytes queued", max),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::UploadNotFound(_, _) => "upload not found",
+            Error::UploadAlreadyQueued(_, _) => "upload already queued",
+            Error::TooManyFiles(_) => "too many files queued",
+            Error::TooManyBytes(_) => "too many bytes queued",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
+}
+
+/// Contains the upload queue and the uploads in progress, and provides a
+/// clean interface to interact with them.
+///
+/// Each user has their own queue, served in the order in which files were
+/// queued. Users take turns in round-robin fashion, so that a user queueing
+/// many files cannot make everybody else wait. Users with a higher priority
+/// are always served first.
+#[derive(Debug)]
+pub struct UploadQueue {
+    /// The uploads waiting for a slot, for each user, in chronological order.
+    queued: collections::HashMap<String, collections::VecDeque<Upload>>,
+    /// The users with uploads waiting for a slot, in the order in which they
+    /// take turns.
+    turns: collections::VecDeque<String>,
+    /// The uploads that have been given a slot.
+    active: Vec<Upload>,
+    /// The maximum number of files each user may have queued.
+    max_files_per_user: usize,
+    /// The maximum number of bytes each user may have queued.
+    max_bytes_per_user: u64,
+}
+
+impl UploadQueue {
+    /// Creates an empty queue, with the given limits on what each user may
+    /// have queued.
+    pub fn new(max_files_per_user: usize, max_bytes_per_user: u64) -> Self {
+        UploadQueue {
+            queued: collections::HashMap::new(),
+            turns: collections::VecDeque::new(),
+            active: Vec::new(),
+            max_files_per_user: max_files_per_user,
+            max_bytes_per_user: max_bytes_per_user,
+        }
+    }
+
+    /// Looks up the upload of the given file to the given user, queued or in
+    /// progress.
+    pub fn get(&self, user_name: &str, remote_path: &str) -> Option<&Upload> {
+        let is_match =
+            |upload: &&Upload| upload.user_name == user_name && upload.remote_path == remote_path;
+        if let Some(upload) = self.active.iter().find(&is_match) {
+            return Some(upload);
+        }
+        match self.queued.get(user_name) {
+            Some(uploads) => uploads.iter().find(&is_match),
+            None => None,
+        }
+    }
+
+    fn get_active_mut(&mut self, user_name: &str, remote_path: &str) -> Result<&mut Upload, Error> {
+        match self
+            .active
+            .iter_mut()
+            .find(|upload| upload.user_name == user_name && upload.remote_path == remote_path)
+        {
+            Some(upload) => Ok(upload),
+            None => Err(Error::UploadNotFound(
+                user_name.to_string(),
+                remote_path.to_string(),
+            )),
+        }
+    }
+
+    /// Adds the given upload to the end of its user's queue, provided that
+    /// it does not take the user over the limits.
+    pub fn enqueue(&mut self, upload: Upload) -> Result<(), Error> {
+        if self.get(&upload.user_name, &upload.remote_path).is_some() {
+            return Err(Error::UploadAlreadyQueued(
+                upload.user_name,
+                upload.remote_path,
+            ));
+        }
+
+        let mut num_files = 1;
+        let mut num_bytes = upload.size;
+        {
+            let user_uploads = self
+                .active
+                .iter()
+                .filter(|active| active.user_name == upload.user_name)
+                .chain(
+                    self.queued
+                        .get(&upload.user_name)
+                        .into_iter()
+                        .flat_map(|q| q),
+                );
+            for user_upload in user_uploads {
+                num_files += 1;
+                num_bytes += user_upload.size;
+            }
+        }
+        if num_files > self.max_files_per_user {
+            return Err(Error::TooManyFiles(self.max_files_per_user));
+        }
+        if num_bytes > self.max_bytes_per_user {
+            return Err(Error::TooManyBytes(self.max_bytes_per_user));
+        }
+
+        if !self.queued.contains_key(&upload.user_name) {
+            self.turns.push_back(upload.user_name.clone());
+        }
+        self.queued
+            .entry(upload.user_name.clone())
+            .or_insert_with(collections::VecDeque::new)
+            .push_back(upload);
+        Ok(())
+    }
+
+    /// Returns the index in `turns` of the user whose turn it is, given how
+    /// many uploads were already taken from each user's queue.
+    fn next_turn<F>(
+        &self,
+        turns: &collections::VecDeque<String>,
+        taken: &collections::HashMap<String, usize>,
+        priority: &F,
+    ) -> Option<usize>
+    where
+        F: Fn(&str) -> Priority,
+    {
+        turns
+            .iter()
+            .enumerate()
+            .filter(|&(_, user_name)| {
+                let num_taken = taken.get(user_name).cloned().unwrap_or(0);
+                self.queued
+                    .get(user_name)
+                    .map_or(false, |uploads| num_taken < uploads.len())
+            })
+            .min_by_key(|&(index, user_name)| (priority(user_name), index))
+            .map(|(index, _)| index)
+    }
+
+    /// Returns the place of the upload of the given file to the given user in
+    /// the queue, given the priority of each user: 1 if it is next, 2 if it
+    /// comes after that, and so on. Uploads in progress are at place 0.
+    pub fn place_in_queue<F>(&self, user_name: &str, remote_path: &str, priority: F) -> Option<u32>
+    where
+        F: Fn(&str) -> Priority,
+    {
+        if self
+            .active
+            .iter()
+            .any(|upload| upload.user_name == user_name && upload.remote_path == remote_path)
+        {
+            return Some(0);
+        }
+
+        // Play out the turns until the upload is reached.
+        let mut turns = self.turns.clone();
+        let mut taken = collections::HashMap::new();
+        let mut place = 1;
+        while let Some(index) = self.next_turn(&turns, &taken, &priority) {
+            let turn_user_name = turns.remove(index).unwrap();
+            let num_taken = taken.entry(turn_user_name.clone()).or_insert(0);
+            let upload = &self.queued[&turn_user_name][*num_taken];
+            if upload.user_name == user_name && upload.remote_path == remote_path {
+                return Some(place);
+            }
+            *num_taken += 1;
+            place += 1;
+            turns.push_back(turn_user_name);
+        }
+        None
+    }
+
+    /// Gives a slot to the next upload in the queue, given the priority of
+    /// each user, and marks it as requested with the given token.
+    /// Returns the upload, if any was queued.
+    pub fn start_next<F>(&mut self, priority: F, token: u32) -> Option<Upload>
+    where
+        F: Fn(&str) -> Priority,
+    {
+        let index = match self.next_turn(&self.turns, &collections::HashMap::new(), &priority) {
+            Some(index) => index,
+            None => return None,
+        };
+        let user_name = self.turns.remove(index).unwrap();
+
+        let (mut upload, is_empty) = {
+            let uploads = self.queued.get_mut(&user_name).unwrap();
+            (uploads.pop_front().unwrap(), uploads.is_empty())
+        };
+        if is_empty {
+            self.queued.remove(&user_name);
+        } else {
+            self.turns.push_back(user_name);
+        }
+
+        upload.state = State::Requested {
+            token: token,
+            since: time::Instant::now(),
+        };
+        self.active.push(upload.clone());
+        Some(upload)
+    }
+
+    /// Returns the number of uploads waiting for a slot.
+    pub fn num_queued(&self) -> usize {
+        self.queued.values().map(|uploads| uploads.len()).sum()
+    }
+
+    /// Returns the number of uploads that have been given a slot.
+    pub fn num_active(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Returns the uploads that have been given a slot, followed by those
+    /// waiting for one.
+    pub fn get_list(&self) -> Vec<Upload> {
+        let mut uploads = self.active.clone();
+        for user_name in self.turns.iter() {
+            if let Some(queue) = self.queued.get(user_name) {
+                uploads.extend(queue.iter().cloned());
+            }
+        }
+        uploads
+    }
+
+    /// Looks up the upload to the given user identified by the given token.
+    pub fn get_requested(&self, user_name: &str, token: u32) -> Option<&Upload> {
+        self.active.iter().find(|upload| {
+            upload.user_name == user_name
+                && match upload.state {
+                    State::Requested {
+                        token: upload_token,
+                        ..
+                    } => upload_token == token,
+                    _ => false,
+                }
+        })
+    }
+
+    /// Frees the slots of the uploads for which the given predicate holds,
+    /// and returns them.
+    fn remove_active<F>(&mut self, predicate: F) -> Vec<Upload>
+    where
+        F: Fn(&Upload) -> bool,
+    {
+        let (removed, kept) = self.active.drain(..).partition(predicate);
+        self.active = kept;
+        removed
+    }
+
+    /// Frees the slots of the uploads offered to the given user that have
+    /// not started yet, and returns them.
+    pub fn cancel_requests(&mut self, user_name: &str) -> Vec<Upload> {
+        self.remove_active(|upload| match upload.state {
+            State::Requested { .. } => upload.user_name == user_name,
+            _ => false,
+        })
+    }
+
+    /// Frees the slots of the uploads offered longer than the given duration
+    /// ago that have not started yet, and returns them.
+    pub fn expire_requests(&mut self, timeout: time::Duration) -> Vec<Upload> {
+        self.remove_active(|upload| match upload.state {
+            State::Requested { since, .. } => since.elapsed() >= timeout,
+            _ => false,
+        })
+    }
+
+    /// Marks the given upload as transferring.
+    pub fn start_transferring(&mut self, user_name: &str, remote_path: &str) -> Result<(), Error> {
+        self.get_active_mut(user_name, remote_path)?.state = State::Transferring;
+        Ok(())
+    }
+
+    /// Sets the number of bytes sent for the given upload.
+    pub fn set_bytes_sent(
+        &mut self,
+        user_name: &str,
+        remote_path: &str,
+        bytes_sent: u64,
+    ) -> Result<(), Error> {
+        self.get_active_mut(user_name, remote_path)?.bytes_sent = bytes_sent;
+        Ok(())
+    }
+
+    /// Frees the slot of the given upload, which is over, and returns it.
+    pub fn finish(&mut self, user_name: &str, remote_path: &str) -> Result<Upload, Error> {
+        match self
+            .active
+            .iter()
+            .position(|upload| upload.user_name == user_name && upload.remote_path == remote_path)
+        {
+            Some(index) => Ok(self.active.remove(index)),
+            None => Err(Error::UploadNotFound(
+                user_name.to_string(),
+                remote_path.to_string(),
+            )),
+        }
+    }
+}
+
+/*=======*
+ * TESTS *
+ *=======*/
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use super::{Error, Priority, Upload, UploadQueue};
+
+    fn upload(user_name: &str, remote_path: &str, size: u64) -> Upload {
+        Upload::new(
+            user_name.to_string(),
+            remote_path.to_string(),
+            path::PathBuf::from(remote_path),
+            size,
+        )
+    }
+
+    fn priority(user_name: &str) -> Priority {
+        match user_name {
+            "carol" => Priority::Privileged,
+            "bob" => Priority::Buddy,
+            _ => Priority::Regular,
+        }
+    }
+
+    /// Starts every queued upload, and returns their remote paths in the
+    /// order in which they were started.
+    fn start_all(queue: &mut UploadQueue) -> Vec<String> {
+        let mut remote_paths = Vec::new();
+        while let Some(upload) = queue.start_next(priority, 0) {
+            remote_paths.push(upload.remote_path);
+        }
+        remote_paths
+    }
+
+    #[test]
+    fn start_next_round_robin() {
+        let mut queue = UploadQueue::new(10, 1000);
+        for remote_path in ["a1", "a2", "a3"].iter() {
+            queue.enqueue(upload("alice", remote_path, 1)).unwrap();
+        }
+        for remote_path in ["d1", "d2"].iter() {
+            queue.enqueue(upload("dave", remote_path, 1)).unwrap();
+        }
+
+        assert_eq!(start_all(&mut queue), vec!["a1", "d1", "a2", "d2", "a3"]);
+        assert_eq!(queue.num_queued(), 0);
+        assert_eq!(queue.num_active(), 5);
+    }
+
+    #[test]
+    fn start_next_privileged_then_buddies_first() {
+        let mut queue = UploadQueue::new(10, 1000);
+        queue.enqueue(upload("alice", "a1", 1)).unwrap();
+        queue.enqueue(upload("alice", "a2", 1)).unwrap();
+        queue.enqueue(upload("bob", "b1", 1)).unwrap();
+        queue.enqueue(upload("bob", "b2", 1)).unwrap();
+        queue.enqueue(upload("carol", "c1", 1)).unwrap();
+        queue.enqueue(upload("carol", "c2", 1)).unwrap();
+        queue.enqueue(upload("dave", "d1", 1)).unwrap();
+
+        assert_eq!(
+            start_all(&mut queue),
+            vec!["c1", "c2", "b1", "b2", "a1", "d1", "a2"]
+        );
+    }
+
+    #[test]
+    fn enqueue_too_many_files() {
+        let mut queue = UploadQueue::new(2, 1000);
+        queue.enqueue(upload("alice", "a1", 1)).unwrap();
+        queue.enqueue(upload("alice", "a2", 1)).unwrap();
+        match queue.enqueue(upload("alice", "a3", 1)) {
+            Err(Error::TooManyFiles(2)) => (),
+            result => panic!("{:?}", result),
+        }
+        // Uploads in progress count too.
+        queue.start_next(priority, 0).unwrap();
+        match queue.enqueue(upload("alice", "a3", 1)) {
+            Err(Error::TooManyFiles(2)) => (),
+            result => panic!("{:?}", result),
+        }
+        // Other users have limits of their own.
+        queue.enqueue(upload("dave", "d1", 1)).unwrap();
+    }
+
+    #[test]
+    fn enqueue_too_many_bytes() {
+        let mut queue = UploadQueue::new(10, 100);
+        queue.enqueue(upload("alice", "a1", 60)).unwrap();
+        match queue.enqueue(upload("alice", "a2", 50)) {
+            Err(Error::TooManyBytes(100)) => (),
+            result => panic!("{:?}", result),
+        }
+        queue.enqueue(upload("alice", "a2", 40)).unwrap();
+        queue.enqueue(upload("dave", "d1", 100)).unwrap();
+    }
+
+    #[test]
+    fn enqueue_already_queued() {
+        let mut queue = UploadQueue::new(10, 1000);
+        queue.enqueue(upload("alice", "a1", 1)).unwrap();
+        match queue.enqueue(upload("alice", "a1", 1)) {
+            Err(Error::UploadAlreadyQueued(_, _)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn place_in_queue_matches_start_order() {
+        let mut queue = UploadQueue::new(10, 1000);
+        let uploads = [
+            ("alice", "a1"),
+            ("dave", "d1"),
+            ("alice", "a2"),
+            ("bob", "b1"),
+            ("alice", "a3"),
+            ("carol", "c1"),
+            ("dave", "d2"),
+            ("bob", "b2"),
+        ];
+        for &(user_name, remote_path) in uploads.iter() {
+            queue.enqueue(upload(user_name, remote_path, 1)).unwrap();
+        }
+
+        let mut places = Vec::new();
+        for &(user_name, remote_path) in uploads.iter() {
+            let place = queue
+                .place_in_queue(user_name, remote_path, priority)
+                .unwrap();
+            places.push((place, remote_path));
+        }
+        places.sort();
+        let expected: Vec<_> = places.iter().map(|&(_, remote_path)| remote_path).collect();
+        let places: Vec<_> = places.iter().map(|&(place, _)| place).collect();
+        assert_eq!(places, (1..uploads.len() as u32 + 1).collect::<Vec<_>>());
+
+        // Places are recomputed as uploads start.
+        let mut started = Vec::new();
+        while let Some(upload) = queue.start_next(priority, 0) {
+            assert_eq!(
+                queue.place_in_queue(&upload.user_name, &upload.remote_path, priority),
+                Some(0)
+            );
+            for &(user_name, remote_path) in uploads.iter() {
+                if let Some(place) = queue.place_in_queue(user_name, remote_path, priority) {
+                    if place > 0 {
+                        let index = started.len() + place as usize;
+                        assert_eq!(expected[index], remote_path);
+                    }
+                }
+            }
+            started.push(upload.remote_path);
+        }
+        assert_eq!(started, expected);
+
+        assert_eq!(queue.place_in_queue("alice", "unknown", priority), None);
+    }
+}
@@ -31,6 +31,10 @@ pub struct UserMap {
     map: collections::HashMap<String, User>,
     /// The set of privileged users.
     privileged: collections::HashSet<String>,
+    /// The set of users we consider our buddies.
+    buddies: collections::HashSet<String>,
+    /// The set of users whose chat messages we ignore.
+    ignored: collections::HashSet<String>,
 }
 
 impl UserMap {
@@ -39,6 +43,8 @@ impl UserMap {
         UserMap {
             map: collections::HashMap::new(),
             privileged: collections::HashSet::new(),
+            buddies: collections::HashSet::new(),
+            ignored: collections::HashSet::new(),
         }
     }
 
@@ -107,4 +113,29 @@ impl UserMap {
     pub fn is_privileged(&self, user_name: &str) -> bool {
         self.privileged.contains(user_name)
     }
+
+    /// Marks the given user as a buddy.
+    pub fn insert_buddy(&mut self, user_name: String) {
+        self.buddies.insert(user_name);
+    }
+
+    /// Checks if the given user is a buddy.
+    pub fn is_buddy(&self, user_name: &str) -> bool {
+        self.buddies.contains(user_name)
+    }
+
+    /// Marks the given user as ignored.
+    pub fn insert_ignored(&mut self, user_name: String) {
+        self.ignored.insert(user_name);
+    }
+
+    /// Marks the given user as not ignored.
+    pub fn remove_ignored(&mut self, user_name: &str) {
+        self.ignored.remove(user_name);
+    }
+
+    /// Checks if the given user is ignored.
+    pub fn is_ignored(&self, user_name: &str) -> bool {
+        self.ignored.contains(user_name)
+    }
 }